@@ -0,0 +1,83 @@
+/*
+ * C interface to privsep-channel, for children that can't be written in Rust.
+ *
+ * A child exec'd by `proc::start` finds its end of the controller's socket at
+ * a fixed fd (56 in the examples) and opens it with privsep_channel_open.
+ * Any other fds it inherits follow, named in order by PRIVSEP_FDNAMES.
+ * Payloads are exchanged as the controller encodes them (bincode): this
+ * library does the framing, fd passing, heartbeats and credit grants, never
+ * the decoding.
+ *
+ * A channel is not thread safe. Every call blocks until it completes.
+ */
+#ifndef PRIVSEP_CHANNEL_H
+#define PRIVSEP_CHANNEL_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The largest payload a frame can carry: a receive buffer of this size
+ * always suffices. */
+#define PRIVSEP_CHANNEL_MAX_PAYLOAD 4092
+
+enum privsep_status {
+    PRIVSEP_OK = 0,
+    PRIVSEP_ERR_IO = -1,
+    /* The peer closed the channel. */
+    PRIVSEP_ERR_CLOSED = -2,
+    /* The payload doesn't fit in a frame, or in the caller's buffer. */
+    PRIVSEP_ERR_TOO_LARGE = -3,
+    /* The peer sent something the protocol doesn't allow here. */
+    PRIVSEP_ERR_PROTOCOL = -4,
+    /* The peer has sent too many malformed frames to go on. */
+    PRIVSEP_ERR_POISONED = -5,
+    /* `ch` is NULL, as from a failed privsep_channel_open. */
+    PRIVSEP_ERR_NULL = -6,
+};
+
+typedef struct privsep_channel privsep_channel;
+
+/* Takes ownership of `fd`, a unix stream socket. Returns NULL on failure. */
+privsep_channel *privsep_channel_open(int fd);
+
+/* Closes the channel, its socket and any fds received but not taken. */
+void privsep_channel_close(privsep_channel *ch);
+
+/* Sends `len` bytes as one message, passing `fd` along with it unless it is
+ * -1. The fd is duplicated by the kernel: the caller still owns it. */
+int privsep_channel_send(privsep_channel *ch, const uint8_t *buf, size_t len, int fd);
+
+/* Receives the next message into `buf`, storing its length in `len`. A
+ * message too large for `cap` stays queued, with any fd, and `len` is set to
+ * the size it needs (PRIVSEP_ERR_TOO_LARGE): retry with a larger buffer. */
+int privsep_channel_recv(privsep_channel *ch, uint8_t *buf, size_t cap, size_t *len);
+
+/* The oldest fd received and not yet taken, or -1. Fds are queued in the
+ * order of the messages that carried them; the caller owns the result. */
+int privsep_channel_take_fd(privsep_channel *ch);
+
+/* Tells the controller we are alive, see its liveness watchers. */
+int privsep_channel_send_heartbeat(privsep_channel *ch);
+
+/* Allows the peer to send `n` more messages, if it is flow controlled. */
+int privsep_channel_grant(privsep_channel *ch, uint32_t n);
+
+/* Receives the spawn-time handshake: `n` messages, each a variant of the
+ * controller's message enum that carries nothing but an fd. `variants` holds
+ * their expected indices in the enum, in order, and their fds are stored in
+ * `fds`. */
+int privsep_channel_handshake(privsep_channel *ch, const uint32_t *variants, size_t n, int *fds);
+
+/* Describes the last error on `ch`, or returns NULL. Valid until the next
+ * call on `ch`. */
+const char *privsep_channel_last_error(const privsep_channel *ch);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PRIVSEP_CHANNEL_H */
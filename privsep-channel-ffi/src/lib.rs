@@ -0,0 +1,372 @@
+//! C interface to `privsep_channel`, declared in `include/privsep_channel.h`.
+//!
+//! Each handle owns a single-threaded runtime that its calls block on, so C
+//! code sees a plain blocking API speaking the same wire protocol as the Rust
+//! side.
+
+use privsep_channel::channel_redux::{Channel, ChannelRx, ChannelTx};
+use privsep_channel::error::ChannelError;
+use privsep_channel::serializefd::SerializeFd;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, CString};
+use std::os::fd::{IntoRawFd, OwnedFd, RawFd};
+use std::ptr;
+use tokio::runtime::{Builder, Runtime};
+
+pub const PRIVSEP_OK: c_int = 0;
+pub const PRIVSEP_ERR_IO: c_int = -1;
+pub const PRIVSEP_ERR_CLOSED: c_int = -2;
+pub const PRIVSEP_ERR_TOO_LARGE: c_int = -3;
+pub const PRIVSEP_ERR_PROTOCOL: c_int = -4;
+pub const PRIVSEP_ERR_POISONED: c_int = -5;
+pub const PRIVSEP_ERR_NULL: c_int = -6;
+
+/// Payloads are passed through as bytes and never decoded on this side.
+#[derive(Serialize, Deserialize)]
+enum Opaque {}
+
+impl SerializeFd for Opaque {
+    fn extract_fd(&self) -> Option<RawFd> {
+        match *self {}
+    }
+
+    fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+        match self {}
+    }
+}
+
+pub struct PrivsepChannel {
+    tx: ChannelTx<Opaque>,
+    rx: ChannelRx<Opaque>,
+    last_error: Option<CString>,
+    // Dropped after the halves, which are registered with it.
+    runtime: Runtime,
+}
+
+impl PrivsepChannel {
+    fn status(&mut self, result: Result<(), ChannelError>) -> c_int {
+        let Err(e) = result else {
+            self.last_error = None;
+            return PRIVSEP_OK;
+        };
+
+        let status = match e {
+            ChannelError::Io(_) => PRIVSEP_ERR_IO,
+            ChannelError::ConnectionClosedPrematurely => PRIVSEP_ERR_CLOSED,
+            ChannelError::MessageTooLargeForTxBuffer(..)
+            | ChannelError::MessageTooLargeForRxBuffer(..) => PRIVSEP_ERR_TOO_LARGE,
+            ChannelError::Poisoned(_) => PRIVSEP_ERR_POISONED,
+            _ => PRIVSEP_ERR_PROTOCOL,
+        };
+        self.last_error = CString::new(e.to_string()).ok();
+
+        status
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, ChannelError> {
+        self.runtime.block_on(self.rx.recv_raw())
+    }
+
+    /// Receives one handshake step: variant `index` of the peer's enum,
+    /// carrying an fd and nothing else.
+    fn handshake_step(&mut self, index: u32) -> Result<OwnedFd, ChannelError> {
+        // Claiming the fd with the payload closes it if the step is rejected,
+        // rather than leaving it for the next `privsep_channel_take_fd`.
+        let (payload, fd) = self.runtime.block_on(self.rx.recv_raw_with_fd())?;
+        // bincode encodes the variant index as a little endian u32, and the
+        // fd field itself is skipped.
+        if payload != index.to_le_bytes() {
+            return Err(ChannelError::UnexpectedMessage {
+                expected: "handshake step",
+                received: format!("{payload:?}"),
+            });
+        }
+
+        fd.ok_or(ChannelError::MissingFdForMessage)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn privsep_channel_open(fd: c_int) -> *mut PrivsepChannel {
+    let Ok(runtime) = Builder::new_current_thread().enable_all().build() else {
+        return ptr::null_mut();
+    };
+    let channel = {
+        let _guard = runtime.enter();
+        Channel::new_from_fd(fd)
+    };
+
+    match channel {
+        Ok((tx, rx)) => Box::into_raw(Box::new(PrivsepChannel {
+            tx,
+            rx,
+            last_error: None,
+            runtime,
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// `ch` must come from `privsep_channel_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_close(ch: *mut PrivsepChannel) {
+    if !ch.is_null() {
+        drop(Box::from_raw(ch));
+    }
+}
+
+/// # Safety
+/// `ch` must be an open channel or null, and `buf` valid for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_send(
+    ch: *mut PrivsepChannel,
+    buf: *const u8,
+    len: usize,
+    fd: c_int,
+) -> c_int {
+    let Some(ch) = ch.as_mut() else {
+        return PRIVSEP_ERR_NULL;
+    };
+    let payload = match len {
+        0 => &[],
+        _ => std::slice::from_raw_parts(buf, len),
+    };
+    let fd = (fd >= 0).then_some(fd);
+
+    let result = ch.runtime.block_on(ch.tx.send_raw(payload, fd));
+    ch.status(result)
+}
+
+/// # Safety
+/// `ch` must be an open channel or null, `buf` valid for `cap` bytes and
+/// `len` for a write.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_recv(
+    ch: *mut PrivsepChannel,
+    buf: *mut u8,
+    cap: usize,
+    len: *mut usize,
+) -> c_int {
+    let Some(ch) = ch.as_mut() else {
+        return PRIVSEP_ERR_NULL;
+    };
+    // Too large a message stays queued, with its fds, for a larger buffer.
+    let result = match ch.runtime.block_on(ch.rx.peek_raw()) {
+        Ok(needed) if needed > cap => {
+            *len = needed;
+            Err(ChannelError::MessageTooLargeForRxBuffer(needed, cap))
+        }
+        Ok(_) => ch.recv().map(|payload| {
+            ptr::copy_nonoverlapping(payload.as_ptr(), buf, payload.len());
+            *len = payload.len();
+        }),
+        Err(e) => Err(e),
+    };
+
+    ch.status(result)
+}
+
+/// # Safety
+/// `ch` must be an open channel or null.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_take_fd(ch: *mut PrivsepChannel) -> c_int {
+    match ch.as_mut() {
+        Some(ch) => ch.rx.take_fd().unwrap_or(-1),
+        None => -1,
+    }
+}
+
+/// # Safety
+/// `ch` must be an open channel or null.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_send_heartbeat(ch: *mut PrivsepChannel) -> c_int {
+    let Some(ch) = ch.as_mut() else {
+        return PRIVSEP_ERR_NULL;
+    };
+    let result = ch.runtime.block_on(ch.tx.send_heartbeat());
+    ch.status(result)
+}
+
+/// # Safety
+/// `ch` must be an open channel or null.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_grant(ch: *mut PrivsepChannel, n: u32) -> c_int {
+    let Some(ch) = ch.as_mut() else {
+        return PRIVSEP_ERR_NULL;
+    };
+    let result = ch.runtime.block_on(ch.tx.grant(n));
+    ch.status(result)
+}
+
+/// # Safety
+/// `ch` must be an open channel or null, `variants` valid for `n` reads and
+/// `fds` for `n` writes.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_handshake(
+    ch: *mut PrivsepChannel,
+    variants: *const u32,
+    n: usize,
+    fds: *mut c_int,
+) -> c_int {
+    let Some(ch) = ch.as_mut() else {
+        return PRIVSEP_ERR_NULL;
+    };
+    // Fds received before a failing step are closed with `received`.
+    let mut received = Vec::with_capacity(n);
+    for i in 0..n {
+        match ch.handshake_step(*variants.add(i)) {
+            Ok(fd) => received.push(fd),
+            Err(e) => return ch.status(Err(e)),
+        }
+    }
+
+    for (i, fd) in received.into_iter().enumerate() {
+        *fds.add(i) = fd.into_raw_fd();
+    }
+    ch.status(Ok(()))
+}
+
+/// # Safety
+/// `ch` must be an open channel or null.
+#[no_mangle]
+pub unsafe extern "C" fn privsep_channel_last_error(ch: *const PrivsepChannel) -> *const c_char {
+    match ch.as_ref().and_then(|ch| ch.last_error.as_ref()) {
+        Some(e) => e.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Msg {
+        Hello(String),
+        Lane(#[serde(skip)] RawFd),
+    }
+
+    impl SerializeFd for Msg {
+        fn extract_fd(&self) -> Option<RawFd> {
+            match self {
+                Self::Lane(fd) => Some(*fd),
+                Self::Hello(_) => None,
+            }
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    #[test]
+    fn test_c_child_speaks_the_wire_protocol() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let (mut tx, mut rx) = {
+            let _guard = rt.enter();
+            Channel::from_stream::<Msg, Msg>(tokio::net::UnixStream::from_std(ours).unwrap())
+        };
+
+        let ch = privsep_channel_open(theirs.into_raw_fd());
+        assert!(!ch.is_null());
+
+        let file = tempfile::tempfile().unwrap();
+        rt.block_on(tx.send(&Msg::Lane(file.as_raw_fd()))).unwrap();
+        rt.block_on(tx.send(&Msg::Hello("hi".to_owned()))).unwrap();
+
+        unsafe {
+            let mut fd = -1;
+            assert_eq!(privsep_channel_handshake(ch, &1, 1, &mut fd), PRIVSEP_OK);
+            assert!(fd >= 0);
+            drop(OwnedFd::from_raw_fd(fd));
+
+            let mut buf = [0u8; 64];
+            let mut len = 0;
+            assert_eq!(
+                privsep_channel_recv(ch, buf.as_mut_ptr(), buf.len(), &mut len),
+                PRIVSEP_OK
+            );
+            let hello = bincode::deserialize::<Msg>(&buf[..len]).unwrap();
+            assert_eq!(hello, Msg::Hello("hi".to_owned()));
+
+            let reply = bincode::serialize(&Msg::Hello("back".to_owned())).unwrap();
+            assert_eq!(
+                privsep_channel_send(ch, reply.as_ptr(), reply.len(), -1),
+                PRIVSEP_OK
+            );
+            assert_eq!(
+                rt.block_on(rx.recv()).unwrap(),
+                Msg::Hello("back".to_owned())
+            );
+
+            // Too large a message is kept, and its size reported.
+            let long = Msg::Hello("x".repeat(100));
+            rt.block_on(tx.send(&long)).unwrap();
+            let mut small = [0u8; 8];
+            assert_eq!(
+                privsep_channel_recv(ch, small.as_mut_ptr(), small.len(), &mut len),
+                PRIVSEP_ERR_TOO_LARGE
+            );
+            assert!(len > small.len());
+            let mut large = vec![0u8; len];
+            assert_eq!(
+                privsep_channel_recv(ch, large.as_mut_ptr(), large.len(), &mut len),
+                PRIVSEP_OK
+            );
+            assert_eq!(bincode::deserialize::<Msg>(&large[..len]).unwrap(), long);
+
+            // A handshake step that isn't what we expect is refused.
+            rt.block_on(tx.send(&Msg::Hello("hi".to_owned()))).unwrap();
+            assert_eq!(
+                privsep_channel_handshake(ch, &1, 1, &mut fd),
+                PRIVSEP_ERR_PROTOCOL
+            );
+            assert!(!privsep_channel_last_error(ch).is_null());
+
+            // Nor is its fd left for the next `privsep_channel_take_fd`.
+            rt.block_on(tx.send(&Msg::Lane(file.as_raw_fd()))).unwrap();
+            assert_eq!(
+                privsep_channel_handshake(ch, &0, 1, &mut fd),
+                PRIVSEP_ERR_PROTOCOL
+            );
+            rt.block_on(tx.send(&Msg::Hello("hi".to_owned()))).unwrap();
+            assert_eq!(
+                privsep_channel_recv(ch, buf.as_mut_ptr(), buf.len(), &mut len),
+                PRIVSEP_OK
+            );
+            assert_eq!(privsep_channel_take_fd(ch), -1);
+
+            privsep_channel_close(ch);
+        }
+    }
+
+    #[test]
+    fn test_null_channel_is_refused() {
+        unsafe {
+            let mut len = 0;
+            let ch = ptr::null_mut();
+            assert_eq!(
+                privsep_channel_send(ch, ptr::null(), 0, -1),
+                PRIVSEP_ERR_NULL
+            );
+            assert_eq!(
+                privsep_channel_recv(ch, ptr::null_mut(), 0, &mut len),
+                PRIVSEP_ERR_NULL
+            );
+            assert_eq!(privsep_channel_take_fd(ch), -1);
+            assert_eq!(privsep_channel_send_heartbeat(ch), PRIVSEP_ERR_NULL);
+            assert_eq!(privsep_channel_grant(ch, 1), PRIVSEP_ERR_NULL);
+            assert_eq!(
+                privsep_channel_handshake(ch, ptr::null(), 0, ptr::null_mut()),
+                PRIVSEP_ERR_NULL
+            );
+            assert!(privsep_channel_last_error(ch).is_null());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt::{self, Debug, Write as _};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A capture file shared by any number of taps.
+///
+/// Each frame becomes one tab-separated line:
+/// `<micros> <label> <tx|rx> <fds> <hex payload> <debug>`, where the payload
+/// is the bincode body without its length prefix and `micros` counts from
+/// when the capture was created.
+pub struct Capture {
+    out: Mutex<BufWriter<File>>,
+    start: Instant,
+}
+
+impl Capture {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Capture {
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+            start: Instant::now(),
+        }))
+    }
+
+    /// Opens the capture named by `PRIVSEP_CAPTURE`, if set.
+    pub fn from_env() -> io::Result<Option<Arc<Self>>> {
+        match std::env::var_os("PRIVSEP_CAPTURE") {
+            Some(path) => Capture::create(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Appends `record`, timed under the lock so that the file stays in
+    /// time order however many taps write to it.
+    fn write(&self, mut record: Record) {
+        let mut out = self.out.lock().unwrap();
+        record.time = self.start.elapsed();
+        // Capturing is best effort: a full disk must not take the channel down.
+        let _ = writeln!(out, "{record}").and_then(|()| out.flush());
+    }
+}
+
+/// Which way a frame went, from the point of view of the tapped end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// One line of a capture file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time: Duration,
+    pub label: String,
+    pub direction: Direction,
+    pub fds: usize,
+    pub payload: Vec<u8>,
+    pub debug: String,
+}
+
+impl Record {
+    pub fn payload_hex(&self) -> String {
+        let mut hex = String::with_capacity(self.payload.len() * 2);
+        for byte in &self.payload {
+            let _ = write!(hex, "{byte:02x}");
+        }
+
+        hex
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Tx => "tx",
+            Direction::Rx => "rx",
+        };
+        write!(
+            f,
+            "{}\t{}\t{direction}\t{}\t{}\t{}",
+            self.time.as_micros(),
+            self.label,
+            self.fds,
+            self.payload_hex(),
+            self.debug.replace(['\t', '\n'], " ")
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseRecordError;
+
+impl fmt::Display for ParseRecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("malformed capture record")
+    }
+}
+
+impl std::error::Error for ParseRecordError {}
+
+impl FromStr for Record {
+    type Err = ParseRecordError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.splitn(6, '\t');
+        let mut field = || fields.next().ok_or(ParseRecordError);
+
+        let time = Duration::from_micros(field()?.parse().map_err(|_| ParseRecordError)?);
+        let label = field()?.to_owned();
+        let direction = match field()? {
+            "tx" => Direction::Tx,
+            "rx" => Direction::Rx,
+            _ => return Err(ParseRecordError),
+        };
+        let fds = field()?.parse().map_err(|_| ParseRecordError)?;
+
+        let hex = field()?;
+        if hex.len() % 2 != 0 {
+            return Err(ParseRecordError);
+        }
+        let payload = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ParseRecordError))
+            .collect::<Result<_, _>>()?;
+
+        let debug = field()?.to_owned();
+
+        Ok(Record {
+            time,
+            label,
+            direction,
+            fds,
+            payload,
+            debug,
+        })
+    }
+}
+
+/// Where a `ChannelTx`/`ChannelRx` records its frames.
+pub(crate) struct Tap<M> {
+    capture: Arc<Capture>,
+    label: String,
+    // A plain fn so that only `with_capture` needs `M: Debug`.
+    debug: fn(&M) -> String,
+}
+
+impl<M: Debug> Tap<M> {
+    pub(crate) fn new(capture: Arc<Capture>, label: &str) -> Self {
+        Tap {
+            capture,
+            label: label.to_owned(),
+            debug: |msg| format!("{msg:?}"),
+        }
+    }
+}
+
+impl<M> Tap<M> {
+    pub(crate) fn record(&self, direction: Direction, msg: &M, fds: usize, payload: &[u8]) {
+        self.capture.write(Record {
+            time: Duration::ZERO,
+            label: self.label.clone(),
+            direction,
+            fds,
+            payload: payload.to_vec(),
+            debug: (self.debug)(msg),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capture, Direction, Record};
+    use crate::channel_redux::Channel;
+    use crate::test_util::TestMsg;
+    use std::os::fd::AsRawFd;
+
+    #[tokio::test]
+    async fn test_frames_are_captured_both_ways() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture");
+        let capture = Capture::create(&path).unwrap();
+
+        let ((tx, _rx), (_tx, rx)) = Channel::loopback::<TestMsg, TestMsg>();
+        let mut tx = tx.with_capture(capture.clone(), "a");
+        let mut rx = rx.with_capture(capture, "b");
+
+        let file = tempfile::tempfile().unwrap();
+        tx.send(&TestMsg::Text("one\ttwo".to_owned()))
+            .await
+            .unwrap();
+        tx.send(&TestMsg::Fd(file.as_raw_fd())).await.unwrap();
+        rx.recv().await.unwrap();
+        rx.recv().await.unwrap();
+
+        let records = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.parse::<Record>().unwrap())
+            .collect::<Vec<_>>();
+
+        let summary = records
+            .iter()
+            .map(|r| (r.label.as_str(), r.direction, r.fds))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("a", Direction::Tx, 0),
+                ("a", Direction::Tx, 1),
+                ("b", Direction::Rx, 0),
+                ("b", Direction::Rx, 1),
+            ]
+        );
+
+        assert_eq!(records[0].payload, records[2].payload);
+        assert_eq!(
+            bincode::deserialize::<TestMsg>(&records[0].payload).unwrap(),
+            TestMsg::Text("one\ttwo".to_owned())
+        );
+        assert_eq!(records[0].debug, r#"Text("one\ttwo")"#);
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+}
@@ -1,53 +1,154 @@
-use bincode::{deserialize, serialize};
-use byteorder::{BigEndian, WriteBytesExt};
-use sendfd::{RecvWithFd, SendWithFd};
+use bincode::serialize;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::collections::VecDeque;
+use std::fmt::{self, Debug};
 use std::io::{self, ErrorKind};
 use std::marker::PhantomData;
-use std::os::fd::FromRawFd;
+use std::os::fd::{FromRawFd, OwnedFd};
 use std::os::unix::io::RawFd;
-use tokio::io::AsyncReadExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::tcp;
 use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::UnixStream;
+use tokio::net::{TcpStream, UnixStream};
 
+use crate::capture::{Capture, Direction, Tap};
+use crate::credit::Credits;
+use crate::envelope::{Tolerant, Versioned};
 use crate::error::ChannelError;
+use crate::liveness::{HeartbeatConfig, Liveness};
+use crate::loopback::{self, LoopbackRx, LoopbackTx};
+use crate::metrics::{monotonic_nanos, Metrics};
+use crate::policy::DecodePolicy;
+use crate::seal::{SealKeys, Sealer, TAG_BYTES};
 use crate::serializefd::SerializeFd;
+use crate::transport::{RxTransport, TxTransport};
+
+// Tags both halves made by one `from_transport` call, so `reunite` can tell
+// whether they belong together.
+static NEXT_PAIR: AtomicU64 = AtomicU64::new(0);
 
 // Define fixed buffer sizes. TX needs space for prefix + data.
 const TX_BUFFER_SIZE: usize = 4096;
 const RX_BUFFER_SIZE: usize = 4096;
 const PREFIX_BYTES: usize = 4;
 // Max payload size must fit within buffer minus prefix length
-const MAX_PAYLOAD_SIZE: usize = TX_BUFFER_SIZE - PREFIX_BYTES;
+pub(crate) const MAX_PAYLOAD_SIZE: usize = TX_BUFFER_SIZE - PREFIX_BYTES;
+// The prefix packs four fields:
+// - bit 31: the payload is preceded by the sender's `CLOCK_MONOTONIC` time
+//   (u64 nanoseconds, big endian),
+// - bit 30: an fd came with the frame's first byte,
+// - bits 24-29: the `FrameKind`,
+// - bits 0-23: the length of everything after the prefix.
+const TIMESTAMP_FLAG: u32 = 1 << 31;
+const TIMESTAMP_BYTES: usize = 8;
+const FD_FLAG: u32 = 1 << 30;
+const KIND_SHIFT: u32 = 24;
+const KIND_MASK: u32 = 0x3f << KIND_SHIFT;
+const LENGTH_MASK: u32 = (1 << KIND_SHIFT) - 1;
+const ENVELOPE_BYTES: usize = 5;
 
-pub struct ChannelTx<M>
-where
-    M: SerializeFd,
-    M: Serialize,
-{
-    stream: OwnedWriteHalf,
+/// What a frame carries. Anything but `Message` is handled by the channel
+/// itself and never returned from `recv`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameKind {
+    Message = 0,
+    Heartbeat = 1,
+    Credit = 2,
+    // A message preceded by its `Versioned::variant_id` (u32, big endian)
+    // and the number of fds it carries (u8).
+    Envelope = 3,
+}
+
+impl FrameKind {
+    fn from_prefix(prefix: u32) -> Result<Self, ChannelError> {
+        match (prefix & KIND_MASK) >> KIND_SHIFT {
+            0 => Ok(FrameKind::Message),
+            1 => Ok(FrameKind::Heartbeat),
+            2 => Ok(FrameKind::Credit),
+            3 => Ok(FrameKind::Envelope),
+            kind => Err(ChannelError::UnknownFrameKind(kind as u8)),
+        }
+    }
+}
+
+pub struct ChannelTx<M, T = OwnedWriteHalf> {
+    stream: T,
+    pair: u64,
     tx_buffer: Box<[u8]>,
+    capture: Option<Tap<M>>,
+    metrics: Arc<Metrics>,
+    timestamps: bool,
+    // Credit granted by the peer, if flow controlled.
+    credits: Option<Arc<Credits>>,
+    // A plain fn so that only `with_envelopes` needs `M: Versioned`.
+    envelope: Option<fn(&M) -> u32>,
+    sealer: Option<Sealer>,
 
     phantom: PhantomData<M>,
 }
 
-pub struct ChannelRx<N>
-where
-    N: SerializeFd,
-    N: DeserializeOwned,
-{
-    stream: OwnedReadHalf,
+pub struct ChannelRx<N, R = OwnedReadHalf> {
+    stream: R,
+    pair: u64,
     received_fds: VecDeque<RawFd>,
     rx_buffer: Box<[u8]>,
     rx_buffer_offset: usize,
+    policy: DecodePolicy,
+    // Malformed frames received so far, charged against `policy.error_budget`.
+    errors: u32,
+    poisoned: bool,
+    capture: Option<Tap<N>>,
+    metrics: Arc<Metrics>,
+    // When a frame of any kind last arrived, if a `Liveness` is watching.
+    last_seen: Option<Arc<AtomicU64>>,
+    // Where grants from the peer go, if our `ChannelTx` is flow controlled.
+    credits: Option<Arc<Credits>>,
+    opener: Option<Sealer>,
+    // Whether the frame at the start of the buffer has been opened already.
+    opened: bool,
     phantom: PhantomData<N>,
 }
 
-pub struct Channel;
+/// Both halves of one end of a channel, for code that sends and receives in
+/// turn. `Channel` on its own (no type parameters) also hosts the
+/// constructors, most of which hand out the halves separately; rejoin them
+/// with `ChannelTx::reunite`.
+pub struct Channel<M = (), N = (), T = OwnedWriteHalf, R = OwnedReadHalf> {
+    tx: ChannelTx<M, T>,
+    rx: ChannelRx<N, R>,
+}
 
 impl Channel {
+    /// A duplex channel over `stream`, e.g. for a subsystem that talks to a
+    /// single peer.
+    pub fn duplex<M, N>(stream: UnixStream) -> Channel<M, N>
+    where
+        M: SerializeFd,
+        M: Serialize,
+        N: SerializeFd,
+        N: DeserializeOwned,
+    {
+        let (tx, rx) = Channel::from_stream(stream);
+
+        Channel { tx, rx }
+    }
+
+    pub fn duplex_from_fd<M, N>(fd: RawFd) -> io::Result<Channel<M, N>>
+    where
+        M: SerializeFd,
+        M: Serialize,
+        N: SerializeFd,
+        N: DeserializeOwned,
+    {
+        let stream = make_stream(fd)?;
+
+        Ok(Channel::duplex(stream))
+    }
+
     pub fn from_stream<M, N>(stream: UnixStream) -> (ChannelTx<M>, ChannelRx<N>)
     where
         M: SerializeFd,
@@ -59,22 +160,99 @@ impl Channel {
         // stream.set_nonblocking(true).expect("Failed to set non-blocking");
         let (rx, tx) = stream.into_split();
 
+        Channel::from_transport(tx, rx)
+    }
+
+    /// Builds a channel over any transport pair, e.g. the halves of a `loopback`.
+    pub fn from_transport<M, N, T, R>(tx: T, rx: R) -> (ChannelTx<M, T>, ChannelRx<N, R>)
+    where
+        M: SerializeFd,
+        M: Serialize,
+        N: SerializeFd,
+        N: DeserializeOwned,
+        T: TxTransport,
+        R: RxTransport,
+    {
+        let pair = NEXT_PAIR.fetch_add(1, Ordering::Relaxed);
+
         (
             ChannelTx {
                 stream: tx,
+                pair,
                 tx_buffer: vec![0u8; TX_BUFFER_SIZE].into_boxed_slice(),
+                capture: None,
+                metrics: Arc::default(),
+                timestamps: false,
+                credits: None,
+                envelope: None,
+                sealer: None,
                 phantom: PhantomData::<M>,
             },
             ChannelRx {
                 stream: rx,
+                pair,
                 received_fds: VecDeque::new(),
                 rx_buffer: vec![0u8; RX_BUFFER_SIZE].into_boxed_slice(),
                 rx_buffer_offset: 0,
+                policy: DecodePolicy::default(),
+                errors: 0,
+                poisoned: false,
+                capture: None,
+                metrics: Arc::default(),
+                last_seen: None,
+                credits: None,
+                opener: None,
+                opened: false,
                 phantom: PhantomData,
             },
         )
     }
 
+    /// Creates two connected channel ends backed by in-memory buffers instead of
+    /// a socket. Framing, size limits and fd passing behave as they do over a
+    /// unix socket (fds are dup'd on send), so subsystem logic can be exercised
+    /// in a single test process.
+    #[allow(clippy::type_complexity)]
+    pub fn loopback<M, N>() -> (
+        (ChannelTx<M, LoopbackTx>, ChannelRx<N, LoopbackRx>),
+        (ChannelTx<N, LoopbackTx>, ChannelRx<M, LoopbackRx>),
+    )
+    where
+        M: SerializeFd,
+        M: Serialize,
+        M: DeserializeOwned,
+        N: SerializeFd,
+        N: Serialize,
+        N: DeserializeOwned,
+    {
+        let ((tx_a, rx_a), (tx_b, rx_b)) = loopback::pair();
+
+        (
+            Channel::from_transport(tx_a, rx_a),
+            Channel::from_transport(tx_b, rx_b),
+        )
+    }
+
+    /// A channel over TCP, e.g. to forward between hosts. TCP can't carry
+    /// fds, and anything between the peers can read and rewrite the stream:
+    /// seal both halves (see `ChannelTx::with_seal`).
+    pub fn from_tcp<M, N>(
+        stream: TcpStream,
+    ) -> (
+        ChannelTx<M, tcp::OwnedWriteHalf>,
+        ChannelRx<N, tcp::OwnedReadHalf>,
+    )
+    where
+        M: SerializeFd,
+        M: Serialize,
+        N: SerializeFd,
+        N: DeserializeOwned,
+    {
+        let (rx, tx) = stream.into_split();
+
+        Channel::from_transport(tx, rx)
+    }
+
     pub fn new_from_fd<M, N>(fd: RawFd) -> io::Result<(ChannelTx<M>, ChannelRx<N>)>
     where
         M: SerializeFd,
@@ -88,243 +266,911 @@ impl Channel {
     }
 }
 
-impl<M> ChannelTx<M>
+impl<M, N, T, R> Channel<M, N, T, R>
 where
     M: SerializeFd,
     M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    T: TxTransport,
+    R: RxTransport,
 {
     pub async fn send(&mut self, msg: &M) -> Result<(), ChannelError> {
-        loop {
-            self.stream.writable().await?;
+        self.tx.send(msg).await
+    }
 
-            match self.send_msg(msg).await {
-                Err(ChannelError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {
-                    continue;
-                }
-                r => return r,
-            }
-        }
+    pub async fn recv(&mut self) -> Result<N, ChannelError> {
+        self.rx.recv().await
     }
 
+    pub fn tx(&mut self) -> &mut ChannelTx<M, T> {
+        &mut self.tx
+    }
+
+    pub fn rx(&mut self) -> &mut ChannelRx<N, R> {
+        &mut self.rx
+    }
+
+    pub fn into_split(self) -> (ChannelTx<M, T>, ChannelRx<N, R>) {
+        (self.tx, self.rx)
+    }
+}
+
+/// Returned by `ChannelTx::reunite` when the halves came from different
+/// channels. Both are handed back untouched.
+pub struct ReuniteError<M, N, T = OwnedWriteHalf, R = OwnedReadHalf>(
+    pub ChannelTx<M, T>,
+    pub ChannelRx<N, R>,
+);
+
+impl<M, N, T, R> fmt::Debug for ReuniteError<M, N, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReuniteError").finish_non_exhaustive()
+    }
+}
+
+impl<M, N, T, R> fmt::Display for ReuniteError<M, N, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("tried to reunite halves of different channels")
+    }
+}
+
+impl<M, N, T, R> std::error::Error for ReuniteError<M, N, T, R> {}
+
+impl<M, T> ChannelTx<M, T>
+where
+    M: SerializeFd,
+    M: Serialize,
+    T: TxTransport,
+{
     /// Sends a message over the Unix socket.
-    /// If the message is `Msg::FileDescriptor`, the contained `RawFd` is sent
-    /// via ancillary data.
-    async fn send_msg(&mut self, msg: &M) -> Result<(), ChannelError>
+    /// If the message carries a `RawFd` (see `SerializeFd`), it is sent via
+    /// ancillary data along with the first byte of the frame.
+    pub async fn send(&mut self, msg: &M) -> Result<(), ChannelError> {
+        let result = self.send_msg(msg).await;
+        if let Err(ref e) = result {
+            self.metrics.error(e);
+        }
+
+        result
+    }
+
+    async fn send_msg(&mut self, msg: &M) -> Result<(), ChannelError> {
+        let fd: Option<RawFd> = msg.extract_fd();
+        self.check_fd(fd)?;
+        let total_msg_len = self.encode(msg)?;
+        self.spend_credit()?;
+        self.write(total_msg_len, fd).await?;
+
+        if let Some(tap) = &self.capture {
+            let payload = &self.tx_buffer[self.message_start()..total_msg_len];
+            tap.record(Direction::Tx, msg, fd.iter().count(), payload);
+        }
+
+        Ok(())
+    }
+
+    /// Sends an already-serialized payload, e.g. one read from a capture.
+    pub async fn send_raw(
+        &mut self,
+        payload: &[u8],
+        fd: Option<RawFd>,
+    ) -> Result<(), ChannelError> {
+        self.check_fd(fd)?;
+        let total_msg_len = self.frame(FrameKind::Message, payload, fd.is_some())?;
+        self.spend_credit()?;
+        self.write(total_msg_len, fd).await
+    }
+
+    /// Tells the peer we are alive (see `ChannelRx::watch_liveness`). Send
+    /// these from the loop whose progress matters, typically on
+    /// `HeartbeatConfig::ticker`.
+    pub async fn send_heartbeat(&mut self) -> Result<(), ChannelError> {
+        let total_msg_len = self.frame(FrameKind::Heartbeat, &[], false)?;
+        self.write(total_msg_len, None).await
+    }
+
+    /// Allows the peer to send `n` more messages to us, if it has enabled
+    /// flow control (see `with_flow_control`).
+    pub async fn grant(&mut self, n: u32) -> Result<(), ChannelError> {
+        let total_msg_len = self.frame(FrameKind::Credit, &n.to_be_bytes(), false)?;
+        self.write(total_msg_len, None).await
+    }
+
+    /// Only sends while the peer has granted credit: each message spends one,
+    /// and without any `send` fails with `ChannelError::NoCredit` rather than
+    /// queueing. Grants arrive on `rx`, the other half of this end, and are
+    /// only seen while it is being polled.
+    pub fn with_flow_control<N, R>(mut self, rx: &mut ChannelRx<N, R>) -> Self
     where
-        M: SerializeFd,
-        M: Serialize,
+        N: SerializeFd,
+        N: DeserializeOwned,
+        R: RxTransport,
     {
-        let fd: Option<RawFd> = msg.extract_fd();
+        let credits = Arc::new(Credits::default());
+        rx.credits = Some(credits.clone());
+        self.credits = Some(credits);
+        self
+    }
 
-        let serialized_msg = serialize(msg)?;
-        let serialized_len = serialized_msg.len();
+    /// Messages we may still send, if flow controlled.
+    pub fn credits(&self) -> Option<u64> {
+        self.credits.as_ref().map(|credits| credits.available())
+    }
 
-        if serialized_len > MAX_PAYLOAD_SIZE {
-            return Err(ChannelError::MessageTooLargeForTxBuffer(
-                serialized_len,
-                MAX_PAYLOAD_SIZE,
-            ));
+    /// Waits until at least one message may be sent. Resolves at once when
+    /// not flow controlled.
+    pub async fn credited(&self) {
+        if let Some(credits) = &self.credits {
+            credits.wait().await;
+        }
+    }
+
+    /// Refuses an fd the transport can't carry, before anything is framed,
+    /// sealed or paid for.
+    fn check_fd(&self, fd: Option<RawFd>) -> Result<(), ChannelError> {
+        match fd {
+            Some(_) if !self.stream.passes_fds() => Err(ChannelError::FdPassingUnsupported),
+            _ => Ok(()),
+        }
+    }
+
+    fn spend_credit(&self) -> Result<(), ChannelError> {
+        match &self.credits {
+            Some(credits) => credits.take(),
+            None => Ok(()),
         }
-        let total_msg_len = PREFIX_BYTES + serialized_len;
+    }
 
-        (&mut self.tx_buffer[0..PREFIX_BYTES]).write_u32::<BigEndian>(serialized_len as u32)?;
-        self.tx_buffer[PREFIX_BYTES..total_msg_len].copy_from_slice(&serialized_msg);
+    /// Encrypts and authenticates every frame from now on, for transports
+    /// the peers don't trust (see `Channel::from_tcp`). The peer's
+    /// `ChannelRx::with_seal` must use `keys.peer()`. The prefix, which
+    /// frames the stream, is authenticated but sent in the clear.
+    pub fn with_seal(mut self, keys: &SealKeys) -> Self {
+        self.sealer = Some(keys.sealer());
+        self
+    }
 
-        let mut total_bytes_sent = 0;
-        while total_bytes_sent < total_msg_len {
-            let buf = &self.tx_buffer[total_bytes_sent..total_msg_len];
+    /// Stamps every frame with the send time so the receiver can measure
+    /// latency (see `MetricsSnapshot::latency`).
+    pub fn with_timestamps(mut self) -> Self {
+        self.timestamps = true;
+        self
+    }
 
-            let fd_storage: [RawFd; 1];
-            let fds: &[RawFd];
+    /// A handle on this half's counters.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
 
-            if total_bytes_sent == 0 {
-                if let Some(fd_val) = fd {
-                    fd_storage = [fd_val];
+    /// Records every frame sent from now on to `capture`, tagged with `label`.
+    pub fn with_capture(mut self, capture: Arc<Capture>, label: &str) -> Self
+    where
+        M: Debug,
+    {
+        self.capture = Some(Tap::new(capture, label));
+        self
+    }
 
-                    fds = &fd_storage;
-                } else {
-                    fds = &[];
-                }
-            } else {
-                // Not the first iteration, don't send FD again.
-                fds = &[];
-            }
+    /// Puts the halves made by one `Channel` constructor back together.
+    /// Their configuration (and any fds received but not yet claimed) is
+    /// kept.
+    #[allow(clippy::result_large_err, clippy::type_complexity)]
+    pub fn reunite<N, R>(
+        self,
+        rx: ChannelRx<N, R>,
+    ) -> Result<Channel<M, N, T, R>, ReuniteError<M, N, T, R>>
+    where
+        N: SerializeFd,
+        N: DeserializeOwned,
+        R: RxTransport,
+    {
+        if self.pair != rx.pair {
+            return Err(ReuniteError(self, rx));
+        }
 
-            match self.stream.send_with_fd(buf, fds) {
-                Ok(n) => {
-                    if n == 0 {
-                        return Err(ChannelError::Io(io::Error::new(
-                            io::ErrorKind::WriteZero,
-                            "send_fd returned 0 bytes sent",
-                        )));
-                    }
+        Ok(Channel { tx: self, rx })
+    }
 
-                    total_bytes_sent += n;
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::Interrupted {
-                        continue;
-                    }
+    /// Tags every message with its variant id, so that a peer running an
+    /// older definition of `M` can skip variants it doesn't have (see
+    /// `ChannelRx::recv_tolerant`). Receivers that don't ask for tolerance
+    /// decode enveloped messages as usual.
+    pub fn with_envelopes(mut self) -> Self
+    where
+        M: Versioned,
+    {
+        self.envelope = Some(M::variant_id);
+        self
+    }
 
-                    return Err(ChannelError::Io(e));
-                }
+    /// Serializes `msg` into the tx buffer, returning the frame length.
+    fn encode(&mut self, msg: &M) -> Result<usize, ChannelError> {
+        let serialized_msg = serialize(msg)?;
+        let fd = msg.extract_fd().is_some();
+
+        match self.envelope {
+            Some(variant_id) => {
+                let mut body = Vec::with_capacity(ENVELOPE_BYTES + serialized_msg.len());
+                body.write_u32::<BigEndian>(variant_id(msg))?;
+                body.push(fd as u8);
+                body.extend_from_slice(&serialized_msg);
+
+                self.frame(FrameKind::Envelope, &body, fd)
             }
+            None => self.frame(FrameKind::Message, &serialized_msg, fd),
         }
+    }
+
+    /// Writes the first `total_msg_len` bytes of the tx buffer, sealed if
+    /// need be. The buffer itself is left as it was, for the tap.
+    async fn write(&mut self, total_msg_len: usize, fd: Option<RawFd>) -> Result<(), ChannelError> {
+        let frame = &self.tx_buffer[..total_msg_len];
+        let frame = match &mut self.sealer {
+            Some(sealer) => sealer.seal(frame, PREFIX_BYTES),
+            None => frame,
+        };
+
+        write_frame(&self.stream, frame, fd, &self.metrics).await?;
+        // Only a frame that went out uses up its nonce.
+        if let Some(sealer) = &mut self.sealer {
+            sealer.sent();
+        }
+        self.metrics.frame(total_msg_len, fd.iter().count());
 
         Ok(())
     }
+
+    /// Where the serialized message starts in a frame from `encode`.
+    fn message_start(&self) -> usize {
+        match self.envelope {
+            Some(_) => self.payload_start() + ENVELOPE_BYTES,
+            None => self.payload_start(),
+        }
+    }
+
+    fn payload_start(&self) -> usize {
+        if self.timestamps {
+            PREFIX_BYTES + TIMESTAMP_BYTES
+        } else {
+            PREFIX_BYTES
+        }
+    }
+
+    /// Writes the length prefix, timestamp and payload into the tx buffer,
+    /// returning the frame length. `fd` says one is sent with the frame.
+    fn frame(&mut self, kind: FrameKind, payload: &[u8], fd: bool) -> Result<usize, ChannelError> {
+        let serialized_len = payload.len();
+        let payload_start = self.payload_start();
+        // A sealed frame must still fit the peer's rx buffer.
+        let tag_bytes = if self.sealer.is_some() { TAG_BYTES } else { 0 };
+        let max_payload_size = TX_BUFFER_SIZE - payload_start - tag_bytes;
+
+        if serialized_len > max_payload_size {
+            return Err(ChannelError::MessageTooLargeForTxBuffer(
+                serialized_len,
+                max_payload_size,
+            ));
+        }
+        let total_msg_len = payload_start + serialized_len;
+
+        let mut prefix = (total_msg_len - PREFIX_BYTES) as u32 | (kind as u32) << KIND_SHIFT;
+        if fd {
+            prefix |= FD_FLAG;
+        }
+        if self.timestamps {
+            prefix |= TIMESTAMP_FLAG;
+            (&mut self.tx_buffer[PREFIX_BYTES..payload_start])
+                .write_u64::<BigEndian>(monotonic_nanos())?;
+        }
+        (&mut self.tx_buffer[0..PREFIX_BYTES]).write_u32::<BigEndian>(prefix)?;
+        self.tx_buffer[payload_start..total_msg_len].copy_from_slice(payload);
+
+        Ok(total_msg_len)
+    }
 }
 
-impl<N> ChannelRx<N>
+impl<N, R> ChannelRx<N, R>
 where
     N: SerializeFd,
     N: DeserializeOwned,
+    R: RxTransport,
 {
+    /// Replaces the default `DecodePolicy` for messages from the peer.
+    pub fn with_policy(mut self, policy: DecodePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Records every frame received from now on to `capture`, tagged with `label`.
+    pub fn with_capture(mut self, capture: Arc<Capture>, label: &str) -> Self
+    where
+        N: Debug,
+    {
+        self.capture = Some(Tap::new(capture, label));
+        self
+    }
+
+    /// A handle on this half's counters.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Starts tracking when frames arrive and returns a watcher that fires
+    /// once the peer has been silent for `config.timeout()`. Frames are only
+    /// read while `recv` is being polled, so keep it polled (e.g. in the same
+    /// `select!` as the watcher).
+    pub fn watch_liveness(&mut self, config: HeartbeatConfig) -> Liveness {
+        let last_seen = Arc::new(AtomicU64::new(monotonic_nanos()));
+        self.last_seen = Some(last_seen.clone());
+
+        Liveness::new(last_seen, config)
+    }
+
+    /// Opens frames sealed by the peer's `ChannelTx::with_seal`. A frame that
+    /// fails to open (tampered with, replayed, reordered or dropped) poisons
+    /// the channel.
+    pub fn with_seal(mut self, keys: &SealKeys) -> Self {
+        self.opener = Some(keys.opener());
+        self
+    }
+
+    /// Whether the peer has exhausted its error budget (or desynchronised the
+    /// stream). A poisoned channel only ever returns `ChannelError::Poisoned`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
     pub async fn recv(&mut self) -> Result<N, ChannelError> {
-        loop {
-            self.stream.readable().await?;
+        match self.next(|_| true).await? {
+            Tolerant::Known(msg) => Ok(msg),
+            Tolerant::Unknown { .. } => unreachable!("every variant id is known"),
+        }
+    }
+
+    /// Like `recv`, but a message the peer sent with `with_envelopes` whose
+    /// variant we don't have is returned as `Tolerant::Unknown` rather than
+    /// failing to decode.
+    pub async fn recv_tolerant(&mut self) -> Result<Tolerant<N>, ChannelError>
+    where
+        N: Versioned,
+    {
+        self.next(N::knows).await
+    }
 
-            match self.recv_msg().await {
+    async fn next(&mut self, knows: fn(u32) -> bool) -> Result<Tolerant<N>, ChannelError> {
+        if self.poisoned {
+            return Err(ChannelError::Poisoned(self.errors));
+        }
+
+        loop {
+            // Try before waiting: a previous read may have left a complete
+            // message in the buffer, in which case the stream may never become
+            // readable again.
+            match self.recv_msg(knows).await {
                 Err(ChannelError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {
-                    continue;
+                    self.metrics.would_block();
+                    self.stream.readable().await?;
+                }
+                Err(e) => {
+                    self.metrics.error(&e);
+                    return Err(self.charge(e));
                 }
                 r => return r,
             }
         }
     }
 
+    /// Counts malformed frames against the error budget, poisoning the
+    /// channel once it is exceeded. A frame too large for the buffer leaves
+    /// us unable to find the next one, and one that fails authentication
+    /// means the stream can't be trusted, so those poison the channel at once.
+    fn charge(&mut self, e: ChannelError) -> ChannelError {
+        match e {
+            ChannelError::Bincode(_) | ChannelError::UnknownFrameKind(_) => {
+                self.errors += 1;
+                if self.errors <= self.policy.error_budget {
+                    return e;
+                }
+            }
+            ChannelError::MessageTooLargeForRxBuffer(..) | ChannelError::Unauthenticated => {
+                self.errors += 1
+            }
+            e => return e,
+        }
+
+        self.poisoned = true;
+        ChannelError::Poisoned(self.errors)
+    }
+
     /// Receives a message from the Unix socket.
     /// Handles receiving file descriptors via ancillary data and associates
-    /// them with `Msg::FileDescriptor` messages.
-    async fn recv_msg(&mut self) -> Result<N, ChannelError> {
-        // total_bytes_read now tracks ALL valid bytes from the start of the buffer,
-        // including any leftover data from the previous call.
-        let mut total_bytes_read = self.rx_buffer_offset;
-        let mut message_length: Option<usize> = None; // Full message length (prefix + payload)
-        let mut fd_buf = [0 as RawFd; 8];
+    /// them with the messages that expect them.
+    ///
+    /// Everything read is kept in `rx_buffer` (with `rx_buffer_offset` valid
+    /// bytes), so a WouldBlock part way through a frame loses nothing: the next
+    /// call picks up where this one stopped.
+    async fn recv_msg(&mut self, knows: fn(u32) -> bool) -> Result<Tolerant<N>, ChannelError> {
+        // Set when a complete frame is waiting on fds that have not arrived
+        // yet, so that at least one more read happens before retrying.
+        let mut need_read = false;
 
-        // --- Try checking for message length using potentially leftover data first ---
-        if message_length.is_none() && total_bytes_read >= PREFIX_BYTES {
-            let payload_len = (&self.rx_buffer[0..PREFIX_BYTES]).read_u32().await? as usize;
-            let expected_total_len = PREFIX_BYTES + payload_len;
-            if expected_total_len > RX_BUFFER_SIZE {
-                // Reset offset? Maybe not, let the error propagate. This state is likely unrecoverable.
-                // self.rx_buffer_offset = 0;
-                return Err(ChannelError::MessageTooLargeForRxBuffer(
-                    expected_total_len,
-                    RX_BUFFER_SIZE,
-                ));
+        loop {
+            if !need_read {
+                if let Some(frame_len) = self.next_frame()? {
+                    if self.control(frame_len)? {
+                        continue;
+                    }
+                    let msg = match self.envelope(frame_len) {
+                        Some((id, nfds)) if !knows(id) => self.skip(frame_len, id, nfds),
+                        _ => self.decode(frame_len)?.map(Tolerant::Known),
+                    };
+                    match msg {
+                        Some(msg) => return Ok(msg),
+                        None => need_read = true,
+                    }
+                }
             }
-            message_length = Some(expected_total_len);
-
-            // Check if leftover data already contains the full message
-            if let Some(expected_len) = message_length {
-                if total_bytes_read >= expected_len {
-                    // Full message was already in the buffer from last time!
-                    // No need to read more for THIS message. Proceed to deserialize.
-                    // The loop below will be skipped.
+
+            // Includes WouldBlock: the bytes read so far stay buffered.
+            self.read_more(need_read)?;
+            need_read = false;
+        }
+    }
+
+    /// Receives the next frame's payload without decoding it, e.g. to replay
+    /// or inspect traffic of an unknown type. Any fds stay queued.
+    pub async fn recv_raw(&mut self) -> Result<Vec<u8>, ChannelError> {
+        let frame_len = self.next_raw().await?;
+        let payload_start = self.received(frame_len, 0);
+        let payload = self.rx_buffer[payload_start..frame_len].to_vec();
+        self.consume(frame_len);
+
+        Ok(payload)
+    }
+
+    /// Like `recv_raw`, but also claims the fd the frame carried, if any, so
+    /// that it is closed along with the frame when the caller rejects it.
+    pub async fn recv_raw_with_fd(&mut self) -> Result<(Vec<u8>, Option<OwnedFd>), ChannelError> {
+        let frame_len = self.next_raw().await?;
+        let prefix = u32::from_be_bytes(self.rx_buffer[0..PREFIX_BYTES].try_into().unwrap());
+        let fd = match prefix & FD_FLAG {
+            0 => None,
+            _ => self.received_fds.pop_front(),
+        };
+        let payload_start = self.received(frame_len, fd.iter().count());
+        let payload = self.rx_buffer[payload_start..frame_len].to_vec();
+        self.consume(frame_len);
+
+        Ok((payload, fd.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })))
+    }
+
+    /// Waits for the next frame and returns the length of its payload,
+    /// leaving the frame for `recv_raw`, e.g. to size a buffer for it.
+    pub async fn peek_raw(&mut self) -> Result<usize, ChannelError> {
+        let frame_len = self.next_raw().await?;
+        let (payload_start, _) = self.header(frame_len);
+
+        Ok(frame_len - payload_start)
+    }
+
+    /// Reads until a complete message frame is at the start of the buffer,
+    /// handling any control frames before it, and returns its length.
+    async fn next_raw(&mut self) -> Result<usize, ChannelError> {
+        if self.poisoned {
+            return Err(ChannelError::Poisoned(self.errors));
+        }
+
+        loop {
+            let frame_len = self.next_frame().map_err(|e| self.charge(e))?;
+            if let Some(frame_len) = frame_len {
+                if self.control(frame_len).map_err(|e| self.charge(e))? {
+                    continue;
+                }
+                return Ok(frame_len);
+            }
+
+            match self.read_more(false) {
+                Err(ChannelError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {
+                    self.metrics.would_block();
+                    self.stream.readable().await?;
                 }
+                r => r.map_err(|e| self.charge(e))?,
             }
         }
+    }
 
-        // --- Loop reading chunks if message not fully present in leftover data ---
-        // Condition: Continue if we don't know the length yet, OR if we know the length
-        //            but haven't read enough total bytes yet.
-        while message_length.is_none() || total_bytes_read < message_length.unwrap() {
-            // Determine slice to read into (space after existing valid data)
-            let current_read_slice = &mut self.rx_buffer[total_bytes_read..];
+    /// Claims the oldest fd received but not yet claimed by a message, e.g.
+    /// the one carried by a frame from `recv_raw`. Fds arrive with the first
+    /// byte of their frame, so they are queued in frame order.
+    pub fn take_fd(&mut self) -> Option<RawFd> {
+        self.received_fds.pop_front()
+    }
+
+    /// Reads whatever is available into the buffer after the valid data.
+    /// `need_read` says a complete frame is waiting on fds, which changes how
+    /// running out of buffer or stream is reported.
+    fn read_more(&mut self, need_read: bool) -> Result<(), ChannelError> {
+        let mut fd_buf = [0 as RawFd; 8];
+
+        loop {
+            // Space after existing valid data
+            let current_read_slice = &mut self.rx_buffer[self.rx_buffer_offset..];
 
             if current_read_slice.is_empty() {
+                if need_read {
+                    // Can't read any further to find the fds this frame needs.
+                    return Err(ChannelError::MissingFdForMessage);
+                }
                 // Buffer is full, but we still haven't completed the message.
                 return Err(ChannelError::MessageTooLargeForRxBuffer(
-                    message_length.unwrap_or(RX_BUFFER_SIZE + 1), // Best guess
+                    RX_BUFFER_SIZE + 1, // Best guess
                     RX_BUFFER_SIZE,
                 ));
             }
 
-            // Perform the read
             match self.stream.recv_with_fd(current_read_slice, &mut fd_buf) {
-                Ok((bytes_read_this_iter, fds_received_this_iter)) => {
-                    if bytes_read_this_iter == 0 {
-                        // EOF
-                        return Err(ChannelError::ConnectionClosedPrematurely);
+                Ok((0, _)) => {
+                    if need_read {
+                        return Err(ChannelError::MissingFdForMessage);
                     }
+                    // EOF
+                    return Err(ChannelError::ConnectionClosedPrematurely);
+                }
+                Ok((bytes_read, fds_received)) => {
+                    self.rx_buffer_offset += bytes_read;
 
-                    total_bytes_read += bytes_read_this_iter; // Update total valid bytes count
-
-                    // Buffer FDs
-                    (0..fds_received_this_iter).for_each(|i| {
-                        if fd_buf[i] >= 0 {
-                            self.received_fds.push_back(fd_buf[i]);
+                    // Buffer FDs. One the kernel reports as invalid is
+                    // counted as missing, which its message will then be.
+                    for &fd in &fd_buf[..fds_received] {
+                        if fd >= 0 {
+                            self.received_fds.push_back(fd);
                         } else {
-                            eprintln!("Warning: Received invalid FD {}", fd_buf[i]);
+                            self.metrics.error(&ChannelError::MissingFdForMessage);
                         }
-                    });
-
-                    // Try parsing length again if we just got enough bytes
-                    if message_length.is_none() && total_bytes_read >= PREFIX_BYTES {
-                        let payload_len =
-                            (&self.rx_buffer[0..PREFIX_BYTES]).read_u32().await? as usize;
-                        let expected_total_len = PREFIX_BYTES + payload_len;
-                        if expected_total_len > RX_BUFFER_SIZE {
-                            // Reset offset before error? Probably not needed.
-                            // self.rx_buffer_offset = 0;
-                            return Err(ChannelError::MessageTooLargeForRxBuffer(
-                                expected_total_len,
-                                RX_BUFFER_SIZE,
-                            ));
-                        }
-                        message_length = Some(expected_total_len);
                     }
 
-                    // Check if we've completed reading the message *after* this read
-                    if let Some(expected_len) = message_length {
-                        if total_bytes_read >= expected_len {
-                            break; // Exit loop, message complete
-                        }
-                        // else: continue loop, need more data
-                    }
-                    // else: continue loop, still need prefix data
-                } // end Ok match arm
-                // Err(sendfd::RecvFdError::Io(ref e)) if e.kind() == io::ErrorKind::Interrupted => {
-                //     continue; // Retry on signal
-                // }
-                Err(e) => {
-                    // Reset offset before error? Probably not. Error state.
-                    // self.rx_buffer_offset = 0;
-                    return Err(ChannelError::Io(e));
+                    return Ok(());
                 }
-            } // end match recv_fd
-        } // end loop
-
-        // --- Post-loop processing: A full message is now in the buffer ---
-        let final_message_len = message_length.expect("Loop exited without msg length");
-
-        // Deserialize payload (index 4 up to message end)
-        let payload_slice = &self.rx_buffer[PREFIX_BYTES..final_message_len];
-        let msg: N = deserialize(payload_slice)?;
-        let msg = msg.compose_fd(&mut self.received_fds)?;
-
-        // --- Handle Leftover Data ---
-        let leftover_len = total_bytes_read - final_message_len;
-        if leftover_len > 0 {
-            // Move the leftover data to the beginning of the buffer
-            self.rx_buffer
-                .copy_within(final_message_len..total_bytes_read, 0);
-            // Update the offset for the next read
-            self.rx_buffer_offset = leftover_len;
-        } else {
-            // No leftover data
-            self.rx_buffer_offset = 0;
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ChannelError::Io(e)),
+            }
         }
+    }
 
-        Ok(msg)
+    /// Length (prefix + payload) of the frame at the start of the buffer, if
+    /// it has been read completely.
+    fn frame_len(&self) -> Result<Option<usize>, ChannelError> {
+        if self.rx_buffer_offset < PREFIX_BYTES {
+            return Ok(None);
+        }
+
+        let prefix = (&self.rx_buffer[0..PREFIX_BYTES]).read_u32::<BigEndian>()?;
+        let payload_len = (prefix & LENGTH_MASK) as usize;
+        let expected_total_len = PREFIX_BYTES + payload_len;
+        if expected_total_len > RX_BUFFER_SIZE {
+            // This state is likely unrecoverable: we can't find the next frame.
+            return Err(ChannelError::MessageTooLargeForRxBuffer(
+                expected_total_len,
+                RX_BUFFER_SIZE,
+            ));
+        }
+
+        if self.rx_buffer_offset < expected_total_len {
+            return Ok(None);
+        }
+
+        Ok(Some(expected_total_len))
+    }
+
+    /// Like `frame_len`, but opens a sealed frame first. The opened frame
+    /// replaces the sealed one at the start of the buffer, tag removed, so
+    /// the rest of the channel never sees the difference.
+    fn next_frame(&mut self) -> Result<Option<usize>, ChannelError> {
+        let Some(sealed_len) = self.frame_len()? else {
+            return Ok(None);
+        };
+        let Some(opener) = &mut self.opener else {
+            return Ok(Some(sealed_len));
+        };
+        if self.opened {
+            return Ok(Some(sealed_len));
+        }
+        if sealed_len < PREFIX_BYTES + TAG_BYTES {
+            return Err(ChannelError::Unauthenticated);
+        }
+
+        let frame_len = sealed_len - TAG_BYTES;
+        let (prefix, rest) = self.rx_buffer.split_at_mut(PREFIX_BYTES);
+        let (body, rest) = rest.split_at_mut(frame_len - PREFIX_BYTES);
+        opener.open(prefix, body, &rest[..TAG_BYTES])?;
+
+        let prefix = u32::from_be_bytes(prefix[..].try_into().unwrap()) - TAG_BYTES as u32;
+        self.rx_buffer[0..PREFIX_BYTES].copy_from_slice(&prefix.to_be_bytes());
+        self.rx_buffer
+            .copy_within(sealed_len..self.rx_buffer_offset, frame_len);
+        self.rx_buffer_offset -= TAG_BYTES;
+        self.opened = true;
+
+        Ok(Some(frame_len))
+    }
+
+    /// Where the payload of the complete frame at the start of the buffer
+    /// begins, and when it was sent if it carries a timestamp.
+    fn header(&self, frame_len: usize) -> (usize, Option<u64>) {
+        let prefix = u32::from_be_bytes(self.rx_buffer[0..PREFIX_BYTES].try_into().unwrap());
+        let mut payload_start = PREFIX_BYTES;
+        let mut sent = None;
+
+        // A frame too short for its headers is left for the decoder to reject.
+        if prefix & TIMESTAMP_FLAG != 0 && frame_len >= payload_start + TIMESTAMP_BYTES {
+            sent = Some(u64::from_be_bytes(
+                self.rx_buffer[payload_start..payload_start + TIMESTAMP_BYTES]
+                    .try_into()
+                    .unwrap(),
+            ));
+            payload_start += TIMESTAMP_BYTES;
+        }
+        if matches!(FrameKind::from_prefix(prefix), Ok(FrameKind::Envelope))
+            && frame_len >= payload_start + ENVELOPE_BYTES
+        {
+            payload_start += ENVELOPE_BYTES;
+        }
+
+        (payload_start, sent)
+    }
+
+    /// The variant id and fd count of the complete frame at the start of the
+    /// buffer, if it is an envelope.
+    fn envelope(&self, frame_len: usize) -> Option<(u32, usize)> {
+        let prefix = u32::from_be_bytes(self.rx_buffer[0..PREFIX_BYTES].try_into().unwrap());
+        if !matches!(FrameKind::from_prefix(prefix), Ok(FrameKind::Envelope)) {
+            return None;
+        }
+
+        let start = match prefix & TIMESTAMP_FLAG {
+            0 => PREFIX_BYTES,
+            _ => PREFIX_BYTES + TIMESTAMP_BYTES,
+        };
+        let header = self.rx_buffer.get(start..start + ENVELOPE_BYTES)?;
+        if frame_len < start + ENVELOPE_BYTES {
+            return None;
+        }
+        let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+
+        Some((id, header[4] as usize))
+    }
+
+    /// Drops the complete enveloped frame at the start of the buffer along
+    /// with its fds, for a variant we don't know. Returns `None` (leaving the
+    /// frame in place) if the fds have not all been received yet.
+    fn skip(&mut self, frame_len: usize, id: u32, nfds: usize) -> Option<Tolerant<N>> {
+        if self.received_fds.len() < nfds {
+            return None;
+        }
+        for fd in self.received_fds.drain(..nfds) {
+            unsafe { nix::libc::close(fd) };
+        }
+
+        let payload_start = self.received(frame_len, nfds);
+        let bytes = self.rx_buffer[payload_start..frame_len].to_vec();
+        self.consume(frame_len);
+
+        Some(Tolerant::Unknown { id, bytes })
+    }
+
+    /// Handles the complete frame at the start of the buffer if it is not a
+    /// message, returning whether it was.
+    fn control(&mut self, frame_len: usize) -> Result<bool, ChannelError> {
+        let prefix = (&self.rx_buffer[0..PREFIX_BYTES]).read_u32::<BigEndian>()?;
+
+        match FrameKind::from_prefix(prefix) {
+            Ok(FrameKind::Message | FrameKind::Envelope) => Ok(false),
+            Ok(FrameKind::Heartbeat) => {
+                self.received(frame_len, 0);
+                self.consume(frame_len);
+                Ok(true)
+            }
+            Ok(FrameKind::Credit) => {
+                let payload_start = self.received(frame_len, 0);
+                let grant = (&self.rx_buffer[payload_start..frame_len]).read_u32::<BigEndian>();
+                self.consume(frame_len);
+                if let Some(credits) = &self.credits {
+                    credits.grant(grant?);
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                self.discard(frame_len);
+                Err(e)
+            }
+        }
+    }
+
+    /// Counts a received frame (and its latency, if timestamped), returning
+    /// where its payload starts.
+    fn received(&self, frame_len: usize, fds: usize) -> usize {
+        let (payload_start, sent) = self.header(frame_len);
+
+        if let Some(last_seen) = &self.last_seen {
+            last_seen.store(monotonic_nanos(), Ordering::Relaxed);
+        }
+
+        self.metrics.frame(frame_len, fds);
+        if let Some(sent) = sent {
+            let latency = monotonic_nanos().saturating_sub(sent);
+            self.metrics.latency(Duration::from_nanos(latency));
+        }
+
+        payload_start
+    }
+
+    /// Decodes the complete frame at the start of the buffer. Returns `None`
+    /// (leaving the frame in place) if the message needs fds that have not
+    /// been received yet.
+    fn decode(&mut self, frame_len: usize) -> Result<Option<N>, ChannelError> {
+        let (payload_start, _) = self.header(frame_len);
+        let payload_slice = &self.rx_buffer[payload_start..frame_len];
+        let msg: N = match self.policy.decode(payload_slice) {
+            Ok(msg) => msg,
+            Err(e) => {
+                // The frame is unusable but the stream is still in sync: drop
+                // it, and its fd, which must not be taken for the next one's.
+                self.discard(frame_len);
+                return Err(e.into());
+            }
+        };
+
+        // Compose against a copy so a partial match doesn't eat fds we still need.
+        let mut fds = self.received_fds.clone();
+        let msg = match msg.compose_fd(&mut fds) {
+            Ok(msg) => msg,
+            Err(ChannelError::MissingFdForMessage) => return Ok(None),
+            Err(e) => {
+                self.discard(frame_len);
+                return Err(e);
+            }
+        };
+        let nfds = self.received_fds.len() - fds.len();
+        self.received(frame_len, nfds);
+        if let Some(tap) = &self.capture {
+            let payload = &self.rx_buffer[payload_start..frame_len];
+            tap.record(Direction::Rx, &msg, nfds, payload);
+        }
+        self.received_fds = fds;
+        self.consume(frame_len);
+
+        Ok(Some(msg))
+    }
+
+    /// Drops the complete frame at the start of the buffer unread, closing
+    /// the fd that came with it. Fds arrive in frame order, so it is the
+    /// oldest one queued.
+    fn discard(&mut self, frame_len: usize) {
+        let prefix = u32::from_be_bytes(self.rx_buffer[0..PREFIX_BYTES].try_into().unwrap());
+        if prefix & FD_FLAG != 0 {
+            if let Some(fd) = self.received_fds.pop_front() {
+                unsafe { nix::libc::close(fd) };
+            }
+        }
+        self.consume(frame_len);
+    }
+
+    /// Drops the first `frame_len` bytes, moving any leftover data to the
+    /// beginning of the buffer.
+    fn consume(&mut self, frame_len: usize) {
+        self.rx_buffer
+            .copy_within(frame_len..self.rx_buffer_offset, 0);
+        self.rx_buffer_offset -= frame_len;
+        self.opened = false;
+    }
+}
+
+impl<N, R> Drop for ChannelRx<N, R> {
+    fn drop(&mut self) {
+        // Fds whose message never arrived (or failed to decode) would leak.
+        for fd in self.received_fds.drain(..) {
+            unsafe { nix::libc::close(fd) };
+        }
     }
 }
 
-fn make_stream(fd: RawFd) -> io::Result<UnixStream> {
+/// Writes a whole frame, passing `fd` along with its first byte.
+///
+/// Progress is tracked across WouldBlock so a short write is resumed rather
+/// than restarted.
+pub(crate) async fn write_frame<T: TxTransport>(
+    stream: &T,
+    frame: &[u8],
+    fd: Option<RawFd>,
+    metrics: &Metrics,
+) -> Result<(), ChannelError> {
+    let mut total_bytes_sent = 0;
+    while total_bytes_sent < frame.len() {
+        let buf = &frame[total_bytes_sent..];
+
+        // Only the first write carries the fd.
+        let fds = if total_bytes_sent == 0 {
+            fd.as_slice()
+        } else {
+            &[]
+        };
+
+        match stream.send_with_fd(buf, fds) {
+            Ok(0) => {
+                return Err(ChannelError::Io(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "send_fd returned 0 bytes sent",
+                )));
+            }
+            Ok(n) => total_bytes_sent += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                metrics.would_block();
+                stream.writable().await?;
+            }
+            Err(e) => return Err(ChannelError::Io(e)),
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn make_stream(fd: RawFd) -> io::Result<UnixStream> {
     let sock = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
     sock.set_nonblocking(true)?;
 
     UnixStream::from_std(sock)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, ReuniteError};
+    use crate::error::ChannelError;
+    use crate::test_util::TestMsg;
+    use std::fs::File;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::fs::MetadataExt;
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn test_split_halves_reunite_into_duplex() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut a = Channel::duplex::<TestMsg, TestMsg>(a);
+        let (tx, rx) = Channel::from_stream::<TestMsg, TestMsg>(b);
+        let ((_, other_rx), _) = Channel::loopback::<TestMsg, TestMsg>();
+
+        let Err(ReuniteError(tx, _)) = tx.reunite(other_rx) else {
+            panic!("reunited halves of different channels");
+        };
+        let mut b = tx.reunite(rx).unwrap();
+
+        let file = tempfile::tempfile().unwrap();
+        a.send(&TestMsg::Fd(file.as_raw_fd())).await.unwrap();
+        b.send(&TestMsg::Text("pong".to_owned())).await.unwrap();
+
+        assert!(matches!(b.recv().await.unwrap(), TestMsg::Fd(_)));
+        assert_eq!(a.recv().await.unwrap(), TestMsg::Text("pong".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frame_takes_its_fd_along() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut tx, _) = Channel::from_stream::<TestMsg, TestMsg>(a);
+        let (_, mut rx) = Channel::from_stream::<TestMsg, TestMsg>(b);
+
+        let stray = tempfile::tempfile().unwrap();
+        let meant = tempfile::tempfile().unwrap();
+        tx.send_raw(&[0xff; 4], Some(stray.as_raw_fd()))
+            .await
+            .unwrap();
+        tx.send(&TestMsg::Fd(meant.as_raw_fd())).await.unwrap();
+
+        assert!(matches!(rx.recv().await, Err(ChannelError::Bincode(_))));
+        let TestMsg::Fd(fd) = rx.recv().await.unwrap() else {
+            panic!("expected an fd");
+        };
+        let received = unsafe { File::from_raw_fd(fd) }.metadata().unwrap();
+        assert_eq!(received.ino(), meant.metadata().unwrap().ino());
+    }
+}
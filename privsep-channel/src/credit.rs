@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+use crate::error::ChannelError;
+
+/// Messages the peer has agreed to accept, shared between the `ChannelRx`
+/// that receives its grants and the `ChannelTx` that spends them (see
+/// `ChannelTx::with_flow_control`).
+#[derive(Default)]
+pub(crate) struct Credits {
+    available: AtomicU64,
+    granted: Notify,
+}
+
+impl Credits {
+    pub(crate) fn grant(&self, n: u32) {
+        self.available.fetch_add(n as u64, Ordering::Relaxed);
+        self.granted.notify_one();
+    }
+
+    pub(crate) fn available(&self) -> u64 {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    /// Spends one credit, or fails without waiting if there is none.
+    pub(crate) fn take(&self) -> Result<(), ChannelError> {
+        self.available
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .map(|_| ())
+            .map_err(|_| ChannelError::NoCredit)
+    }
+
+    pub(crate) async fn wait(&self) {
+        while self.available() == 0 {
+            self.granted.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::serializefd::SerializeFd;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::os::unix::io::RawFd;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Value(u32);
+
+    impl SerializeFd for Value {
+        fn extract_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sender_spends_granted_credits() {
+        let ((tx, mut rx), (mut tx_peer, mut rx_peer)) = Channel::loopback::<Value, Value>();
+        let mut tx = tx.with_flow_control(&mut rx);
+
+        assert_eq!(tx.credits(), Some(0));
+        assert!(matches!(
+            tx.send(&Value(0)).await,
+            Err(ChannelError::NoCredit)
+        ));
+
+        tx_peer.grant(2).await.unwrap();
+        // Grants are only seen while our receiving half is polled.
+        tokio::select! {
+            msg = rx.recv() => panic!("grant delivered as {msg:?}"),
+            _ = tx.credited() => {}
+        }
+        assert_eq!(tx.credits(), Some(2));
+
+        tx.send(&Value(1)).await.unwrap();
+        tx.send(&Value(2)).await.unwrap();
+        assert!(matches!(
+            tx.send(&Value(3)).await,
+            Err(ChannelError::NoCredit)
+        ));
+
+        assert_eq!(rx_peer.recv().await.unwrap(), Value(1));
+        assert_eq!(rx_peer.recv().await.unwrap(), Value(2));
+    }
+}
@@ -0,0 +1,153 @@
+/// Message enums whose variants carry stable ids, so that a receiver built
+/// from an older definition can recognise (and skip) variants it doesn't
+/// have. See `ChannelTx::with_envelopes` and `ChannelRx::recv_tolerant`.
+///
+/// The body of an enveloped message is still plain bincode, which encodes
+/// the variant's position: add new variants at the end of the enum and never
+/// reorder or remove the old ones.
+pub trait Versioned {
+    /// This variant's id. Ids are part of the protocol: never reuse or
+    /// renumber them.
+    fn variant_id(&self) -> u32;
+
+    /// Whether this build of the enum has a variant with `id`.
+    fn knows(id: u32) -> bool;
+}
+
+/// A message from `ChannelRx::recv_tolerant`.
+#[derive(Debug, PartialEq)]
+pub enum Tolerant<N> {
+    Known(N),
+    /// A variant the peer knows and we don't. Any fds it carried have been
+    /// closed.
+    Unknown {
+        id: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tolerant, Versioned};
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::loopback;
+    use crate::serializefd::{pop_fd, SerializeFd};
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::io::RawFd;
+
+    // Two versions of the same protocol: `New` adds `Resize` at the end.
+    mod old {
+        use serde::{Deserialize, Serialize};
+        use std::os::unix::io::RawFd;
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        pub enum Msg {
+            Value(u32),
+            Fd(#[serde(skip)] RawFd),
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum New {
+        Value(u32),
+        Fd(#[serde(skip)] RawFd),
+        Resize(#[serde(skip)] RawFd, u64),
+    }
+
+    impl SerializeFd for old::Msg {
+        fn extract_fd(&self) -> Option<RawFd> {
+            match self {
+                Self::Fd(fd) => Some(*fd),
+                Self::Value(_) => None,
+            }
+        }
+
+        fn compose_fd(self, fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            match self {
+                Self::Fd(_) => Ok(Self::Fd(pop_fd(fds)?)),
+                msg => Ok(msg),
+            }
+        }
+    }
+
+    impl Versioned for old::Msg {
+        fn variant_id(&self) -> u32 {
+            match self {
+                Self::Value(_) => 1,
+                Self::Fd(_) => 2,
+            }
+        }
+
+        fn knows(id: u32) -> bool {
+            matches!(id, 1 | 2)
+        }
+    }
+
+    impl SerializeFd for New {
+        fn extract_fd(&self) -> Option<RawFd> {
+            match self {
+                Self::Fd(fd) | Self::Resize(fd, _) => Some(*fd),
+                Self::Value(_) => None,
+            }
+        }
+
+        fn compose_fd(self, fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            match self {
+                Self::Fd(_) => Ok(Self::Fd(pop_fd(fds)?)),
+                Self::Resize(_, size) => Ok(Self::Resize(pop_fd(fds)?, size)),
+                msg => Ok(msg),
+            }
+        }
+    }
+
+    impl Versioned for New {
+        fn variant_id(&self) -> u32 {
+            match self {
+                Self::Value(_) => 1,
+                Self::Fd(_) => 2,
+                Self::Resize(..) => 3,
+            }
+        }
+
+        fn knows(id: u32) -> bool {
+            matches!(id, 1..=3)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_old_receiver_skips_new_variants() {
+        // The two ends disagree on the message type, so build them separately.
+        let ((tx_a, rx_a), (tx_b, rx_b)) = loopback::pair();
+        let (tx, _rx) = Channel::from_transport::<New, New, _, _>(tx_a, rx_a);
+        let (_tx, mut rx) = Channel::from_transport::<old::Msg, old::Msg, _, _>(tx_b, rx_b);
+        let mut tx = tx.with_envelopes();
+
+        let file = tempfile::tempfile().unwrap();
+        tx.send(&New::Value(1)).await.unwrap();
+        tx.send(&New::Resize(file.as_raw_fd(), 4096)).await.unwrap();
+        tx.send(&New::Fd(file.as_raw_fd())).await.unwrap();
+        tx.send(&New::Resize(file.as_raw_fd(), 8192)).await.unwrap();
+
+        assert_eq!(
+            rx.recv_tolerant().await.unwrap(),
+            Tolerant::Known(old::Msg::Value(1))
+        );
+        let Tolerant::Unknown { id, bytes } = rx.recv_tolerant().await.unwrap() else {
+            panic!("decoded a variant the receiver doesn't have");
+        };
+        assert_eq!(id, 3);
+        assert_eq!(bytes, bincode::serialize(&New::Resize(-1, 4096)).unwrap());
+
+        // The unknown message's fd was dropped, not handed to the next one.
+        assert!(matches!(
+            rx.recv_tolerant().await.unwrap(),
+            Tolerant::Known(old::Msg::Fd(_))
+        ));
+
+        // Without tolerance an unknown variant fails to decode, as before.
+        assert!(matches!(rx.recv().await, Err(ChannelError::Bincode(_))));
+    }
+}
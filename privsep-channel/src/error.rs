@@ -2,6 +2,10 @@ use std::io;
 
 use thiserror::Error;
 
+use crate::remote::RemoteError;
+
+pub type Result<T> = std::result::Result<T, ChannelError>;
+
 #[derive(Error, Debug)]
 pub enum ChannelError {
     #[error("I/O error: {0}")]
@@ -22,4 +26,25 @@ pub enum ChannelError {
         "Received FileDescriptor message but no FD was available in the ancillary data buffer"
     )]
     MissingFdForMessage,
+    #[error("Unknown frame kind {0}")]
+    UnknownFrameKind(u8),
+    #[error("Unknown imsg type {0}")]
+    UnknownImsgType(u32),
+    #[error("Malformed imsg: {0}")]
+    MalformedImsg(&'static str),
+    #[error("Remote error: {0}")]
+    Remote(#[from] RemoteError),
+    #[error("Transport can't pass file descriptors")]
+    FdPassingUnsupported,
+    #[error("Peer has not granted credit to send")]
+    NoCredit,
+    #[error("Frame failed authentication")]
+    Unauthenticated,
+    #[error("Channel poisoned after {0} malformed frames from peer")]
+    Poisoned(u32),
+    #[error("Expected {expected} message but received {received}")]
+    UnexpectedMessage {
+        expected: &'static str,
+        received: String,
+    },
 }
@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use crate::transport::{RxTransport, TxTransport};
+
+/// What to do on one call to `send_with_fd` / `recv_with_fd`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Hand the call to the inner transport unchanged.
+    Pass,
+    /// Transfer at most this many bytes (at least one).
+    Short(usize),
+    /// Fail with `Interrupted` (EINTR) without touching the inner transport.
+    Interrupted,
+    /// Fail with `WouldBlock` (EAGAIN) without touching the inner transport.
+    WouldBlock,
+    /// Read side only: if this read carries fds, return only the first part of
+    /// the data and deliver the fds with the rest on the next read.
+    DelayFds,
+    /// Read side only: report EOF from now on.
+    Eof,
+}
+
+/// A schedule of faults, one per call. Once exhausted every call passes.
+pub struct Faults(Mutex<VecDeque<Fault>>);
+
+impl Faults {
+    pub fn new(faults: impl IntoIterator<Item = Fault>) -> Self {
+        Faults(Mutex::new(faults.into_iter().collect()))
+    }
+
+    fn next(&self) -> Fault {
+        self.0.lock().unwrap().pop_front().unwrap_or(Fault::Pass)
+    }
+}
+
+/// A `TxTransport` wrapper that misbehaves according to a `Faults` schedule.
+pub struct FaultyTx<T> {
+    inner: T,
+    faults: Faults,
+}
+
+impl<T: TxTransport> FaultyTx<T> {
+    pub fn new(inner: T, faults: Faults) -> Self {
+        FaultyTx { inner, faults }
+    }
+}
+
+impl<T> TxTransport for FaultyTx<T>
+where
+    T: TxTransport,
+    T: Sync,
+{
+    async fn writable(&self) -> io::Result<()> {
+        self.inner.writable().await
+    }
+
+    fn send_with_fd(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        match self.faults.next() {
+            Fault::Short(n) => {
+                let n = n.max(1).min(buf.len());
+                self.inner.send_with_fd(&buf[..n], fds)
+            }
+            Fault::Interrupted => Err(io::Error::from(io::ErrorKind::Interrupted)),
+            Fault::WouldBlock => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Fault::Pass | Fault::DelayFds | Fault::Eof => self.inner.send_with_fd(buf, fds),
+        }
+    }
+
+    fn passes_fds(&self) -> bool {
+        self.inner.passes_fds()
+    }
+}
+
+/// An `RxTransport` wrapper that misbehaves according to a `Faults` schedule.
+pub struct FaultyRx<R> {
+    inner: R,
+    faults: Faults,
+    held: Mutex<Held>,
+    eof: Mutex<bool>,
+}
+
+/// Data and fds read from the inner transport but not yet handed out.
+#[derive(Default)]
+struct Held {
+    data: Vec<u8>,
+    fds: Vec<RawFd>,
+}
+
+impl<R: RxTransport> FaultyRx<R> {
+    pub fn new(inner: R, faults: Faults) -> Self {
+        FaultyRx {
+            inner,
+            faults,
+            held: Mutex::new(Held::default()),
+            eof: Mutex::new(false),
+        }
+    }
+}
+
+impl<R> RxTransport for FaultyRx<R>
+where
+    R: RxTransport,
+    R: Sync,
+{
+    async fn readable(&self) -> io::Result<()> {
+        if *self.eof.lock().unwrap() || !self.held.lock().unwrap().data.is_empty() {
+            return Ok(());
+        }
+        self.inner.readable().await
+    }
+
+    fn recv_with_fd(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        if *self.eof.lock().unwrap() {
+            return Ok((0, 0));
+        }
+
+        let fault = self.faults.next();
+        let len = match fault {
+            Fault::Interrupted => return Err(io::Error::from(io::ErrorKind::Interrupted)),
+            Fault::WouldBlock => return Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Fault::Eof => {
+                *self.eof.lock().unwrap() = true;
+                return Ok((0, 0));
+            }
+            Fault::Short(n) => n.max(1).min(buf.len()),
+            Fault::Pass | Fault::DelayFds => buf.len(),
+        };
+
+        let mut held = self.held.lock().unwrap();
+        if !held.data.is_empty() {
+            let n = len.min(held.data.len());
+            buf[..n].copy_from_slice(&held.data[..n]);
+            held.data.drain(..n);
+
+            let nfds = held.fds.len().min(fds.len());
+            fds[..nfds].copy_from_slice(&held.fds[..nfds]);
+            held.fds.drain(..nfds);
+
+            return Ok((n, nfds));
+        }
+
+        let mut inner_fds = [0 as RawFd; 8];
+        let (n, nfds) = self.inner.recv_with_fd(&mut buf[..len], &mut inner_fds)?;
+
+        if fault == Fault::DelayFds && nfds > 0 && n >= 2 {
+            let keep = n / 2;
+            held.data.extend_from_slice(&buf[keep..n]);
+            held.fds.extend_from_slice(&inner_fds[..nfds]);
+
+            return Ok((keep, 0));
+        }
+
+        let nfds = nfds.min(fds.len());
+        fds[..nfds].copy_from_slice(&inner_fds[..nfds]);
+
+        Ok((n, nfds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, Faults, FaultyRx, FaultyTx};
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::loopback;
+    use crate::test_util::TestMsg;
+    use proptest::prelude::*;
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+
+    /// `None` stands for an fd-carrying message.
+    fn message() -> impl Strategy<Value = Option<String>> {
+        prop_oneof![
+            4 => "[a-z]{0,600}".prop_map(Some),
+            1 => Just(None),
+        ]
+    }
+
+    fn fault(read_side: bool) -> impl Strategy<Value = Fault> {
+        let delay = if read_side { 1 } else { 0 };
+        prop_oneof![
+            4 => Just(Fault::Pass),
+            3 => (1usize..64).prop_map(Fault::Short),
+            1 => Just(Fault::Interrupted),
+            1 => Just(Fault::WouldBlock),
+            delay => Just(Fault::DelayFds),
+        ]
+    }
+
+    /// Sends `messages` (plus a trailing sentinel) through faulty transports
+    /// and returns what was received, stopping at the first error.
+    fn transfer(
+        messages: &[Option<String>],
+        tx_faults: Vec<Fault>,
+        rx_faults: Vec<Fault>,
+    ) -> (Vec<TestMsg>, Option<ChannelError>) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let ((tx_a, _rx_a), (_tx_b, rx_b)) = loopback::pair();
+            let (mut tx, mut rx) = Channel::from_transport::<TestMsg, TestMsg, _, _>(
+                FaultyTx::new(tx_a, Faults::new(tx_faults)),
+                FaultyRx::new(rx_b, Faults::new(rx_faults)),
+            );
+
+            let file = tempfile::tempfile().unwrap();
+            let fd = file.as_raw_fd();
+
+            let expected = messages.len() + 1;
+            let send = async {
+                for msg in messages {
+                    let msg = match msg {
+                        Some(text) => TestMsg::Text(text.clone()),
+                        None => TestMsg::Fd(fd),
+                    };
+                    tx.send(&msg).await.unwrap();
+                }
+                tx.send(&TestMsg::Text("end".to_owned())).await.unwrap();
+            };
+            let recv = async {
+                let mut received = Vec::new();
+                while received.len() < expected {
+                    match rx.recv().await {
+                        Ok(msg) => received.push(msg),
+                        Err(e) => return (received, Some(e)),
+                    }
+                }
+                (received, None)
+            };
+
+            // A stalled transfer is a failure, not a hung test run.
+            let both = async { tokio::join!(send, recv) };
+            let ((), result) = tokio::time::timeout(Duration::from_secs(5), both)
+                .await
+                .expect("transfer stalled");
+            result
+        })
+    }
+
+    fn check(sent: &[Option<String>], received: &[TestMsg]) {
+        for (sent, received) in sent.iter().zip(received) {
+            match (sent, received) {
+                (Some(text), TestMsg::Text(got)) => assert_eq!(text, got),
+                (None, TestMsg::Fd(fd)) => {
+                    assert_ne!(unsafe { nix::libc::fcntl(*fd, nix::libc::F_GETFD) }, -1);
+                    unsafe { nix::libc::close(*fd) };
+                }
+                (sent, received) => panic!("sent {sent:?}, received {received:?}"),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_faults_do_not_corrupt_stream(
+            messages in prop::collection::vec(message(), 1..40),
+            tx_faults in prop::collection::vec(fault(false), 0..200),
+            rx_faults in prop::collection::vec(fault(true), 0..200),
+        ) {
+            let (received, err) = transfer(&messages, tx_faults, rx_faults);
+
+            prop_assert!(err.is_none(), "unexpected error {:?}", err);
+            prop_assert_eq!(received.len(), messages.len() + 1);
+            check(&messages, &received);
+            prop_assert_eq!(received.last(), Some(&TestMsg::Text("end".to_owned())));
+        }
+
+        #[test]
+        fn test_eof_ends_stream_cleanly(
+            messages in prop::collection::vec(message(), 1..40),
+            rx_faults in prop::collection::vec(fault(true), 0..100),
+            eof_at in 0usize..100,
+        ) {
+            let mut rx_faults = rx_faults;
+            let eof_at = eof_at.min(rx_faults.len());
+            rx_faults.insert(eof_at, Fault::Eof);
+
+            let (received, err) = transfer(&messages, Vec::new(), rx_faults);
+
+            // Whatever arrived before EOF is an intact prefix of what was sent.
+            check(&messages, &received);
+            if received.len() <= messages.len() {
+                prop_assert!(matches!(
+                    err,
+                    Some(ChannelError::ConnectionClosedPrematurely)
+                        | Some(ChannelError::MissingFdForMessage)
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,540 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::marker::PhantomData;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+use crate::channel_redux::{make_stream, write_frame};
+use crate::error::ChannelError;
+use crate::metrics::Metrics;
+use crate::transport::{RxTransport, TxTransport};
+
+// Wire format of OpenBSD's imsg(3): a `struct imsg_hdr` in host byte order
+// followed by the payload. `len` covers the header too.
+pub const IMSG_HEADER_SIZE: usize = 16;
+pub const MAX_IMSGSIZE: usize = 16384;
+/// Set in `flags` when an fd was passed along with the message.
+pub const IMSGF_HASFD: u16 = 1;
+
+/// `struct imsg_hdr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImsgHdr {
+    pub type_: u32,
+    pub len: u16,
+    pub flags: u16,
+    pub peerid: u32,
+    pub pid: u32,
+}
+
+impl ImsgHdr {
+    fn read(buf: &[u8]) -> Self {
+        let u32_at = |i: usize| u32::from_ne_bytes(buf[i..i + 4].try_into().unwrap());
+        let u16_at = |i: usize| u16::from_ne_bytes(buf[i..i + 2].try_into().unwrap());
+
+        ImsgHdr {
+            type_: u32_at(0),
+            len: u16_at(4),
+            flags: u16_at(6),
+            peerid: u32_at(8),
+            pid: u32_at(12),
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.type_.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.len.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.flags.to_ne_bytes());
+        buf[8..12].copy_from_slice(&self.peerid.to_ne_bytes());
+        buf[12..16].copy_from_slice(&self.pid.to_ne_bytes());
+    }
+}
+
+/// A received imsg, as returned by `imsg_get`.
+#[derive(Debug)]
+pub struct Imsg {
+    pub hdr: ImsgHdr,
+    pub fd: Option<OwnedFd>,
+    pub data: Vec<u8>,
+}
+
+/// Maps a message enum onto imsg type codes and payloads.
+///
+/// Payloads are raw bytes, as C daemons send them, so each variant decides
+/// its own layout (typically a C struct in host byte order).
+pub trait ImsgCodec: Sized {
+    fn imsg_type(&self) -> u32;
+
+    fn encode(&self, data: &mut Vec<u8>);
+
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Fails with `ChannelError::UnknownImsgType` for unmapped type codes.
+    fn decode(imsg: Imsg) -> Result<Self, ChannelError>;
+}
+
+pub struct ImsgTx<M, T = OwnedWriteHalf>
+where
+    M: ImsgCodec,
+    T: TxTransport,
+{
+    stream: T,
+    pid: u32,
+    tx_buffer: Vec<u8>,
+    metrics: Arc<Metrics>,
+    phantom: PhantomData<M>,
+}
+
+pub struct ImsgRx<N, R = OwnedReadHalf>
+where
+    N: ImsgCodec,
+    R: RxTransport,
+{
+    stream: R,
+    received_fds: VecDeque<RawFd>,
+    rx_buffer: Box<[u8]>,
+    rx_buffer_offset: usize,
+    poisoned: bool,
+    metrics: Arc<Metrics>,
+    phantom: PhantomData<N>,
+}
+
+pub fn from_stream<M, N>(stream: UnixStream) -> (ImsgTx<M>, ImsgRx<N>)
+where
+    M: ImsgCodec,
+    N: ImsgCodec,
+{
+    let (rx, tx) = stream.into_split();
+
+    from_transport(tx, rx)
+}
+
+pub fn from_transport<M, N, T, R>(tx: T, rx: R) -> (ImsgTx<M, T>, ImsgRx<N, R>)
+where
+    M: ImsgCodec,
+    N: ImsgCodec,
+    T: TxTransport,
+    R: RxTransport,
+{
+    (
+        ImsgTx {
+            stream: tx,
+            pid: std::process::id(),
+            tx_buffer: Vec::with_capacity(MAX_IMSGSIZE),
+            metrics: Arc::default(),
+            phantom: PhantomData,
+        },
+        ImsgRx {
+            stream: rx,
+            received_fds: VecDeque::new(),
+            rx_buffer: vec![0u8; MAX_IMSGSIZE].into_boxed_slice(),
+            rx_buffer_offset: 0,
+            poisoned: false,
+            metrics: Arc::default(),
+            phantom: PhantomData,
+        },
+    )
+}
+
+pub fn new_from_fd<M, N>(fd: RawFd) -> io::Result<(ImsgTx<M>, ImsgRx<N>)>
+where
+    M: ImsgCodec,
+    N: ImsgCodec,
+{
+    let stream = make_stream(fd)?;
+
+    Ok(from_stream(stream))
+}
+
+impl<M, T> ImsgTx<M, T>
+where
+    M: ImsgCodec,
+    T: TxTransport,
+{
+    pub async fn send(&mut self, msg: &M) -> Result<(), ChannelError> {
+        let mut data = Vec::new();
+        msg.encode(&mut data);
+
+        self.compose(msg.imsg_type(), 0, 0, msg.fd(), &data).await
+    }
+
+    /// Like `imsg_compose`: a `pid` of 0 is replaced by our own.
+    pub async fn compose(
+        &mut self,
+        type_: u32,
+        peerid: u32,
+        pid: u32,
+        fd: Option<RawFd>,
+        data: &[u8],
+    ) -> Result<(), ChannelError> {
+        let len = IMSG_HEADER_SIZE + data.len();
+        if len > MAX_IMSGSIZE {
+            return Err(ChannelError::MessageTooLargeForTxBuffer(len, MAX_IMSGSIZE));
+        }
+
+        let hdr = ImsgHdr {
+            type_,
+            len: len as u16,
+            flags: if fd.is_some() { IMSGF_HASFD } else { 0 },
+            peerid,
+            pid: if pid == 0 { self.pid } else { pid },
+        };
+
+        self.tx_buffer.clear();
+        self.tx_buffer.resize(IMSG_HEADER_SIZE, 0);
+        hdr.write(&mut self.tx_buffer);
+        self.tx_buffer.extend_from_slice(data);
+
+        write_frame(&self.stream, &self.tx_buffer, fd, &self.metrics).await?;
+        self.metrics.frame(len, fd.iter().count());
+
+        Ok(())
+    }
+
+    /// A handle on this half's counters.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+}
+
+impl<N, R> ImsgRx<N, R>
+where
+    N: ImsgCodec,
+    R: RxTransport,
+{
+    pub async fn recv(&mut self) -> Result<N, ChannelError> {
+        N::decode(self.recv_imsg().await?)
+    }
+
+    /// A handle on this half's counters.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// True once a header with an impossible length has arrived: there is no
+    /// telling where the next imsg starts, so every later call returns
+    /// `ChannelError::Poisoned`.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Receives the next imsg without mapping it to `N`.
+    pub async fn recv_imsg(&mut self) -> Result<Imsg, ChannelError> {
+        if self.poisoned {
+            return Err(ChannelError::Poisoned(1));
+        }
+
+        loop {
+            match self.try_recv_imsg() {
+                Err(ChannelError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => {
+                    self.metrics.would_block();
+                    self.stream.readable().await?;
+                }
+                Err(e @ ChannelError::MalformedImsg(_)) => {
+                    self.metrics.error(&e);
+                    self.poisoned = true;
+                    return Err(ChannelError::Poisoned(1));
+                }
+                Err(e) => {
+                    self.metrics.error(&e);
+                    return Err(e);
+                }
+                r => return r,
+            }
+        }
+    }
+
+    fn try_recv_imsg(&mut self) -> Result<Imsg, ChannelError> {
+        let mut fd_buf = [0 as RawFd; 8];
+        let mut need_read = false;
+
+        loop {
+            if !need_read {
+                if let Some(hdr) = self.complete_hdr()? {
+                    if hdr.flags & IMSGF_HASFD == 0 || !self.received_fds.is_empty() {
+                        return Ok(self.take(hdr));
+                    }
+                    // The fd travels with the first byte but may not have
+                    // been picked up by the read that completed the message.
+                    need_read = true;
+                }
+            }
+
+            let current_read_slice = &mut self.rx_buffer[self.rx_buffer_offset..];
+            if current_read_slice.is_empty() {
+                return Err(ChannelError::MissingFdForMessage);
+            }
+
+            match self.stream.recv_with_fd(current_read_slice, &mut fd_buf) {
+                Ok((0, _)) if need_read => return Err(ChannelError::MissingFdForMessage),
+                Ok((0, _)) => return Err(ChannelError::ConnectionClosedPrematurely),
+                Ok((bytes_read, fds_received)) => {
+                    self.rx_buffer_offset += bytes_read;
+                    self.received_fds.extend(&fd_buf[..fds_received]);
+                    need_read = false;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ChannelError::Io(e)),
+            }
+        }
+    }
+
+    /// Header of the imsg at the start of the buffer, if it has been read
+    /// completely.
+    fn complete_hdr(&self) -> Result<Option<ImsgHdr>, ChannelError> {
+        if self.rx_buffer_offset < IMSG_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let hdr = ImsgHdr::read(&self.rx_buffer);
+        let len = hdr.len as usize;
+        if !(IMSG_HEADER_SIZE..=MAX_IMSGSIZE).contains(&len) {
+            return Err(ChannelError::MalformedImsg("bad length in header"));
+        }
+
+        if self.rx_buffer_offset < len {
+            return Ok(None);
+        }
+
+        Ok(Some(hdr))
+    }
+
+    fn take(&mut self, hdr: ImsgHdr) -> Imsg {
+        let len = hdr.len as usize;
+        let data = self.rx_buffer[IMSG_HEADER_SIZE..len].to_vec();
+        let fd = if hdr.flags & IMSGF_HASFD != 0 {
+            self.received_fds
+                .pop_front()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        } else {
+            None
+        };
+
+        self.rx_buffer.copy_within(len..self.rx_buffer_offset, 0);
+        self.rx_buffer_offset -= len;
+        self.metrics.frame(len, fd.iter().count());
+
+        Imsg { hdr, fd, data }
+    }
+}
+
+impl<N, R> Drop for ImsgRx<N, R>
+where
+    N: ImsgCodec,
+    R: RxTransport,
+{
+    fn drop(&mut self) {
+        // Fds whose imsg never arrived would leak.
+        for fd in self.received_fds.drain(..) {
+            unsafe { nix::libc::close(fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Imsg, ImsgCodec};
+    use crate::error::ChannelError;
+    use crate::loopback;
+    use crate::test_util::TestMsg;
+    use crate::transport::{RxTransport, TxTransport};
+    use std::fs::File;
+    use std::io::{Read, Seek, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::io::RawFd;
+
+    const IMSG_HELLO: u32 = 1;
+    const IMSG_FD: u32 = 2;
+    const IMSG_NAME: u32 = 3;
+
+    impl ImsgCodec for TestMsg {
+        fn imsg_type(&self) -> u32 {
+            match self {
+                Self::Hello(_) => IMSG_HELLO,
+                Self::Fd(_) => IMSG_FD,
+                Self::Text(_) => IMSG_NAME,
+                msg => panic!("{msg:?} has no imsg type"),
+            }
+        }
+
+        fn encode(&self, data: &mut Vec<u8>) {
+            match self {
+                Self::Hello(n) => data.extend_from_slice(&n.to_ne_bytes()),
+                Self::Text(name) => data.extend_from_slice(name.as_bytes()),
+                _ => {}
+            }
+        }
+
+        fn fd(&self) -> Option<RawFd> {
+            match self {
+                Self::Fd(fd) => Some(*fd),
+                _ => None,
+            }
+        }
+
+        fn decode(imsg: Imsg) -> Result<Self, ChannelError> {
+            match imsg.hdr.type_ {
+                IMSG_HELLO => {
+                    let n = imsg.data.try_into().map_err(|_| {
+                        ChannelError::MalformedImsg("IMSG_HELLO payload is not a u32")
+                    })?;
+                    Ok(Self::Hello(u32::from_ne_bytes(n)))
+                }
+                IMSG_FD => Ok(Self::Fd(
+                    imsg.fd
+                        .ok_or(ChannelError::MissingFdForMessage)?
+                        .into_raw_fd(),
+                )),
+                IMSG_NAME => Ok(Self::Text(String::from_utf8_lossy(&imsg.data).into_owned())),
+                t => Err(ChannelError::UnknownImsgType(t)),
+            }
+        }
+    }
+
+    /// Written from imsg(3) and `struct imsg_hdr` independently of the code
+    /// above: what `imsg_compose` puts on the wire.
+    fn reference_compose(type_: u32, peerid: u32, pid: u32, has_fd: bool, data: &[u8]) -> Vec<u8> {
+        #[repr(C)]
+        struct imsg_hdr {
+            type_: u32,
+            len: u16,
+            flags: u16,
+            peerid: u32,
+            pid: u32,
+        }
+
+        let hdr = imsg_hdr {
+            type_,
+            len: (std::mem::size_of::<imsg_hdr>() + data.len()) as u16,
+            flags: if has_fd { 1 } else { 0 },
+            peerid,
+            pid,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &hdr as *const imsg_hdr as *const u8,
+                std::mem::size_of::<imsg_hdr>(),
+            )
+        };
+
+        [bytes, data].concat()
+    }
+
+    #[tokio::test]
+    async fn test_encoding_matches_reference() {
+        let ((tx, rx), (_, raw_rx)) = loopback::pair();
+        let (mut tx, _rx) = super::from_transport::<TestMsg, TestMsg, _, _>(tx, rx);
+
+        let file = tempfile::tempfile().unwrap();
+        let pid = std::process::id();
+
+        tx.send(&TestMsg::Hello(7)).await.unwrap();
+        tx.send(&TestMsg::Fd(file.as_raw_fd())).await.unwrap();
+        tx.compose(IMSG_NAME, 9, 1234, None, b"ctl").await.unwrap();
+
+        let mut wire = Vec::new();
+        let mut fds = Vec::new();
+        let mut buf = [0u8; 256];
+        let mut fd_buf = [0 as RawFd; 1];
+        while let Ok((n, nfds)) = raw_rx.recv_with_fd(&mut buf, &mut fd_buf) {
+            wire.extend_from_slice(&buf[..n]);
+            fds.extend_from_slice(&fd_buf[..nfds]);
+        }
+
+        let expected = [
+            reference_compose(IMSG_HELLO, 0, pid, false, &7u32.to_ne_bytes()),
+            reference_compose(IMSG_FD, 0, pid, true, &[]),
+            reference_compose(IMSG_NAME, 9, 1234, false, b"ctl"),
+        ]
+        .concat();
+        assert_eq!(wire, expected);
+
+        assert_eq!(fds.len(), 1);
+        unsafe { nix::libc::close(fds[0]) };
+    }
+
+    #[tokio::test]
+    async fn test_decodes_reference_stream() {
+        let ((raw_tx, _), (tx, rx)) = loopback::pair();
+        let (_tx, mut rx) = super::from_transport::<TestMsg, TestMsg, _, _>(tx, rx);
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"from a C parent").unwrap();
+        file.rewind().unwrap();
+
+        let hello = reference_compose(IMSG_HELLO, 0, 42, false, &5u32.to_ne_bytes());
+        let fd = reference_compose(IMSG_FD, 0, 42, true, &[]);
+        let name = reference_compose(IMSG_NAME, 0, 42, false, b"parent");
+        let unknown = reference_compose(99, 0, 42, false, &[]);
+
+        // Everything in one write except the fd message, which carries its fd
+        // with its first byte as imsg_flush does.
+        raw_tx.send_with_fd(&hello, &[]).unwrap();
+        raw_tx.send_with_fd(&fd, &[file.as_raw_fd()]).unwrap();
+        raw_tx.send_with_fd(&[name, unknown].concat(), &[]).unwrap();
+        drop(file);
+
+        assert_eq!(rx.recv().await.unwrap(), TestMsg::Hello(5));
+
+        let TestMsg::Fd(fd) = rx.recv().await.unwrap() else {
+            panic!("expected fd");
+        };
+        let mut received = unsafe { File::from_raw_fd(fd) };
+        let mut out = String::new();
+        received.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "from a C parent");
+
+        assert_eq!(rx.recv().await.unwrap(), TestMsg::Text("parent".to_owned()));
+        assert!(matches!(
+            rx.recv().await,
+            Err(ChannelError::UnknownImsgType(99))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bad_header_poisons() {
+        let ((raw_tx, _), (tx, rx)) = loopback::pair();
+        let (_tx, mut rx) = super::from_transport::<TestMsg, TestMsg, _, _>(tx, rx);
+
+        let mut bad = reference_compose(IMSG_HELLO, 0, 42, false, &5u32.to_ne_bytes());
+        bad[4..6].copy_from_slice(&3u16.to_ne_bytes());
+        let hello = reference_compose(IMSG_HELLO, 0, 42, false, &5u32.to_ne_bytes());
+        raw_tx.send_with_fd(&[bad, hello].concat(), &[]).unwrap();
+
+        assert!(matches!(rx.recv().await, Err(ChannelError::Poisoned(1))));
+        assert!(rx.is_poisoned());
+        assert!(matches!(rx.recv().await, Err(ChannelError::Poisoned(1))));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_imsg_closes_its_fd() {
+        let ((raw_tx, _), (tx, rx)) = loopback::pair();
+        let (_tx, mut rx) = super::from_transport::<TestMsg, TestMsg, _, _>(tx, rx);
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let mut read_end = File::from(read_end);
+        nix::fcntl::fcntl(
+            read_end.as_raw_fd(),
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
+        )
+        .unwrap();
+
+        let unknown = reference_compose(99, 0, 42, true, &[]);
+        raw_tx
+            .send_with_fd(&unknown, &[write_end.as_raw_fd()])
+            .unwrap();
+        drop(write_end);
+
+        assert!(matches!(
+            rx.recv().await,
+            Err(ChannelError::UnknownImsgType(99))
+        ));
+
+        // Only reads EOF once the received copy of the write end is closed.
+        let mut buf = [0u8; 1];
+        assert_eq!(read_end.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -1,5 +1,21 @@
-pub mod channel;
+pub mod capture;
 pub mod channel_redux;
+pub mod credit;
+pub mod envelope;
+pub mod imsg;
+pub mod liveness;
+pub mod loopback;
+pub mod metrics;
+pub mod policy;
+pub mod priority;
+pub mod remote;
+pub mod seal;
 pub mod serializefd;
+pub mod session;
+pub mod transport;
 
 pub mod error;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
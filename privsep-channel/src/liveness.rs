@@ -0,0 +1,125 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::metrics::monotonic_nanos;
+
+/// How often a peer sends heartbeats and how many it may miss before it is
+/// considered stuck. Both ends should agree on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub misses: u32,
+}
+
+impl HeartbeatConfig {
+    /// Ticks every `interval`, for driving `ChannelTx::send_heartbeat`.
+    pub fn ticker(&self) -> Interval {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        ticker
+    }
+
+    /// How long the peer may stay silent.
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.misses
+    }
+}
+
+/// Watches a `ChannelRx` for silence (see `ChannelRx::watch_liveness`).
+pub struct Liveness {
+    last_seen: Arc<AtomicU64>,
+    timeout: Duration,
+    // When we last fired, so that one stall is only reported once per timeout.
+    fired_at: u64,
+}
+
+impl Liveness {
+    pub(crate) fn new(last_seen: Arc<AtomicU64>, config: HeartbeatConfig) -> Self {
+        Liveness {
+            last_seen,
+            timeout: config.timeout(),
+            fired_at: 0,
+        }
+    }
+
+    /// Resolves once nothing has been received for the configured timeout,
+    /// returning how long the peer has been silent. If the silence goes on,
+    /// it fires again after each further timeout.
+    pub async fn expired(&mut self) -> Duration {
+        let timeout = self.timeout.as_nanos() as u64;
+
+        loop {
+            let last_seen = self.last_seen.load(Ordering::Relaxed);
+            let deadline = last_seen.max(self.fired_at) + timeout;
+            let now = monotonic_nanos();
+
+            if now >= deadline {
+                self.fired_at = now;
+                return Duration::from_nanos(now - last_seen);
+            }
+
+            tokio::time::sleep(Duration::from_nanos(deadline - now)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeartbeatConfig;
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::serializefd::SerializeFd;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Ping(u32);
+
+    impl SerializeFd for Ping {
+        fn extract_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    const CONFIG: HeartbeatConfig = HeartbeatConfig {
+        interval: Duration::from_millis(20),
+        misses: 3,
+    };
+
+    #[tokio::test]
+    async fn test_liveness_fires_when_heartbeats_stop() {
+        let ((mut tx, _rx), (_tx, mut rx)) = Channel::loopback::<Ping, Ping>();
+        let mut liveness = rx.watch_liveness(CONFIG);
+
+        // Heartbeats keep the peer alive without surfacing from `recv`.
+        let mut ticker = CONFIG.ticker();
+        for _ in 0..10 {
+            ticker.tick().await;
+            tx.send_heartbeat().await.unwrap();
+            tokio::select! {
+                msg = rx.recv() => panic!("heartbeat delivered as {msg:?}"),
+                _ = liveness.expired() => panic!("expired while heartbeating"),
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {}
+            }
+        }
+        tx.send(&Ping(1)).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Ping(1));
+        assert_eq!(rx.metrics().snapshot().frames, 11);
+
+        // Then silence.
+        let silent = tokio::select! {
+            silent = liveness.expired() => silent,
+            msg = rx.recv() => panic!("unexpected {msg:?}"),
+        };
+        assert!(silent >= CONFIG.timeout());
+    }
+}
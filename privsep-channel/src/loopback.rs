@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::{BorrowedFd, IntoRawFd, OwnedFd};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::transport::{RxTransport, TxTransport};
+
+/// Bytes buffered in one direction before writes start returning `WouldBlock`.
+const LOOPBACK_CAPACITY: usize = 64 * 1024;
+
+/// One `send_with_fd` call. As with SCM_RIGHTS on a stream socket, the fds
+/// travel with the first byte of the segment and a read never spans into a
+/// later segment that carries fds of its own.
+struct Segment {
+    data: Vec<u8>,
+    read: usize,
+    fds: Vec<OwnedFd>,
+}
+
+struct PipeState {
+    segments: VecDeque<Segment>,
+    buffered: usize,
+    write_closed: bool,
+    read_closed: bool,
+}
+
+/// A unidirectional in-memory byte stream with fd passing.
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl Pipe {
+    fn new() -> Arc<Self> {
+        Arc::new(Pipe {
+            state: Mutex::new(PipeState {
+                segments: VecDeque::new(),
+                buffered: 0,
+                write_closed: false,
+                read_closed: false,
+            }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        })
+    }
+}
+
+/// Write end of an in-memory loopback stream.
+pub struct LoopbackTx {
+    pipe: Arc<Pipe>,
+}
+
+/// Read end of an in-memory loopback stream.
+pub struct LoopbackRx {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a connected pair of in-memory streams, one per direction.
+pub fn pair() -> ((LoopbackTx, LoopbackRx), (LoopbackTx, LoopbackRx)) {
+    let a_to_b = Pipe::new();
+    let b_to_a = Pipe::new();
+
+    (
+        (
+            LoopbackTx {
+                pipe: a_to_b.clone(),
+            },
+            LoopbackRx {
+                pipe: b_to_a.clone(),
+            },
+        ),
+        (LoopbackTx { pipe: b_to_a }, LoopbackRx { pipe: a_to_b }),
+    )
+}
+
+impl TxTransport for LoopbackTx {
+    async fn writable(&self) -> io::Result<()> {
+        loop {
+            let notified = self.pipe.writable.notified();
+            {
+                let state = self.pipe.state.lock().unwrap();
+                if state.read_closed || state.buffered < LOOPBACK_CAPACITY {
+                    return Ok(());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn send_with_fd(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let mut state = self.pipe.state.lock().unwrap();
+
+        if state.read_closed {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+
+        let space = LOOPBACK_CAPACITY - state.buffered;
+        if space == 0 {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        // Duplicate the fds as the kernel would, so the receiver owns its own copies.
+        let fds = fds
+            .iter()
+            .map(|&fd| unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned())
+            .collect::<io::Result<Vec<OwnedFd>>>()?;
+
+        let n = buf.len().min(space);
+        state.segments.push_back(Segment {
+            data: buf[..n].to_vec(),
+            read: 0,
+            fds,
+        });
+        state.buffered += n;
+        drop(state);
+
+        self.pipe.readable.notify_waiters();
+
+        Ok(n)
+    }
+}
+
+impl RxTransport for LoopbackRx {
+    async fn readable(&self) -> io::Result<()> {
+        loop {
+            let notified = self.pipe.readable.notified();
+            {
+                let state = self.pipe.state.lock().unwrap();
+                if state.write_closed || !state.segments.is_empty() {
+                    return Ok(());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn recv_with_fd(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let mut state = self.pipe.state.lock().unwrap();
+
+        if state.segments.is_empty() {
+            if state.write_closed {
+                return Ok((0, 0));
+            }
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        let mut bytes_read = 0;
+        let mut fds_read = 0;
+        let mut first = true;
+
+        while bytes_read < buf.len() {
+            let Some(segment) = state.segments.front_mut() else {
+                break;
+            };
+
+            if !segment.fds.is_empty() {
+                if !first {
+                    break;
+                }
+                // Like MSG_CTRUNC: fds that do not fit are closed.
+                for fd in segment.fds.drain(..) {
+                    if fds_read < fds.len() {
+                        fds[fds_read] = fd.into_raw_fd();
+                        fds_read += 1;
+                    }
+                }
+            }
+            first = false;
+
+            let n = (segment.data.len() - segment.read).min(buf.len() - bytes_read);
+            buf[bytes_read..bytes_read + n]
+                .copy_from_slice(&segment.data[segment.read..segment.read + n]);
+            segment.read += n;
+            bytes_read += n;
+
+            if segment.read == segment.data.len() {
+                state.segments.pop_front();
+            }
+        }
+
+        state.buffered -= bytes_read;
+        drop(state);
+
+        self.pipe.writable.notify_waiters();
+
+        Ok((bytes_read, fds_read))
+    }
+}
+
+impl Drop for LoopbackTx {
+    fn drop(&mut self) {
+        self.pipe.state.lock().unwrap().write_closed = true;
+        self.pipe.readable.notify_waiters();
+    }
+}
+
+impl Drop for LoopbackRx {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock().unwrap();
+        state.read_closed = true;
+        state.segments.clear();
+        state.buffered = 0;
+        drop(state);
+
+        self.pipe.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::test_util::TestMsg;
+    use std::fs::File;
+    use std::io::{Read, Seek, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    #[tokio::test]
+    async fn test_messages_arrive_in_order() {
+        let ((mut tx, _rx), (_tx, mut rx)) = Channel::loopback::<TestMsg, TestMsg>();
+
+        for i in 0..100 {
+            tx.send(&TestMsg::Text(format!("msg {i}"))).await.unwrap();
+        }
+
+        for i in 0..100 {
+            assert_eq!(rx.recv().await.unwrap(), TestMsg::Text(format!("msg {i}")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fd_is_duplicated() {
+        let ((mut tx, _rx), (_tx, mut rx)) = Channel::loopback::<TestMsg, TestMsg>();
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"over the loopback").unwrap();
+        file.rewind().unwrap();
+
+        tx.send(&TestMsg::Fd(file.as_raw_fd())).await.unwrap();
+        drop(file);
+
+        let TestMsg::Fd(fd) = rx.recv().await.unwrap() else {
+            panic!("expected fd");
+        };
+
+        let mut received = unsafe { File::from_raw_fd(fd) };
+        let mut out = String::new();
+        received.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "over the loopback");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected() {
+        let ((mut tx, _rx), _) = Channel::loopback::<TestMsg, TestMsg>();
+
+        let result = tx.send(&TestMsg::Blob(vec![0; 8192])).await;
+        assert!(matches!(
+            result,
+            Err(ChannelError::MessageTooLargeForTxBuffer(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_sender_closes_channel() {
+        let ((tx, _rx), (_tx, mut rx)) = Channel::loopback::<TestMsg, TestMsg>();
+        drop(tx);
+
+        assert!(matches!(
+            rx.recv().await,
+            Err(ChannelError::ConnectionClosedPrematurely)
+        ));
+    }
+}
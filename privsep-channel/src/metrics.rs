@@ -0,0 +1,264 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::error::ChannelError;
+
+/// Number of latency buckets. Bucket 0 holds latencies under 1µs and bucket
+/// `i` those in `[2^(i-1), 2^i)` µs; the last one also takes anything longer.
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Live counters for one channel half.
+///
+/// Updated with relaxed atomics so a handle (see `ChannelTx::metrics`) can be
+/// read from another task while the half is in use.
+#[derive(Default)]
+pub struct Metrics {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    fds: AtomicU64,
+    would_block: AtomicU64,
+    errors: [AtomicU64; ErrorKind::COUNT],
+    latency: [AtomicU64; LATENCY_BUCKETS],
+    latency_total_micros: AtomicU64,
+}
+
+/// Coarse classification of `ChannelError`s for counting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Io,
+    Decode,
+    TooLarge,
+    Closed,
+    MissingFd,
+    Other,
+}
+
+impl ErrorKind {
+    const COUNT: usize = 6;
+
+    pub const ALL: [ErrorKind; ErrorKind::COUNT] = [
+        ErrorKind::Io,
+        ErrorKind::Decode,
+        ErrorKind::TooLarge,
+        ErrorKind::Closed,
+        ErrorKind::MissingFd,
+        ErrorKind::Other,
+    ];
+
+    fn of(e: &ChannelError) -> Self {
+        match e {
+            ChannelError::Io(_) => ErrorKind::Io,
+            ChannelError::Bincode(_)
+            | ChannelError::UnknownFrameKind(_)
+            | ChannelError::MalformedImsg(_)
+            | ChannelError::Unauthenticated => ErrorKind::Decode,
+            ChannelError::MessageTooLargeForTxBuffer(..)
+            | ChannelError::MessageTooLargeForRxBuffer(..) => ErrorKind::TooLarge,
+            ChannelError::ConnectionClosedPrematurely => ErrorKind::Closed,
+            ChannelError::MissingFdForMessage => ErrorKind::MissingFd,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn frame(&self, bytes: usize, fds: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.fds.fetch_add(fds as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn would_block(&self) {
+        self.would_block.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self, e: &ChannelError) {
+        self.errors[ErrorKind::of(e) as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn latency(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.latency_total_micros
+            .fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        MetricsSnapshot {
+            frames: load(&self.frames),
+            bytes: load(&self.bytes),
+            fds: load(&self.fds),
+            would_block: load(&self.would_block),
+            errors: self.errors.each_ref().map(load),
+            latency: self.latency.each_ref().map(load),
+            latency_total_micros: load(&self.latency_total_micros),
+        }
+    }
+}
+
+/// A point-in-time copy of `Metrics`. Snapshots of several halves can be
+/// `merge`d, e.g. into per-edge totals.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub frames: u64,
+    pub bytes: u64,
+    pub fds: u64,
+    pub would_block: u64,
+    pub errors: [u64; ErrorKind::COUNT],
+    pub latency: [u64; LATENCY_BUCKETS],
+    pub latency_total_micros: u64,
+}
+
+impl MetricsSnapshot {
+    pub fn errors(&self, kind: ErrorKind) -> u64 {
+        self.errors[kind as usize]
+    }
+
+    pub fn merge(&mut self, other: &MetricsSnapshot) {
+        self.frames += other.frames;
+        self.bytes += other.bytes;
+        self.fds += other.fds;
+        self.would_block += other.would_block;
+        for (a, b) in self.errors.iter_mut().zip(other.errors) {
+            *a += b;
+        }
+        for (a, b) in self.latency.iter_mut().zip(other.latency) {
+            *a += b;
+        }
+        self.latency_total_micros += other.latency_total_micros;
+    }
+
+    /// Number of frames whose latency was measured.
+    pub fn latency_count(&self) -> u64 {
+        self.latency.iter().sum()
+    }
+
+    pub fn latency_mean(&self) -> Option<Duration> {
+        let count = self.latency_count();
+        (count > 0).then(|| Duration::from_micros(self.latency_total_micros / count))
+    }
+
+    /// Upper bound of the bucket holding the `p`th percentile (0.0..=1.0).
+    pub fn latency_percentile(&self, p: f64) -> Option<Duration> {
+        let count = self.latency_count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((count as f64 * p).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (bucket, n) in self.latency.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << bucket));
+            }
+        }
+
+        None
+    }
+}
+
+/// `CLOCK_MONOTONIC` in nanoseconds. Unlike `Instant` it is comparable
+/// between processes on the same host, so it can travel in a frame.
+pub(crate) fn monotonic_nanos() -> u64 {
+    let mut ts = nix::libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { nix::libc::clock_gettime(nix::libc::CLOCK_MONOTONIC, &mut ts) };
+
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorKind, Metrics, MetricsSnapshot};
+    use crate::channel_redux::Channel;
+    use crate::test_util::TestMsg;
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_both_halves_count_the_same_traffic() {
+        let ((tx, _rx), (_tx, mut rx)) = Channel::loopback::<TestMsg, TestMsg>();
+        let mut tx = tx.with_timestamps();
+
+        let file = tempfile::tempfile().unwrap();
+        for i in 0..10 {
+            tx.send(&TestMsg::Text(format!("msg {i}"))).await.unwrap();
+        }
+        tx.send(&TestMsg::Fd(file.as_raw_fd())).await.unwrap();
+        for _ in 0..11 {
+            rx.recv().await.unwrap();
+        }
+
+        let sent = tx.metrics().snapshot();
+        let received = rx.metrics().snapshot();
+
+        assert_eq!(sent.frames, 11);
+        assert_eq!(sent.fds, 1);
+        assert_eq!(received.frames, sent.frames);
+        assert_eq!(received.bytes, sent.bytes);
+        assert_eq!(received.fds, sent.fds);
+
+        assert_eq!(sent.latency_count(), 0);
+        assert_eq!(received.latency_count(), 11);
+        assert!(received.latency_percentile(0.99).unwrap() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_counted_by_kind() {
+        let ((mut tx, _rx), (_tx, mut rx)) = Channel::loopback::<TestMsg, TestMsg>();
+        let tx_metrics = tx.metrics();
+
+        assert!(tx.send(&TestMsg::Blob(vec![0; 8192])).await.is_err());
+        drop(tx);
+        assert!(rx.recv().await.is_err());
+
+        let sent = tx_metrics.snapshot();
+        assert_eq!(sent.errors(ErrorKind::TooLarge), 1);
+        assert_eq!(sent.frames, 0);
+
+        let received = rx.metrics().snapshot();
+        assert_eq!(received.errors(ErrorKind::Closed), 1);
+        assert_eq!(
+            ErrorKind::ALL
+                .iter()
+                .map(|&kind| received.errors(kind))
+                .sum::<u64>(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_latency_buckets() {
+        let metrics = Metrics::default();
+        for micros in [0, 1, 3, 900, 1000, 1500] {
+            metrics.latency(Duration::from_micros(micros));
+        }
+        let snapshot = metrics.snapshot();
+
+        assert_eq!(snapshot.latency[0], 1);
+        assert_eq!(snapshot.latency[1], 1);
+        assert_eq!(snapshot.latency[2], 1);
+        assert_eq!(snapshot.latency[10], 2);
+        assert_eq!(snapshot.latency[11], 1);
+        assert_eq!(
+            snapshot.latency_percentile(0.5),
+            Some(Duration::from_micros(4))
+        );
+        assert_eq!(
+            snapshot.latency_percentile(1.0),
+            Some(Duration::from_micros(2048))
+        );
+
+        let mut total = MetricsSnapshot::default();
+        total.merge(&snapshot);
+        total.merge(&snapshot);
+        assert_eq!(total.latency_count(), 12);
+    }
+}
@@ -0,0 +1,554 @@
+use bincode::Options;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Limits applied when decoding frames from a (possibly hostile) peer.
+///
+/// The encoding itself is unchanged: `ChannelTx` uses `bincode::serialize`,
+/// i.e. fixed-width little-endian integers, which is what the decoder expects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodePolicy {
+    /// Maximum number of payload bytes bincode may consume, including the
+    /// lengths it pre-allocates for strings and vectors.
+    pub max_payload: usize,
+    /// Reject a frame whose payload has bytes left over after decoding.
+    pub reject_trailing: bool,
+    /// Maximum nesting of sequences, maps, enums, options and newtypes.
+    pub max_depth: usize,
+    /// Maximum number of elements in any one sequence or map.
+    pub max_collection_len: usize,
+    /// Number of malformed frames tolerated before the channel is poisoned.
+    pub error_budget: u32,
+}
+
+impl Default for DecodePolicy {
+    fn default() -> Self {
+        DecodePolicy {
+            max_payload: crate::channel_redux::MAX_PAYLOAD_SIZE,
+            reject_trailing: true,
+            max_depth: 32,
+            max_collection_len: 1024,
+            error_budget: 8,
+        }
+    }
+}
+
+impl DecodePolicy {
+    /// Decodes `payload` as an `N` within the limits of this policy.
+    pub fn decode<N: DeserializeOwned>(&self, payload: &[u8]) -> bincode::Result<N> {
+        let limits = Limits {
+            max_depth: self.max_depth,
+            max_collection_len: self.max_collection_len,
+            depth: Cell::new(0),
+        };
+        let seed = LimitedSeed {
+            seed: PhantomData::<N>,
+            limits: &limits,
+        };
+        let options = bincode::options()
+            .with_fixint_encoding()
+            .with_limit(self.max_payload as u64);
+
+        if self.reject_trailing {
+            options
+                .reject_trailing_bytes()
+                .deserialize_seed(seed, payload)
+        } else {
+            options
+                .allow_trailing_bytes()
+                .deserialize_seed(seed, payload)
+        }
+    }
+}
+
+struct Limits {
+    max_depth: usize,
+    max_collection_len: usize,
+    depth: Cell<usize>,
+}
+
+impl Limits {
+    fn enter<E: de::Error>(&self) -> Result<(), E> {
+        let depth = self.depth.get() + 1;
+        if depth > self.max_depth {
+            return Err(E::custom(format_args!(
+                "nesting exceeds maximum depth {}",
+                self.max_depth
+            )));
+        }
+        self.depth.set(depth);
+
+        Ok(())
+    }
+
+    fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    fn check_len<E: de::Error>(&self, len: Option<usize>) -> Result<(), E> {
+        match len {
+            Some(len) if len > self.max_collection_len => Err(E::custom(format_args!(
+                "collection of {len} elements exceeds maximum {}",
+                self.max_collection_len
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `f` one level deeper.
+    fn nested<T, E: de::Error>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        self.enter()?;
+        let result = f();
+        self.leave();
+
+        result
+    }
+}
+
+/// Wraps a `DeserializeSeed` so that whatever it deserializes goes through
+/// `Limited`. `PhantomData<T>` is the seed for a plain `T: Deserialize`.
+struct LimitedSeed<'a, S> {
+    seed: S,
+    limits: &'a Limits,
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for LimitedSeed<'_, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.seed.deserialize(Limited {
+            inner: deserializer,
+            limits: self.limits,
+        })
+    }
+}
+
+/// A `Deserializer` that hands every visitor a `LimitedVisitor`.
+struct Limited<'a, D> {
+    inner: D,
+    limits: &'a Limits,
+}
+
+impl<'a, D> Limited<'a, D> {
+    fn visitor<V>(&self, visitor: V) -> LimitedVisitor<'a, V> {
+        LimitedVisitor {
+            inner: visitor,
+            limits: self.limits,
+        }
+    }
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+                let visitor = self.visitor(visitor);
+                self.inner.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Limited<'_, D> {
+    type Error = D::Error;
+
+    forward_deserialize! {
+        deserialize_any deserialize_bool
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+        deserialize_f32 deserialize_f64 deserialize_char deserialize_str deserialize_string
+        deserialize_bytes deserialize_byte_buf deserialize_option deserialize_unit
+        deserialize_seq deserialize_map deserialize_identifier deserialize_ignored_any
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = self.visitor(visitor);
+        self.inner.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = self.visitor(visitor);
+        self.inner.deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = self.visitor(visitor);
+        self.inner.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = self.visitor(visitor);
+        self.inner.deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = self.visitor(visitor);
+        self.inner.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = self.visitor(visitor);
+        self.inner.deserialize_enum(name, variants, visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+/// A `Visitor` that counts nesting and checks collection lengths before
+/// handing over to the real visitor.
+struct LimitedVisitor<'a, V> {
+    inner: V,
+    limits: &'a Limits,
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty))*) => {
+        $(
+            fn $method<E: de::Error>(self, v: $ty) -> Result<Self::Value, E> {
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for LimitedVisitor<'_, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(formatter)
+    }
+
+    forward_visit! {
+        visit_bool(bool)
+        visit_i8(i8) visit_i16(i16) visit_i32(i32) visit_i64(i64) visit_i128(i128)
+        visit_u8(u8) visit_u16(u16) visit_u32(u32) visit_u64(u64) visit_u128(u128)
+        visit_f32(f32) visit_f64(f64) visit_char(char)
+        visit_str(&str) visit_borrowed_str(&'de str) visit_string(String)
+        visit_bytes(&[u8]) visit_borrowed_bytes(&'de [u8]) visit_byte_buf(Vec<u8>)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let limits = self.limits;
+        limits.nested(|| {
+            self.inner.visit_some(Limited {
+                inner: deserializer,
+                limits,
+            })
+        })
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        let limits = self.limits;
+        limits.nested(|| {
+            self.inner.visit_newtype_struct(Limited {
+                inner: deserializer,
+                limits,
+            })
+        })
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let limits = self.limits;
+        limits.check_len(seq.size_hint())?;
+        limits.nested(|| self.inner.visit_seq(LimitedAccess { inner: seq, limits }))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let limits = self.limits;
+        limits.check_len(map.size_hint())?;
+        limits.nested(|| self.inner.visit_map(LimitedAccess { inner: map, limits }))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        let limits = self.limits;
+        limits.nested(|| {
+            self.inner.visit_enum(LimitedAccess {
+                inner: data,
+                limits,
+            })
+        })
+    }
+}
+
+/// Wraps the sequence, map, enum and variant accessors handed to visitors so
+/// that their elements are deserialized through `Limited` as well.
+struct LimitedAccess<'a, A> {
+    inner: A,
+    limits: &'a Limits,
+}
+
+impl<'a, A> LimitedAccess<'a, A> {
+    fn seed<S>(&self, seed: S) -> LimitedSeed<'a, S> {
+        LimitedSeed {
+            seed,
+            limits: self.limits,
+        }
+    }
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for LimitedAccess<'_, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, A::Error> {
+        let seed = self.seed(seed);
+        self.inner.next_element_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for LimitedAccess<'_, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        let seed = self.seed(seed);
+        self.inner.next_key_seed(seed)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, A::Error> {
+        let seed = self.seed(seed);
+        self.inner.next_value_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, 'de, A: EnumAccess<'de>> EnumAccess<'de> for LimitedAccess<'a, A> {
+    type Error = A::Error;
+    type Variant = LimitedAccess<'a, A::Variant>;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self::Variant), A::Error> {
+        let limits = self.limits;
+        let seed = self.seed(seed);
+        let (value, variant) = self.inner.variant_seed(seed)?;
+
+        Ok((
+            value,
+            LimitedAccess {
+                inner: variant,
+                limits,
+            },
+        ))
+    }
+}
+
+impl<'de, A: VariantAccess<'de>> VariantAccess<'de> for LimitedAccess<'_, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.inner.unit_variant()
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, A::Error> {
+        let seed = self.seed(seed);
+        self.inner.newtype_variant_seed(seed)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        let visitor = LimitedVisitor {
+            inner: visitor,
+            limits: self.limits,
+        };
+        self.inner.tuple_variant(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        let visitor = LimitedVisitor {
+            inner: visitor,
+            limits: self.limits,
+        };
+        self.inner.struct_variant(fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecodePolicy;
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::loopback;
+    use crate::serializefd::SerializeFd;
+    use crate::transport::TxTransport;
+    use bincode::serialize;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::os::unix::io::RawFd;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Nested {
+        Leaf(u8),
+        Node(Box<Nested>),
+        List(Vec<()>),
+    }
+
+    impl SerializeFd for Nested {
+        fn extract_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn nest(depth: usize) -> Nested {
+        (0..depth).fold(Nested::Leaf(1), |n, _| Nested::Node(Box::new(n)))
+    }
+
+    #[test]
+    fn test_well_formed_payload_decodes() {
+        let msg = nest(4);
+        let payload = serialize(&msg).unwrap();
+
+        assert_eq!(
+            DecodePolicy::default().decode::<Nested>(&payload).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn test_trailing_bytes_are_rejected() {
+        let mut payload = serialize(&Nested::Leaf(7)).unwrap();
+        payload.push(0);
+
+        let policy = DecodePolicy::default();
+        assert!(policy.decode::<Nested>(&payload).is_err());
+
+        let lenient = DecodePolicy {
+            reject_trailing: false,
+            ..policy
+        };
+        assert_eq!(lenient.decode::<Nested>(&payload).unwrap(), Nested::Leaf(7));
+    }
+
+    #[test]
+    fn test_deep_nesting_is_rejected() {
+        let policy = DecodePolicy {
+            max_depth: 8,
+            ..DecodePolicy::default()
+        };
+
+        assert!(policy
+            .decode::<Nested>(&serialize(&nest(7)).unwrap())
+            .is_ok());
+        assert!(policy
+            .decode::<Nested>(&serialize(&nest(8)).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_long_collection_is_rejected() {
+        // Eight bytes claim a billion zero-sized elements.
+        let mut payload = serialize(&Nested::List(Vec::new())).unwrap();
+        let len = payload.len();
+        payload[len - 8..].copy_from_slice(&1_000_000_000u64.to_le_bytes());
+
+        assert!(DecodePolicy::default().decode::<Nested>(&payload).is_err());
+    }
+
+    #[test]
+    fn test_declared_length_beyond_limit_is_rejected() {
+        let mut payload = serialize(&String::from("short")).unwrap();
+        payload[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(DecodePolicy::default().decode::<String>(&payload).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_error_budget_poisons_channel() {
+        let ((raw_tx, _), (tx, rx)) = loopback::pair();
+        let (_tx, rx) = Channel::from_transport::<Nested, Nested, _, _>(tx, rx);
+        let mut rx = rx.with_policy(DecodePolicy {
+            error_budget: 2,
+            ..DecodePolicy::default()
+        });
+
+        let mut bad = serialize(&Nested::Leaf(1)).unwrap();
+        bad.push(0xff);
+        for _ in 0..3 {
+            raw_tx.send_with_fd(&frame(&bad), &[]).unwrap();
+        }
+        let good = serialize(&Nested::Leaf(2)).unwrap();
+        raw_tx.send_with_fd(&frame(&good), &[]).unwrap();
+
+        assert!(matches!(rx.recv().await, Err(ChannelError::Bincode(_))));
+        assert!(matches!(rx.recv().await, Err(ChannelError::Bincode(_))));
+        assert!(matches!(rx.recv().await, Err(ChannelError::Poisoned(3))));
+
+        // Later frames are never decoded, well-formed or not.
+        assert!(rx.is_poisoned());
+        assert!(matches!(rx.recv().await, Err(ChannelError::Poisoned(3))));
+        assert!(matches!(
+            rx.recv_raw().await,
+            Err(ChannelError::Poisoned(3))
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::channel_redux::{ChannelRx, ChannelTx};
+use crate::error::ChannelError;
+use crate::metrics::Metrics;
+use crate::serializefd::SerializeFd;
+use crate::transport::{RxTransport, TxTransport};
+
+/// Sends over two channels to the same peer: messages for which
+/// `SerializeFd::is_priority` holds go over a separate lane, so they are not
+/// stuck behind bulk traffic already queued in the socket.
+///
+/// Ordering only holds within a lane. A priority message may overtake bulk
+/// messages sent before it, never the other way round once both are queued.
+pub struct PriorityTx<M, T = OwnedWriteHalf>
+where
+    M: SerializeFd,
+    M: Serialize,
+    T: TxTransport,
+{
+    bulk: ChannelTx<M, T>,
+    priority: ChannelTx<M, T>,
+}
+
+/// Receiving end of a `PriorityTx`: whenever both lanes have a message ready,
+/// the priority one wins.
+pub struct PriorityRx<N, R = OwnedReadHalf>
+where
+    N: SerializeFd,
+    N: DeserializeOwned,
+    R: RxTransport,
+{
+    bulk: ChannelRx<N, R>,
+    priority: ChannelRx<N, R>,
+}
+
+impl<M, T> PriorityTx<M, T>
+where
+    M: SerializeFd,
+    M: Serialize,
+    T: TxTransport,
+{
+    pub fn new(bulk: ChannelTx<M, T>, priority: ChannelTx<M, T>) -> Self {
+        PriorityTx { bulk, priority }
+    }
+
+    pub async fn send(&mut self, msg: &M) -> Result<(), ChannelError> {
+        if msg.is_priority() {
+            self.priority.send(msg).await
+        } else {
+            self.bulk.send(msg).await
+        }
+    }
+
+    /// Metrics of the bulk and priority lanes, in that order.
+    pub fn metrics(&self) -> [Arc<Metrics>; 2] {
+        [self.bulk.metrics(), self.priority.metrics()]
+    }
+}
+
+impl<N, R> PriorityRx<N, R>
+where
+    N: SerializeFd,
+    N: DeserializeOwned,
+    R: RxTransport,
+{
+    pub fn new(bulk: ChannelRx<N, R>, priority: ChannelRx<N, R>) -> Self {
+        PriorityRx { bulk, priority }
+    }
+
+    pub async fn recv(&mut self) -> Result<N, ChannelError> {
+        tokio::select! {
+            biased;
+            msg = self.priority.recv() => msg,
+            msg = self.bulk.recv() => msg,
+        }
+    }
+
+    /// Metrics of the bulk and priority lanes, in that order.
+    pub fn metrics(&self) -> [Arc<Metrics>; 2] {
+        [self.bulk.metrics(), self.priority.metrics()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PriorityRx, PriorityTx};
+    use crate::channel_redux::Channel;
+    use crate::test_util::TestMsg;
+
+    #[tokio::test]
+    async fn test_priority_messages_overtake_queued_bulk() {
+        let ((bulk_tx, _), (_, bulk_rx)) = Channel::loopback::<TestMsg, TestMsg>();
+        let ((lane_tx, _), (_, lane_rx)) = Channel::loopback::<TestMsg, TestMsg>();
+        let mut tx = PriorityTx::new(bulk_tx, lane_tx);
+        let mut rx = PriorityRx::new(bulk_rx, lane_rx);
+
+        for i in 0..100 {
+            tx.send(&TestMsg::Hello(i)).await.unwrap();
+        }
+        tx.send(&TestMsg::Stop).await.unwrap();
+
+        assert_eq!(rx.recv().await.unwrap(), TestMsg::Stop);
+        for i in 0..100 {
+            assert_eq!(rx.recv().await.unwrap(), TestMsg::Hello(i));
+        }
+
+        let [bulk, priority] = tx.metrics().map(|m| m.snapshot().frames);
+        assert_eq!((bulk, priority), (100, 1));
+    }
+}
@@ -0,0 +1,193 @@
+use nix::unistd::Pid;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+use crate::channel_redux::{ChannelRx, ChannelTx};
+use crate::error::ChannelError;
+use crate::serializefd::SerializeFd;
+use crate::transport::{RxTransport, TxTransport};
+
+/// An error raised in another process, flattened so it can cross a channel.
+///
+/// Message enums carry it in an `Error(RemoteError)` variant; the receiver
+/// gets the original error's type, message and `source()` chain as strings,
+/// along with where it came from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RemoteError {
+    /// Type name of the original error, e.g. `ParserError`.
+    pub kind: String,
+    pub message: String,
+    /// Messages of the original error's sources, outermost first.
+    pub chain: Vec<String>,
+    pub subsystem: String,
+    pub pid: i32,
+}
+
+impl RemoteError {
+    pub fn new<E: Error>(subsystem: &str, pid: Pid, err: &E) -> Self {
+        let kind = std::any::type_name::<E>();
+        let kind = kind.rsplit("::").next().unwrap_or(kind);
+
+        let mut chain = Vec::new();
+        let mut source = err.source();
+        while let Some(cause) = source {
+            chain.push(cause.to_string());
+            source = cause.source();
+        }
+
+        RemoteError {
+            kind: kind.to_owned(),
+            message: err.to_string(),
+            chain,
+            subsystem: subsystem.to_owned(),
+            pid: pid.as_raw(),
+        }
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}]: {}: {}",
+            self.subsystem, self.pid, self.kind, self.message
+        )?;
+        for cause in &self.chain {
+            write!(f, ": {cause}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for RemoteError {}
+
+/// A reply message that may carry a `RemoteError` instead of a result.
+pub trait Reply: Sized {
+    fn into_result(self) -> Result<Self, RemoteError>;
+}
+
+/// Sends `msg` and waits for the peer's reply. An error reply is returned as
+/// `ChannelError::Remote`, so the caller handles it like any local failure.
+pub async fn call<M, N, T, R>(
+    tx: &mut ChannelTx<M, T>,
+    rx: &mut ChannelRx<N, R>,
+    msg: &M,
+) -> Result<N, ChannelError>
+where
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    N: Reply,
+    T: TxTransport,
+    R: RxTransport,
+{
+    tx.send(msg).await?;
+    let reply = rx.recv().await?;
+
+    Ok(reply.into_result()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{call, RemoteError, Reply};
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::serializefd::SerializeFd;
+    use nix::unistd::getpid;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::os::unix::io::RawFd;
+    use thiserror::Error;
+
+    #[derive(Debug, Error)]
+    enum DivideError {
+        #[error("cannot divide {0}")]
+        Divide(i64, #[source] ZeroError),
+    }
+
+    #[derive(Debug, Error)]
+    #[error("divisor is zero")]
+    struct ZeroError;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Request {
+        Divide(i64, i64),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Response {
+        Quotient(i64),
+        Error(RemoteError),
+    }
+
+    impl SerializeFd for Request {
+        fn extract_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    impl SerializeFd for Response {
+        fn extract_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    impl Reply for Response {
+        fn into_result(self) -> Result<Self, RemoteError> {
+            match self {
+                Self::Error(e) => Err(e),
+                msg => Ok(msg),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_reply_surfaces_as_err() {
+        let ((mut tx, mut rx), (mut tx_server, mut rx_server)) =
+            Channel::loopback::<Request, Response>();
+
+        let server = tokio::spawn(async move {
+            while let Ok(Request::Divide(a, b)) = rx_server.recv().await {
+                let reply = match b {
+                    0 => Response::Error(RemoteError::new(
+                        "server",
+                        getpid(),
+                        &DivideError::Divide(a, ZeroError),
+                    )),
+                    b => Response::Quotient(a / b),
+                };
+                tx_server.send(&reply).await.unwrap();
+            }
+        });
+
+        let ok = call(&mut tx, &mut rx, &Request::Divide(6, 3))
+            .await
+            .unwrap();
+        assert_eq!(ok, Response::Quotient(2));
+
+        let Err(ChannelError::Remote(e)) = call(&mut tx, &mut rx, &Request::Divide(6, 0)).await
+        else {
+            panic!("expected remote error");
+        };
+        assert_eq!(e.kind, "DivideError");
+        assert_eq!(e.message, "cannot divide 6");
+        assert_eq!(e.chain, ["divisor is zero"]);
+        assert_eq!(e.subsystem, "server");
+        assert_eq!(e.pid, getpid().as_raw());
+
+        drop(tx);
+        server.await.unwrap();
+    }
+}
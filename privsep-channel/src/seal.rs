@@ -0,0 +1,254 @@
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use std::io::{self, Read, Write};
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+
+use crate::error::ChannelError;
+
+/// Bytes a sealed frame grows by.
+pub(crate) const TAG_BYTES: usize = 16;
+const KEY_BYTES: usize = 32;
+
+/// Keys for one end of a sealed channel (see `ChannelTx::with_seal`), one per
+/// direction so that the two ends never use a nonce under the same key.
+///
+/// The controller generates them and hands `peer()` to the child at spawn
+/// (`export`, then `import` in the child), so they never cross the
+/// transport they protect. In privsep-framework, `Supervisor::seal` does
+/// this for a subsystem.
+#[derive(Clone)]
+pub struct SealKeys {
+    send: Key,
+    recv: Key,
+}
+
+impl SealKeys {
+    pub fn generate() -> Self {
+        SealKeys {
+            send: ChaCha20Poly1305::generate_key(&mut OsRng),
+            recv: ChaCha20Poly1305::generate_key(&mut OsRng),
+        }
+    }
+
+    /// The keys for the other end of the channel.
+    pub fn peer(&self) -> Self {
+        SealKeys {
+            send: self.recv,
+            recv: self.send,
+        }
+    }
+
+    /// A socket holding `peer()`, for a child to inherit and `import` from,
+    /// so the keys never show in its environment.
+    pub fn export(&self) -> io::Result<OwnedFd> {
+        let (theirs, mut ours) = UnixStream::pair()?;
+
+        // Far less than a socket buffers, so this never blocks.
+        let peer = self.peer();
+        ours.write_all(&peer.send)?;
+        ours.write_all(&peer.recv)?;
+
+        Ok(theirs.into())
+    }
+
+    /// Reads the keys our parent passed us with `export`.
+    pub fn import(fd: OwnedFd) -> io::Result<Self> {
+        let mut bytes = [0; 2 * KEY_BYTES];
+        UnixStream::from(fd).read_exact(&mut bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed channel keys: {e}"),
+            )
+        })?;
+
+        Ok(SealKeys {
+            send: *Key::from_slice(&bytes[..KEY_BYTES]),
+            recv: *Key::from_slice(&bytes[KEY_BYTES..]),
+        })
+    }
+
+    pub(crate) fn sealer(&self) -> Sealer {
+        Sealer::new(&self.send)
+    }
+
+    pub(crate) fn opener(&self) -> Sealer {
+        Sealer::new(&self.recv)
+    }
+}
+
+/// Seals or opens the frames of one direction. The nonce is the number of
+/// frames seen so far, so a frame that is replayed, dropped or reordered
+/// fails to open.
+pub(crate) struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl Sealer {
+    fn new(key: &Key) -> Self {
+        Sealer {
+            cipher: ChaCha20Poly1305::new(key),
+            counter: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn nonce(&self) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+
+        nonce
+    }
+
+    /// Returns a sealed copy of `frame`: everything after the prefix is
+    /// encrypted and a tag appended, and the prefix, which stays in the clear
+    /// for framing, is authenticated. The nonce is only used up by `sent`, so
+    /// a frame that fails to go out is sealed again with the same one.
+    pub(crate) fn seal(&mut self, frame: &[u8], prefix_bytes: usize) -> &[u8] {
+        let nonce = self.nonce();
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(frame);
+        let (prefix, body) = self.buffer.split_at_mut(prefix_bytes);
+        let len = u32::from_be_bytes(prefix.try_into().unwrap()) + TAG_BYTES as u32;
+        prefix.copy_from_slice(&len.to_be_bytes());
+
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, prefix, body)
+            .expect("frames are far below the ChaCha20Poly1305 size limit");
+        self.buffer.extend_from_slice(&tag);
+
+        &self.buffer
+    }
+
+    /// Moves on to the next nonce once the sealed frame has been written.
+    pub(crate) fn sent(&mut self) {
+        self.counter += 1;
+    }
+
+    /// Decrypts `body` in place, checking it against `prefix` and `tag`.
+    pub(crate) fn open(
+        &mut self,
+        prefix: &[u8],
+        body: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), ChannelError> {
+        let nonce = self.nonce();
+        self.counter += 1;
+
+        self.cipher
+            .decrypt_in_place_detached(&nonce, prefix, body, Tag::from_slice(tag))
+            .map_err(|_| ChannelError::Unauthenticated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SealKeys;
+    use crate::channel_redux::{Channel, ChannelRx, ChannelTx};
+    use crate::error::ChannelError;
+    use crate::test_util::TestMsg;
+    use std::os::fd::AsRawFd;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{tcp, TcpListener, TcpStream};
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+        let mut frame = vec![0; 4];
+        stream.read_exact(&mut frame).await.unwrap();
+        let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) & 0x00ff_ffff;
+        frame.resize(4 + len as usize, 0);
+        stream.read_exact(&mut frame[4..]).await.unwrap();
+
+        frame
+    }
+
+    // A sealed channel whose bytes pass through the returned "proxy" streams:
+    // whatever A sends arrives at the first, and what's written to the second
+    // reaches B.
+    async fn sealed_over_proxy() -> (
+        ChannelTx<TestMsg, tcp::OwnedWriteHalf>,
+        TcpStream,
+        TcpStream,
+        ChannelRx<TestMsg, tcp::OwnedReadHalf>,
+    ) {
+        let keys = SealKeys::generate();
+        let (a, proxy_in) = tcp_pair().await;
+        let (proxy_out, b) = tcp_pair().await;
+        let (tx, _) = Channel::from_tcp::<TestMsg, TestMsg>(a);
+        let (_, rx) = Channel::from_tcp::<TestMsg, TestMsg>(b);
+
+        (
+            tx.with_seal(&keys),
+            proxy_in,
+            proxy_out,
+            rx.with_seal(&keys.peer()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_sealed_frames_reject_replay() {
+        let (mut tx, mut proxy_in, mut proxy_out, mut rx) = sealed_over_proxy().await;
+
+        let hello = TestMsg::Text("hello".to_owned());
+        tx.send(&hello).await.unwrap();
+        let frame = read_frame(&mut proxy_in).await;
+        assert!(!frame.windows(5).any(|w| w == b"hello"));
+
+        proxy_out.write_all(&frame).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), hello);
+
+        proxy_out.write_all(&frame).await.unwrap();
+        assert!(matches!(rx.recv().await, Err(ChannelError::Poisoned(1))));
+
+        // Fds can't cross TCP.
+        let file = tempfile::tempfile().unwrap();
+        let fd = TestMsg::Fd(file.as_raw_fd());
+        assert!(matches!(
+            tx.send(&fd).await,
+            Err(ChannelError::FdPassingUnsupported)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refused_fd_keeps_nonces_in_step() {
+        let (mut tx, mut proxy_in, mut proxy_out, mut rx) = sealed_over_proxy().await;
+
+        let file = tempfile::tempfile().unwrap();
+        let fd = TestMsg::Fd(file.as_raw_fd());
+        assert!(matches!(
+            tx.send(&fd).await,
+            Err(ChannelError::FdPassingUnsupported)
+        ));
+
+        let hello = TestMsg::Text("hello".to_owned());
+        tx.send(&hello).await.unwrap();
+        let frame = read_frame(&mut proxy_in).await;
+        proxy_out.write_all(&frame).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), hello);
+    }
+
+    #[tokio::test]
+    async fn test_tampered_frame_fails_to_open() {
+        let (mut tx, mut proxy_in, mut proxy_out, mut rx) = sealed_over_proxy().await;
+
+        tx.send(&TestMsg::Text("pay 10".to_owned())).await.unwrap();
+        let mut frame = read_frame(&mut proxy_in).await;
+        let last = frame.len() - super::TAG_BYTES - 1;
+        frame[last] ^= 1;
+
+        proxy_out.write_all(&frame).await.unwrap();
+        assert!(matches!(rx.recv().await, Err(ChannelError::Poisoned(1))));
+        assert!(rx.is_poisoned());
+    }
+}
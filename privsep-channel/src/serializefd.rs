@@ -7,6 +7,12 @@ pub trait SerializeFd {
     fn compose_fd(self, received_fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError>
     where
         Self: std::marker::Sized;
+
+    /// Whether this message should overtake bulk traffic when sent over a
+    /// `PriorityTx` (e.g. a shutdown request).
+    fn is_priority(&self) -> bool {
+        false
+    }
 }
 
 pub fn pop_fd(fds: &mut VecDeque<RawFd>) -> Result<RawFd, ChannelError> {
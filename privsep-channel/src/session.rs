@@ -0,0 +1,224 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::channel_redux::{ChannelRx, ChannelTx};
+use crate::error::ChannelError;
+use crate::serializefd::SerializeFd;
+use crate::transport::{RxTransport, TxTransport};
+
+/// A single variant of a message enum `M`, usable as a step in a session protocol.
+///
+/// Implement this on a marker type (e.g. `struct PeerSocket;`) to name one variant
+/// of a message enum and to move its payload in and out of the enum, or have
+/// `variant!` do it.
+pub trait Variant<M> {
+    type Value;
+
+    const NAME: &'static str;
+
+    fn wrap(value: Self::Value) -> M;
+    fn unwrap(msg: M) -> Result<Self::Value, M>;
+}
+
+/// Declares the marker type `$marker` and implements `Variant` on it for the
+/// variant of the same name in each of the listed enums, e.g.
+/// `variant!(pub PeerSocket(RawFd): CtrlParseMsg, CtrlEngineMsg);`.
+#[macro_export]
+macro_rules! variant {
+    ($vis:vis $marker:ident($value:ty): $($msg:ident),+ $(,)?) => {
+        $vis struct $marker;
+
+        $(
+            impl $crate::session::Variant<$msg> for $marker {
+                type Value = $value;
+
+                const NAME: &'static str = stringify!($marker);
+
+                fn wrap(value: $value) -> $msg {
+                    $msg::$marker(value)
+                }
+
+                fn unwrap(msg: $msg) -> ::std::result::Result<$value, $msg> {
+                    match msg {
+                        $msg::$marker(value) => Ok(value),
+                        msg => Err(msg),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+/// Protocol step: receive variant `V`, then continue with protocol `P`.
+pub struct Recv<V, P>(PhantomData<(V, P)>);
+
+/// Protocol step: send variant `V`, then continue with protocol `P`.
+pub struct Transmit<V, P>(PhantomData<(V, P)>);
+
+/// End of the handshake: the channel is handed back for free-form use.
+pub struct Open;
+
+/// A channel pair that may only be used according to protocol `P`.
+///
+/// Each step consumes the session and returns one typed for the next step, so
+/// calling `recv` where the protocol says `send` (or skipping a step) does not
+/// compile. A message that arrives out of order is returned as
+/// `ChannelError::UnexpectedMessage` rather than a panic.
+///
+/// ```compile_fail
+/// # use privsep_channel::channel_redux::Channel;
+/// # use privsep_channel::error::ChannelError;
+/// # use privsep_channel::serializefd::SerializeFd;
+/// # use privsep_channel::session::{Open, Recv, Session};
+/// # use serde::{Deserialize, Serialize};
+/// # use std::collections::VecDeque;
+/// # use std::os::fd::RawFd;
+/// # #[derive(Serialize, Deserialize, Debug)]
+/// # enum Msg {
+/// #     Hello(u32),
+/// # }
+/// # impl SerializeFd for Msg {
+/// #     fn extract_fd(&self) -> Option<RawFd> {
+/// #         None
+/// #     }
+/// #     fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+/// #         Ok(self)
+/// #     }
+/// # }
+/// privsep_channel::variant!(Hello(u32): Msg);
+///
+/// # async fn handshake() -> Result<(), ChannelError> {
+/// let ((tx, rx), _) = Channel::loopback::<Msg, Msg>();
+/// let session = Session::<Recv<Hello, Open>, _, _, _, _>::new(tx, rx);
+/// // The protocol receives first.
+/// session.send(1).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Session<P, M, N, T = OwnedWriteHalf, R = OwnedReadHalf>
+where
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    T: TxTransport,
+    R: RxTransport,
+{
+    tx: ChannelTx<M, T>,
+    rx: ChannelRx<N, R>,
+    phantom: PhantomData<P>,
+}
+
+impl<P, M, N, T, R> Session<P, M, N, T, R>
+where
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    T: TxTransport,
+    R: RxTransport,
+{
+    pub fn new(tx: ChannelTx<M, T>, rx: ChannelRx<N, R>) -> Self {
+        Session {
+            tx,
+            rx,
+            phantom: PhantomData,
+        }
+    }
+
+    fn advance<Q>(self) -> Session<Q, M, N, T, R> {
+        Session {
+            tx: self.tx,
+            rx: self.rx,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<V, P, M, N, T, R> Session<Recv<V, P>, M, N, T, R>
+where
+    V: Variant<N>,
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    N: Debug,
+    T: TxTransport,
+    R: RxTransport,
+{
+    pub async fn recv(mut self) -> Result<(V::Value, Session<P, M, N, T, R>), ChannelError> {
+        let msg = self.rx.recv().await?;
+
+        match V::unwrap(msg) {
+            Ok(value) => Ok((value, self.advance())),
+            Err(msg) => Err(ChannelError::UnexpectedMessage {
+                expected: V::NAME,
+                received: format!("{msg:?}"),
+            }),
+        }
+    }
+}
+
+impl<V, P, M, N, T, R> Session<Transmit<V, P>, M, N, T, R>
+where
+    V: Variant<M>,
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    T: TxTransport,
+    R: RxTransport,
+{
+    pub async fn send(mut self, value: V::Value) -> Result<Session<P, M, N, T, R>, ChannelError> {
+        self.tx.send(&V::wrap(value)).await?;
+
+        Ok(self.advance())
+    }
+}
+
+impl<M, N, T, R> Session<Open, M, N, T, R>
+where
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+    T: TxTransport,
+    R: RxTransport,
+{
+    pub fn into_channel(self) -> (ChannelTx<M, T>, ChannelRx<N, R>) {
+        (self.tx, self.rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Open, Recv, Session, Transmit};
+    use crate::channel_redux::Channel;
+    use crate::error::ChannelError;
+    use crate::test_util::TestMsg;
+
+    crate::variant!(Hello(u32): TestMsg);
+    crate::variant!(Bye(u32): TestMsg);
+
+    #[tokio::test]
+    async fn test_out_of_order_message_is_unexpected() {
+        let ((tx_a, rx_a), (tx_b, rx_b)) = Channel::loopback::<TestMsg, TestMsg>();
+        let ours = Session::<Transmit<Bye, Transmit<Hello, Open>>, _, _, _, _>::new(tx_a, rx_a);
+        let theirs = Session::<Recv<Hello, Recv<Bye, Open>>, _, _, _, _>::new(tx_b, rx_b);
+
+        let _ours = ours.send(7).await.unwrap();
+        let Err(e) = theirs.recv().await else {
+            panic!("Bye was taken for Hello");
+        };
+        match e {
+            ChannelError::UnexpectedMessage { expected, received } => {
+                assert_eq!(expected, "Hello");
+                assert_eq!(received, "Bye(7)");
+            }
+            e => panic!("unexpected error {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+
+use crate::error::ChannelError;
+use crate::serializefd::{pop_fd, SerializeFd};
+
+/// A message enum for tests, here and in the crates built on this one.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum TestMsg {
+    Text(String),
+    Fd(#[serde(skip)] RawFd),
+    Blob(Vec<u8>),
+    Hello(u32),
+    Bye(u32),
+    /// Overtakes everything else on a `PriorityTx`.
+    Stop,
+}
+
+impl SerializeFd for TestMsg {
+    fn extract_fd(&self) -> Option<RawFd> {
+        match self {
+            Self::Fd(fd) => Some(*fd),
+            _ => None,
+        }
+    }
+
+    fn compose_fd(self, fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+        match self {
+            Self::Fd(_) => Ok(Self::Fd(pop_fd(fds)?)),
+            msg => Ok(msg),
+        }
+    }
+
+    fn is_priority(&self) -> bool {
+        matches!(self, Self::Stop)
+    }
+}
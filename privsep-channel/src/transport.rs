@@ -0,0 +1,85 @@
+use sendfd::{RecvWithFd, SendWithFd};
+use std::future::Future;
+use std::io;
+use std::os::unix::io::RawFd;
+use tokio::net::tcp;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+
+/// The write side of a byte stream that can carry file descriptors.
+///
+/// `ChannelTx` does its framing on top of this; the unix socket half is the
+/// production implementation.
+pub trait TxTransport {
+    /// Waits until a write is likely to make progress.
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Non-blocking write. Returns `WouldBlock` if no progress can be made.
+    fn send_with_fd(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize>;
+
+    /// Whether `send_with_fd` can carry fds at all.
+    fn passes_fds(&self) -> bool {
+        true
+    }
+}
+
+/// The read side of a byte stream that can carry file descriptors.
+pub trait RxTransport {
+    /// Waits until a read is likely to make progress.
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Non-blocking read. Returns `WouldBlock` if no data is available and
+    /// `Ok((0, 0))` on EOF.
+    fn recv_with_fd(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)>;
+}
+
+impl TxTransport for OwnedWriteHalf {
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        OwnedWriteHalf::writable(self)
+    }
+
+    fn send_with_fd(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        SendWithFd::send_with_fd(self, buf, fds)
+    }
+}
+
+impl RxTransport for OwnedReadHalf {
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        OwnedReadHalf::readable(self)
+    }
+
+    fn recv_with_fd(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        RecvWithFd::recv_with_fd(self, buf, fds)
+    }
+}
+
+/// TCP carries bytes only: fd passing stays on the local unix transport.
+impl TxTransport for tcp::OwnedWriteHalf {
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        tcp::OwnedWriteHalf::writable(self)
+    }
+
+    fn send_with_fd(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        if !fds.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "fd passing needs a unix socket",
+            ));
+        }
+
+        self.try_write(buf)
+    }
+
+    fn passes_fds(&self) -> bool {
+        false
+    }
+}
+
+impl RxTransport for tcp::OwnedReadHalf {
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        tcp::OwnedReadHalf::readable(self)
+    }
+
+    fn recv_with_fd(&self, buf: &mut [u8], _fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        Ok((self.try_read(buf)?, 0))
+    }
+}
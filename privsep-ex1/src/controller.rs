@@ -1,65 +1,46 @@
 use nix::unistd::getpid;
-use privsep_channel::channel_redux::Channel;
 use privsep_channel::error::ChannelError;
+use privsep_channel::session::Session;
+use privsep_framework::supervisor::Supervisor;
 use std::fs::File;
 use std::io::{self, Write};
+use std::net::TcpListener;
 use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 use thiserror::Error;
-use tokio::net::UnixStream;
 
-#[cfg(target_os = "openbsd")]
-use pledge::pledge_promises;
+use privsep_sandbox::Stages;
 
-use crate::msg::{CtrlEngineMsg, CtrlParseMsg, EngineCtrlMsg, ParseCtrlMsg};
-use crate::proc;
+use crate::engine::Engine;
+use crate::msg::{CtrlParseMsg, ParserSetup};
+use crate::parser::Parser;
 
 static NAME: &str = "controller";
 
-pub async fn controller() -> Result<(), ControllerError> {
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Rpath Wpath Cpath Sendfd Proc Exec Ps].unwrap();
+pub async fn controller(mut supervisor: Supervisor) -> Result<(), ControllerError> {
+    let mut stages = stages();
+    stages.advance("spawn")?;
 
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    // Start parser
-    let (mut tx_parser, mut rx_parser, mut parser) = {
-        let (parent_sock, child_sock) = UnixStream::pair()?;
+    // The parser serves connections on a socket we bind for it.
+    let listener = TcpListener::bind("127.0.0.1:8080")?;
+    println!("Server listening on 127.0.0.1:8080");
+    supervisor.pass_fd::<Parser>("listener", listener)?;
 
-        let child = proc::start("parser", parent_sock.as_raw_fd(), child_sock)?;
+    // The parser and engine are started with a socket between them, see the
+    // edge in main.
+    supervisor.spawn_all().await?;
+    let (tx_parser, rx_parser) = supervisor.channel::<Parser>()?.into_split();
+    let (_tx_engine, mut rx_engine) = supervisor.channel::<Engine>()?.into_split();
 
-        let (tx, rx) = Channel::from_stream::<CtrlParseMsg, ParseCtrlMsg>(parent_sock);
+    stages.advance("setup")?;
 
-        (tx, rx, child)
-    };
-
-    // Start engine
-    let (mut tx_engine, mut rx_engine, mut engine) = {
-        let (parent_sock, child_sock) = UnixStream::pair()?;
-
-        let child = proc::start("engine", parent_sock.as_raw_fd(), child_sock)?;
-
-        let (tx, rx) = Channel::from_stream::<CtrlEngineMsg, EngineCtrlMsg>(parent_sock);
-
-        (tx, rx, child)
-    };
-
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Rpath Wpath Cpath Sendfd].unwrap();
-
-    // Child-to-child socket
-    {
-        let (left, right) = UnixStream::pair()?;
-        let lfd = left.as_raw_fd();
-        let rfd = right.as_raw_fd();
-
-        tx_parser.send(&CtrlParseMsg::PeerSocket(lfd)).await?;
-        tx_engine.send(&CtrlEngineMsg::PeerSocket(rfd)).await?;
-    }
+    let parser_setup = Session::<ParserSetup, _, _>::new(tx_parser, rx_parser);
 
     // Send other fd to parser
-    {
+    let parser_setup = {
         // Create a temporary file to send.
         let file_to_send = create_temp_file("Hello from the parent via sendfd!")?;
         let fd = file_to_send.as_raw_fd(); // Get the raw FD to send
@@ -67,10 +48,14 @@ pub async fn controller() -> Result<(), ControllerError> {
         println!("{NAME}[{pid}]: Attempting to send file descriptor: {fd}");
 
         // Send file descriptor
-        tx_parser.send(&CtrlParseMsg::Connection(fd)).await?;
+        let parser_setup = parser_setup.send(fd).await?;
 
         println!("{NAME}[{pid}]: File descriptor {fd} sent using sendfd");
-    }
+
+        parser_setup
+    };
+
+    let (mut tx_parser, mut rx_parser) = parser_setup.into_channel();
 
     println!("{NAME}[{pid}]: Waiting...");
 
@@ -79,8 +64,7 @@ pub async fn controller() -> Result<(), ControllerError> {
 
     let mut flag = false;
 
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio].unwrap();
+    stages.advance("run")?;
 
     loop {
         tokio::select! {
@@ -99,13 +83,9 @@ pub async fn controller() -> Result<(), ControllerError> {
                 // parser_ch.send(&Msg::IntegerMessage(22)).await.unwrap();
                 msg?;
             }
-            _ = parser.wait() => {
-                engine.kill().await?;
-                break;
-
-            }
-            _ = engine.wait() => {
-                parser.kill().await?;
+            (name, _) = supervisor.exited() => {
+                println!("{NAME}[{pid}]: {name} exited, stopping");
+                supervisor.kill_all().await?;
                 break;
             }
         }
@@ -127,6 +107,21 @@ fn create_temp_file(content: &str) -> Result<File, ControllerError> {
     Ok(temp_file)
 }
 
+/// What the controller may do as it goes, from spawning the children to
+/// serving.
+fn stages() -> Stages {
+    use privsep_sandbox::Promise::*;
+
+    Stages::new()
+        .stage(
+            "spawn",
+            &[Stdio, Rpath, Wpath, Cpath, Inet, Sendfd, Proc, Exec, Ps],
+        )
+        // Proc is kept to stop the children.
+        .stage("setup", &[Stdio, Rpath, Wpath, Cpath, Sendfd, Proc])
+        .stage("run", &[Stdio, Proc])
+}
+
 #[derive(Debug, Error)]
 pub enum ControllerError {
     #[error("I/O error: {0}")]
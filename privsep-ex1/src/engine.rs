@@ -1,24 +1,39 @@
-use crate::{
-    msg::{CtrlEngineMsg, EngineCtrlMsg, EngineParseMsg, ParseEngineMsg},
-    proc::SOCKFD,
-};
-use nix::unistd::{getpid, Pid};
-use privsep_channel::{
-    channel_redux::{Channel, ChannelRx, ChannelTx},
-    error::ChannelError,
-};
-use std::os::fd::FromRawFd;
+use crate::msg::{CtrlEngineMsg, EngineCtrlMsg, EngineParseMsg, ParseEngineMsg};
+use crate::parser::Parser;
+use nix::unistd::getpid;
+use privsep_channel::error::ChannelError;
+use privsep_framework::{proc, subsystem::Subsystem, topology::Peer};
+use std::future::Future;
 use thiserror::Error;
-use tokio::net::UnixStream;
 
 static NAME: &str = "engine";
 
+pub struct Engine;
+
+impl Subsystem for Engine {
+    const NAME: &'static str = "engine";
+    const ABOUT: &'static str = "Engine subsystem";
+
+    type ToChild = CtrlEngineMsg;
+    type FromChild = EngineCtrlMsg;
+    type Error = EngineError;
+
+    fn peers() -> Vec<Peer> {
+        vec![Peer::new::<Parser, EngineParseMsg, ParseEngineMsg>()]
+    }
+
+    fn main() -> impl Future<Output = Result<(), EngineError>> {
+        engine()
+    }
+}
+
 pub async fn engine() -> Result<(), EngineError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    let (mut tx_ctrl, mut rx_ctrl) = Channel::new_from_fd(SOCKFD)?;
-    let (mut tx_parser, mut rx_parser) = expect_peer_channel(pid, &mut rx_ctrl).await?;
+    let (mut tx_ctrl, _rx_ctrl) = proc::parent::<Engine>()?.into_split();
+    let (mut tx_parser, mut rx_parser) =
+        proc::peer::<EngineParseMsg, ParseEngineMsg>(Parser::NAME)?.into_split();
 
     println!("{NAME}[{pid}]: Looping.");
 
@@ -44,25 +59,6 @@ pub async fn engine() -> Result<(), EngineError> {
     }
 }
 
-async fn expect_peer_channel(
-    pid: Pid,
-    rx: &mut ChannelRx<CtrlEngineMsg>,
-) -> Result<(ChannelTx<EngineParseMsg>, ChannelRx<ParseEngineMsg>), EngineError> {
-    let CtrlEngineMsg::PeerSocket(ch_fd) = rx.recv().await? else {
-        panic!("expected peer socket");
-    };
-
-    println!("{NAME}[{pid}]: received peer channel fd = {ch_fd}");
-
-    let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(ch_fd) };
-    stream.set_nonblocking(true)?;
-    let stream = UnixStream::from_std(stream)?;
-
-    let ch = Channel::from_stream(stream);
-
-    Ok(ch)
-}
-
 #[derive(Debug, Error)]
 pub enum EngineError {
     #[error("I/O error: {0}")]
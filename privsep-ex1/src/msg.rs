@@ -1,5 +1,6 @@
 use privsep_channel::error::ChannelError;
 use privsep_channel::serializefd::{pop_fd, SerializeFd};
+use privsep_channel::session::{Open, Recv, Transmit};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::os::unix::io::RawFd;
@@ -59,7 +60,6 @@ impl SerializeFd for EngineParseMsg {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum CtrlParseMsg {
-    PeerSocket(#[serde(skip)] RawFd),
     Connection(#[serde(skip)] RawFd),
     Stop,
 }
@@ -67,7 +67,6 @@ pub enum CtrlParseMsg {
 impl SerializeFd for CtrlParseMsg {
     fn extract_fd(&self) -> Option<RawFd> {
         match self {
-            Self::PeerSocket(fd) => Some(*fd),
             Self::Connection(fd) => Some(*fd),
             Self::Stop => None,
         }
@@ -75,7 +74,6 @@ impl SerializeFd for CtrlParseMsg {
 
     fn compose_fd(self, fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
         let msg = match self {
-            Self::PeerSocket(_) => Self::PeerSocket(pop_fd(fds)?),
             Self::Connection(_) => Self::Connection(pop_fd(fds)?),
             Self::Stop => self,
         };
@@ -107,21 +105,18 @@ impl SerializeFd for ParseCtrlMsg {
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum CtrlEngineMsg {
-    PeerSocket(#[serde(skip)] RawFd),
     Stop,
 }
 
 impl SerializeFd for CtrlEngineMsg {
     fn extract_fd(&self) -> Option<RawFd> {
         match self {
-            Self::PeerSocket(fd) => Some(*fd),
             Self::Stop => None,
         }
     }
 
-    fn compose_fd(self, fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+    fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
         let msg = match self {
-            Self::PeerSocket(_) => Self::PeerSocket(pop_fd(fds)?),
             Self::Stop => self,
         };
 
@@ -149,3 +144,12 @@ impl SerializeFd for EngineCtrlMsg {
         Ok(msg)
     }
 }
+
+// Session protocols
+
+privsep_channel::variant!(pub Connection(RawFd): CtrlParseMsg);
+
+/// Controller -> parser: the connection fd. The engine is reached over a
+/// peer socket instead, see `Parser::peers`.
+pub type ParserSetup = Transmit<Connection, Open>;
+pub type ParserHandshake = Recv<Connection, Open>;
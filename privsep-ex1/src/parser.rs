@@ -1,51 +1,74 @@
-use crate::{
-    msg::{CtrlParseMsg, EngineParseMsg, ParseCtrlMsg, ParseEngineMsg},
-    proc::SOCKFD,
-};
+use crate::engine::Engine;
+use crate::msg::{CtrlParseMsg, EngineParseMsg, ParseCtrlMsg, ParseEngineMsg, ParserHandshake};
 use nix::unistd::{getpid, Pid};
 use privsep_channel::{
-    channel_redux::{Channel, ChannelRx, ChannelTx},
     error::ChannelError,
+    session::{Open, Session},
 };
+use privsep_framework::{proc, sandbox, subsystem::Subsystem, topology::Peer};
 use privsep_rpn::rpn::{eval_rpn, RpnError};
-use std::{fs::File, io::Read, os::fd::FromRawFd, time::Duration};
+use privsep_sandbox::Stages;
+use std::{fs::File, future::Future, io::Read, os::fd::FromRawFd, time::Duration};
 use thiserror::Error;
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
-    net::{TcpListener, TcpStream, UnixStream},
+    net::{TcpListener, TcpStream},
     time::timeout,
 };
 
-#[cfg(target_os = "openbsd")]
-use pledge::pledge_promises;
-
 static NAME: &str = "parser";
 
-pub async fn parser() -> Result<(), ParserError> {
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Recvfd Inet].unwrap();
+pub struct Parser;
+
+impl Subsystem for Parser {
+    const NAME: &'static str = "parser";
+    const ABOUT: &'static str = "Parser subsystem";
+
+    type ToChild = CtrlParseMsg;
+    type FromChild = ParseCtrlMsg;
+    type Error = ParserError;
+
+    fn sandbox() -> Stages {
+        use privsep_sandbox::Promise::*;
 
+        Stages::new()
+            .stage("setup", &[Stdio, Recvfd, Inet])
+            .stage("serve", &[Stdio, Inet])
+    }
+
+    fn peers() -> Vec<Peer> {
+        vec![Peer::new::<Engine, ParseEngineMsg, EngineParseMsg>()]
+    }
+
+    fn main() -> impl Future<Output = Result<(), ParserError>> {
+        parser()
+    }
+}
+
+pub async fn parser() -> Result<(), ParserError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
     // New TCP connection stuff //
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    println!("Server listening on 127.0.0.1:8080");
+    let listener = std::net::TcpListener::from(proc::claim_fd("listener")?);
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
 
     let mut connection: Option<BufReader<TcpStream>> = None;
     // Finish TCP connection stuff
 
-    let (mut _tx_ctrl, mut rx_ctrl) = Channel::new_from_fd::<ParseCtrlMsg, CtrlParseMsg>(SOCKFD)?;
+    let (tx_ctrl, rx_ctrl) = proc::parent::<Parser>()?.into_split();
+    let session = Session::<ParserHandshake, ParseCtrlMsg, CtrlParseMsg>::new(tx_ctrl, rx_ctrl);
+    let (mut tx_engine, mut rx_engine) =
+        proc::peer::<ParseEngineMsg, EngineParseMsg>(Engine::NAME)?.into_split();
 
-    let (mut tx_engine, mut rx_engine) = expect_peer_channel(pid, &mut rx_ctrl).await?;
+    let session = expect_fd(pid, session).await?;
+    let (mut _tx_ctrl, mut rx_ctrl) = session.into_channel();
 
-    expect_fd(pid, &mut rx_ctrl).await?;
+    sandbox::advance("serve")?;
 
     println!("{NAME}[{pid}]: Looping.");
 
-    // #[cfg(target_os = "openbsd")]
-    // pledge_promises![Stdio].unwrap();
-
     loop {
         tokio::select! {
             // Start TCP connection stuff
@@ -90,7 +113,6 @@ pub async fn parser() -> Result<(), ParserError> {
                 println!("{NAME}[{pid}]: <- [controller]: Got message {msg:?}.");
 
                 match msg {
-                    CtrlParseMsg::PeerSocket(_) => {},
                     CtrlParseMsg::Connection(_) => {},
                     CtrlParseMsg::Stop => {},
                 }
@@ -113,36 +135,14 @@ pub enum ParserError {
     ConnectionClosed,
 }
 
-async fn expect_peer_channel(
+async fn expect_fd(
     pid: Pid,
-    rx: &mut ChannelRx<CtrlParseMsg>,
-) -> Result<(ChannelTx<ParseEngineMsg>, ChannelRx<EngineParseMsg>), ParserError> {
-    println!("{NAME}[{pid}]: Waiting on peer channel...");
-
-    let CtrlParseMsg::PeerSocket(ch_fd) = rx.recv().await? else {
-        panic!("expected peer socket");
-    };
-
-    println!("{NAME}[{pid}]: received peer channel fd = {ch_fd}");
-
-    let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(ch_fd) };
-    stream.set_nonblocking(true)?;
-    let stream = UnixStream::from_std(stream)?;
-
-    let ch = Channel::from_stream(stream);
-
-    println!("{NAME}[{pid}]: Peer channel received");
-
-    Ok(ch)
-}
-
-async fn expect_fd(pid: Pid, rx: &mut ChannelRx<CtrlParseMsg>) -> Result<(), ParserError> {
+    session: Session<ParserHandshake, ParseCtrlMsg, CtrlParseMsg>,
+) -> Result<Session<Open, ParseCtrlMsg, CtrlParseMsg>, ParserError> {
     // Receive the file descriptor from the parent using sendfd::recv_fd
     println!("{NAME}[{pid}]: Waiting to receive file descriptor from parent...",);
 
-    let CtrlParseMsg::Connection(temp_fd) = rx.recv().await? else {
-        panic!("expected peer socket");
-    };
+    let (temp_fd, session) = session.recv().await?;
 
     println!("{NAME}[{pid}]: received fd = {temp_fd}");
 
@@ -153,7 +153,7 @@ async fn expect_fd(pid: Pid, rx: &mut ChannelRx<CtrlParseMsg>) -> Result<(), Par
 
     println!("{NAME}[{pid}]: read temp file: {out}");
 
-    Ok(())
+    Ok(session)
 }
 
 // TCP stuff
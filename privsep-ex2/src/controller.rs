@@ -1,24 +1,45 @@
-use nix::unistd::getpid;
-use privsep_channel::channel_redux::Channel;
+use nix::unistd::{getpid, Pid};
+use privsep_channel::capture::Capture;
+use privsep_channel::channel_redux::{Channel, ChannelRx, ChannelTx};
 use privsep_channel::error::ChannelError;
+use privsep_channel::metrics::{Metrics, MetricsSnapshot};
+use privsep_channel::priority::PriorityTx;
+use privsep_channel::serializefd::SerializeFd;
+use privsep_channel::session::Session;
+use privsep_framework::limits::Enforcement;
+use privsep_framework::supervisor::{wire, Supervisor};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::{TcpListener, TcpStream, UnixStream};
 use tokio::time::timeout;
 
-#[cfg(target_os = "openbsd")]
-use pledge::pledge_promises;
+use privsep_sandbox::Stages;
 
-use crate::msg::{CtrlEngineMsg, CtrlParseMsg, EngineCtrlMsg, ParseCtrlMsg};
-use crate::proc;
+use crate::engine::{Engine, LATEST_VALUE};
+use crate::msg::{CtrlParseMsg, EngineCtrlMsg, EngineSetup, ParseCtrlMsg, ParserSetup, HEARTBEAT};
+use crate::parser::Parser;
 
 static NAME: &str = "controller";
 
-pub async fn controller() -> Result<(), ControllerError> {
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Ps Rpath Sendfd Inet Proc Exec].unwrap();
+pub async fn controller(mut supervisor: Supervisor) -> Result<(), ControllerError> {
+    // Opened before pledging: creating the file needs cpath/wpath.
+    let capture = Capture::from_env()?;
+    // Unveiling a file that isn't there yet would unveil the whole directory
+    // to the engine, on Linux.
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(LATEST_VALUE)?;
+
+    let mut stages = stages();
+    stages.advance("spawn")?;
 
     // New TCP connection stuff //
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
@@ -30,40 +51,59 @@ pub async fn controller() -> Result<(), ControllerError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    // Start parser
-    let (mut tx_parser, mut rx_parser, mut parser) = {
-        let (parent_sock, child_sock) = UnixStream::pair()?;
-
-        let child = proc::start("parser", parent_sock.as_raw_fd(), child_sock)?;
-
-        let (tx, rx) = Channel::from_stream::<CtrlParseMsg, ParseCtrlMsg>(parent_sock);
-
-        (tx, rx, child)
-    };
-
-    // Start engine
-    let (mut tx_engine, mut rx_engine, mut engine) = {
-        let (parent_sock, child_sock) = UnixStream::pair()?;
-
-        let child = proc::start("engine", parent_sock.as_raw_fd(), child_sock)?;
+    let (parser, enforcement) = supervisor.spawn::<Parser>().await?;
+    if enforcement != Enforcement::Full {
+        println!("{NAME}[{pid}]: Parser limits not fully enforced: {enforcement:?}");
+    }
+    let (tx_parser, rx_parser) = tap(&capture, "parser", parser.into_split());
 
-        let (tx, rx) = Channel::from_stream::<CtrlEngineMsg, EngineCtrlMsg>(parent_sock);
+    let (tx_engine, rx_engine) = tap(
+        &capture,
+        "engine",
+        supervisor.spawn::<Engine>().await?.0.into_split(),
+    );
+    let tx_engine = tx_engine.with_envelopes();
 
-        (tx, rx, child)
-    };
+    stages.advance("setup")?;
 
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Sendfd Inet].unwrap();
+    let parser_setup = Session::<ParserSetup, _, _>::new(tx_parser, rx_parser);
+    let engine_setup = Session::<EngineSetup, _, _>::new(tx_engine, rx_engine);
 
     // Child-to-child socket
-    {
-        let (left, right) = UnixStream::pair()?;
-        let lfd = left.as_raw_fd();
-        let rfd = right.as_raw_fd();
+    let (parser_setup, engine_setup) = wire(parser_setup, engine_setup).await?;
+
+    // Priority lane to the parser, so `Stop` isn't queued behind `Data`
+    let (parser_setup, tx_parser_lane) = {
+        let (ours, theirs) = UnixStream::pair()?;
+        let setup = parser_setup.send(theirs.as_raw_fd()).await?;
+        let (tx, _rx) = tap(
+            &capture,
+            "parser",
+            Channel::from_stream::<CtrlParseMsg, ParseCtrlMsg>(ours),
+        );
+
+        (setup, tx)
+    };
 
-        tx_parser.send(&CtrlParseMsg::PeerSocket(lfd)).await?;
-        tx_engine.send(&CtrlEngineMsg::PeerSocket(rfd)).await?;
-    }
+    let (tx_parser, mut rx_parser) = parser_setup.into_channel();
+    let mut tx_parser = PriorityTx::new(tx_parser, tx_parser_lane);
+    let (tx_engine, mut rx_engine) = engine_setup.into_channel();
+
+    // A child that stops heartbeating is wedged (or stopped); treat it like
+    // one that exited.
+    let mut parser_liveness = rx_parser.watch_liveness(HEARTBEAT);
+    let mut engine_liveness = rx_engine.watch_liveness(HEARTBEAT);
+
+    let [parser_bulk, parser_priority] = tx_parser.metrics();
+    let edges = [
+        (
+            "parser",
+            vec![parser_bulk, parser_priority, rx_parser.metrics()],
+        ),
+        ("engine", vec![tx_engine.metrics(), rx_engine.metrics()]),
+    ];
+    let mut report = tokio::time::interval(Duration::from_secs(30));
+    report.tick().await;
 
     println!("{NAME}[{pid}]: Waiting...");
 
@@ -72,8 +112,7 @@ pub async fn controller() -> Result<(), ControllerError> {
 
     let mut flag = false;
 
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Inet].unwrap();
+    stages.advance("run")?;
 
     loop {
         tokio::select! {
@@ -109,6 +148,8 @@ pub async fn controller() -> Result<(), ControllerError> {
             }
             // Finish TCP connection stuff
 
+            _ = report.tick() => report_metrics(pid, &edges),
+
             _ = &mut delay => {
                 if !flag {
                     tx_parser.send(&CtrlParseMsg::Stop).await?;
@@ -116,29 +157,117 @@ pub async fn controller() -> Result<(), ControllerError> {
                 }
             }
             msg = rx_parser.recv() => {
-                println!("{NAME}[{pid}]: Received from parser {msg:?}");
+                match msg {
+                    Err(ChannelError::Poisoned(errors)) => {
+                        eprintln!("{NAME}[{pid}]: parser sent {errors} malformed frames, stopping");
+                        supervisor.kill_all().await?;
+                        break;
+                    }
+                    // Within the error budget: skip the frame.
+                    Err(ref e @ (ChannelError::Bincode(_) | ChannelError::UnknownFrameKind(_))) => {
+                        eprintln!("{NAME}[{pid}]: malformed frame from parser: {e}");
+                        continue;
+                    }
+                    Ok(ParseCtrlMsg::Error(ref e)) => eprintln!("{NAME}[{pid}]: {e}"),
+                    Ok(ref msg) => println!("{NAME}[{pid}]: Received from parser {msg:?}"),
+                    Err(_) => {}
+                }
                 msg?;
             }
             msg = rx_engine.recv() => {
-                println!("{NAME}[{pid}]: Received from engine {msg:?}");
                 // parser_ch.send(&Msg::IntegerMessage(22)).await.unwrap();
+                match msg {
+                    Err(ChannelError::Poisoned(errors)) => {
+                        eprintln!("{NAME}[{pid}]: engine sent {errors} malformed frames, stopping");
+                        supervisor.kill_all().await?;
+                        break;
+                    }
+                    // Within the error budget: skip the frame.
+                    Err(ref e @ (ChannelError::Bincode(_) | ChannelError::UnknownFrameKind(_))) => {
+                        eprintln!("{NAME}[{pid}]: malformed frame from engine: {e}");
+                        continue;
+                    }
+                    Ok(EngineCtrlMsg::Error(ref e)) => eprintln!("{NAME}[{pid}]: {e}"),
+                    Ok(ref msg) => println!("{NAME}[{pid}]: Received from engine {msg:?}"),
+                    Err(_) => {}
+                }
                 msg?;
             }
-            _ = parser.wait() => {
-                engine.kill().await?;
+            silent = parser_liveness.expired() => {
+                eprintln!("{NAME}[{pid}]: parser silent for {silent:?}, stopping");
+                supervisor.kill_all().await?;
                 break;
-
             }
-            _ = engine.wait() => {
-                parser.kill().await?;
+            silent = engine_liveness.expired() => {
+                eprintln!("{NAME}[{pid}]: engine silent for {silent:?}, stopping");
+                supervisor.kill_all().await?;
+                break;
+            }
+            (name, _) = supervisor.exited() => {
+                println!("{NAME}[{pid}]: {name} exited, stopping");
+                supervisor.kill_all().await?;
                 break;
             }
         }
     }
 
+    report_metrics(pid, &edges);
+
     Ok(())
 }
 
+/// Prints traffic totals for all channels of each edge.
+fn report_metrics(pid: Pid, edges: &[(&str, Vec<Arc<Metrics>>)]) {
+    for (name, halves) in edges {
+        let mut total = MetricsSnapshot::default();
+        for half in halves {
+            total.merge(&half.snapshot());
+        }
+
+        let errors: u64 = total.errors.iter().sum();
+        let p99 = match total.latency_percentile(0.99) {
+            Some(p99) => format!("{p99:?}"),
+            None => "-".to_owned(),
+        };
+
+        println!(
+            "{NAME}[{pid}]: {name}: {} frames, {} bytes, {} fds, {} would-block, {errors} errors, p99 latency {p99}",
+            total.frames, total.bytes, total.fds, total.would_block
+        );
+    }
+}
+
+/// Records both halves of a channel when `PRIVSEP_CAPTURE` is set.
+fn tap<M, N>(
+    capture: &Option<Arc<Capture>>,
+    label: &str,
+    (tx, rx): (ChannelTx<M>, ChannelRx<N>),
+) -> (ChannelTx<M>, ChannelRx<N>)
+where
+    M: SerializeFd + Serialize + Debug,
+    N: SerializeFd + DeserializeOwned + Debug,
+{
+    match capture {
+        Some(capture) => (
+            tx.with_capture(capture.clone(), label),
+            rx.with_capture(capture.clone(), label),
+        ),
+        None => (tx, rx),
+    }
+}
+
+/// What the controller may do as it goes, from spawning the children to
+/// serving.
+fn stages() -> Stages {
+    use privsep_sandbox::Promise::*;
+
+    Stages::new()
+        .stage("spawn", &[Stdio, Ps, Rpath, Sendfd, Inet, Proc, Exec])
+        // Proc is kept to stop the children.
+        .stage("setup", &[Stdio, Sendfd, Inet, Proc])
+        .stage("run", &[Stdio, Inet, Proc])
+}
+
 #[derive(Debug, Error)]
 pub enum ControllerError {
     #[error("I/O error: {0}")]
@@ -1,27 +1,87 @@
-use crate::{
-    msg::{CtrlEngineMsg, EngineCtrlMsg, EngineParseMsg, ParseEngineMsg},
-    proc::SOCKFD,
+use crate::msg::{
+    CtrlEngineMsg, EngineCtrlMsg, EngineHandshake, EngineParseMsg, ParseEngineMsg, ENGINE_CREDIT,
+    HEARTBEAT,
 };
 use nix::unistd::{getpid, Pid};
 use privsep_channel::{
     channel_redux::{Channel, ChannelRx, ChannelTx},
+    envelope::Tolerant,
     error::ChannelError,
+    remote::RemoteError,
+    session::{Open, Session},
 };
+use privsep_framework::{proc, sandbox, subsystem::Subsystem};
+use privsep_sandbox::{unveil::Unveil, Stages};
+use std::future::Future;
 use std::os::fd::FromRawFd;
 use thiserror::Error;
 use tokio::net::UnixStream;
 
 static NAME: &str = "engine";
 
+/// Where the engine keeps the last value it was sent, created by the
+/// controller.
+pub const LATEST_VALUE: &str = "latest-value";
+
+pub struct Engine;
+
+impl Subsystem for Engine {
+    const NAME: &'static str = "engine";
+    const ABOUT: &'static str = "Engine subsystem";
+
+    type ToChild = CtrlEngineMsg;
+    type FromChild = EngineCtrlMsg;
+    type Error = EngineError;
+
+    fn sandbox() -> Stages {
+        use privsep_sandbox::Promise::*;
+
+        Stages::new()
+            .stage("setup", &[Stdio, Recvfd, Wpath, Cpath])
+            .unveil(Unveil::new().path(LATEST_VALUE, "wc"))
+            .stage("run", &[Stdio, Wpath, Cpath])
+    }
+
+    fn main() -> impl Future<Output = Result<(), EngineError>> {
+        engine()
+    }
+}
+
 pub async fn engine() -> Result<(), EngineError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    let (_tx_ctrl, mut rx_ctrl) = Channel::new_from_fd::<EngineCtrlMsg, CtrlEngineMsg>(SOCKFD)?;
-    let (_tx_parser, mut rx_parser) = expect_peer_channel(pid, &mut rx_ctrl).await?;
+    let (tx_ctrl, rx_ctrl) = proc::parent::<Engine>()?.into_split();
+    let session = Session::<EngineHandshake, EngineCtrlMsg, CtrlEngineMsg>::new(tx_ctrl, rx_ctrl);
+    let ((mut tx_parser, rx_parser), session) = expect_peer_channel(pid, session).await?;
+    tx_parser.grant(ENGINE_CREDIT).await?;
+    let (tx_ctrl, rx_ctrl) = session.into_channel();
+    let mut tx_ctrl = tx_ctrl.with_timestamps();
+
+    sandbox::advance("run")?;
 
     println!("{NAME}[{pid}]: Looping.");
 
+    let result = run(pid, &mut tx_ctrl, rx_ctrl, tx_parser, rx_parser).await;
+
+    // Let the controller know why we are exiting; it may already be gone.
+    if let Err(ref e) = result {
+        let msg = EngineCtrlMsg::Error(RemoteError::new(NAME, pid, e));
+        let _ = tx_ctrl.send(&msg).await;
+    }
+
+    result
+}
+
+async fn run(
+    pid: Pid,
+    tx_ctrl: &mut ChannelTx<EngineCtrlMsg>,
+    mut rx_ctrl: ChannelRx<CtrlEngineMsg>,
+    mut tx_parser: ChannelTx<EngineParseMsg>,
+    mut rx_parser: ChannelRx<ParseEngineMsg>,
+) -> Result<(), EngineError> {
+    let mut heartbeat = HEARTBEAT.ticker();
+
     loop {
         std::thread::sleep(std::time::Duration::from_secs(1));
 
@@ -32,21 +92,35 @@ pub async fn engine() -> Result<(), EngineError> {
 
                 match msg {
                     ParseEngineMsg::NewValue(f) => {
-                        tokio::fs::write("latest-value", format!("Latest value = {f}\n")).await?;
+                        tokio::fs::write(LATEST_VALUE, format!("Latest value = {f}\n")).await?;
                     },
                 }
+
+                tx_parser.grant(1).await?;
             }
+            msg = rx_ctrl.recv_tolerant() => match msg? {
+                Tolerant::Known(CtrlEngineMsg::Stop) => {
+                    println!("{NAME}[{pid}]: Stopping.");
+                    return Ok(());
+                }
+                Tolerant::Known(msg) => println!("{NAME}[{pid}]: unexpected message {msg:?}"),
+                // From a newer controller: skip it and carry on.
+                Tolerant::Unknown { id, .. } => {
+                    println!("{NAME}[{pid}]: ignoring unknown message {id}");
+                }
+            },
+            _ = heartbeat.tick() => tx_ctrl.send_heartbeat().await?,
         }
     }
 }
 
+type PeerChannel = (ChannelTx<EngineParseMsg>, ChannelRx<ParseEngineMsg>);
+
 async fn expect_peer_channel(
     pid: Pid,
-    rx: &mut ChannelRx<CtrlEngineMsg>,
-) -> Result<(ChannelTx<EngineParseMsg>, ChannelRx<ParseEngineMsg>), EngineError> {
-    let CtrlEngineMsg::PeerSocket(ch_fd) = rx.recv().await? else {
-        panic!("expected peer socket");
-    };
+    session: Session<EngineHandshake, EngineCtrlMsg, CtrlEngineMsg>,
+) -> Result<(PeerChannel, Session<Open, EngineCtrlMsg, CtrlEngineMsg>), EngineError> {
+    let (ch_fd, session) = session.recv().await?;
 
     println!("{NAME}[{pid}]: received peer channel fd = {ch_fd}");
 
@@ -56,7 +130,7 @@ async fn expect_peer_channel(
 
     let ch = Channel::from_stream(stream);
 
-    Ok(ch)
+    Ok((ch, session))
 }
 
 #[derive(Debug, Error)]
@@ -4,6 +4,8 @@ use crate::{controller::ControllerError, engine::EngineError, parser::ParserErro
 
 #[derive(Debug, Error)]
 pub enum ServiceError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
     #[error("Controller error: {0}")]
     Controller(#[from] ControllerError),
     #[error("Parser error: {0}")]
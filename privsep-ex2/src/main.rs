@@ -1,25 +1,21 @@
-mod cli;
 mod controller;
 mod engine;
 mod error;
 mod msg;
 mod parser;
-mod proc;
 
-use clap::Parser;
-use cli::{Cli, Subsystem};
+use privsep_framework::app::App;
 
+use crate::engine::Engine;
 use crate::error::ServiceError;
+use crate::parser::Parser;
 
 #[tokio::main]
 async fn main() -> Result<(), ServiceError> {
-    let cli = Cli::parse();
-
-    match cli.subsystem {
-        None => controller::controller().await?,
-        Some(Subsystem::Parser) => parser::parser().await?,
-        Some(Subsystem::Engine) => engine::engine().await?,
-    }
-
-    Ok(())
+    App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .subsystem::<Parser>()
+        .subsystem::<Engine>()
+        .run(controller::controller)
+        .await
 }
@@ -1,8 +1,23 @@
+use privsep_channel::envelope::Versioned;
 use privsep_channel::error::ChannelError;
+use privsep_channel::liveness::HeartbeatConfig;
+use privsep_channel::remote::RemoteError;
 use privsep_channel::serializefd::{pop_fd, SerializeFd};
+use privsep_channel::session::{Open, Recv, Transmit};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+/// Values the engine accepts from the parser before it has to catch up.
+pub const ENGINE_CREDIT: u32 = 16;
+
+/// Children heartbeat to the controller, which gives up on them after five
+/// missed beats.
+pub const HEARTBEAT: HeartbeatConfig = HeartbeatConfig {
+    interval: Duration::from_secs(1),
+    misses: 5,
+};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum ParseEngineMsg {
@@ -39,18 +54,21 @@ impl SerializeFd for ParseEngineMsg {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum EngineParseMsg {
     Failed,
+    Error(RemoteError),
 }
 
 impl SerializeFd for EngineParseMsg {
     fn extract_fd(&self) -> Option<RawFd> {
         match self {
             Self::Failed => None,
+            Self::Error(_) => None,
         }
     }
 
     fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
         let msg = match self {
             Self::Failed => self,
+            Self::Error(_) => self,
         };
 
         Ok(msg)
@@ -60,6 +78,7 @@ impl SerializeFd for EngineParseMsg {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum CtrlParseMsg {
     PeerSocket(#[serde(skip)] RawFd),
+    ControlLane(#[serde(skip)] RawFd),
     Connection(#[serde(skip)] RawFd),
     Data(String),
     Stop,
@@ -69,6 +88,7 @@ impl SerializeFd for CtrlParseMsg {
     fn extract_fd(&self) -> Option<RawFd> {
         match self {
             Self::PeerSocket(fd) => Some(*fd),
+            Self::ControlLane(fd) => Some(*fd),
             Self::Connection(fd) => Some(*fd),
             Self::Data(_) => None,
             Self::Stop => None,
@@ -78,6 +98,7 @@ impl SerializeFd for CtrlParseMsg {
     fn compose_fd(self, fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
         let msg = match self {
             Self::PeerSocket(_) => Self::PeerSocket(pop_fd(fds)?),
+            Self::ControlLane(_) => Self::ControlLane(pop_fd(fds)?),
             Self::Connection(_) => Self::Connection(pop_fd(fds)?),
             Self::Data(_) => self,
             Self::Stop => self,
@@ -85,23 +106,30 @@ impl SerializeFd for CtrlParseMsg {
 
         Ok(msg)
     }
+
+    fn is_priority(&self) -> bool {
+        matches!(self, Self::Stop)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum ParseCtrlMsg {
     Foo,
+    Error(RemoteError),
 }
 
 impl SerializeFd for ParseCtrlMsg {
     fn extract_fd(&self) -> Option<RawFd> {
         match self {
             Self::Foo => None,
+            Self::Error(_) => None,
         }
     }
 
     fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
         let msg = match self {
             Self::Foo => self,
+            Self::Error(_) => self,
         };
 
         Ok(msg)
@@ -132,23 +160,55 @@ impl SerializeFd for CtrlEngineMsg {
     }
 }
 
+// The controller sends these in envelopes so the engine can be upgraded
+// separately: add variants at the end, with a new id.
+impl Versioned for CtrlEngineMsg {
+    fn variant_id(&self) -> u32 {
+        match self {
+            Self::PeerSocket(_) => 1,
+            Self::Stop => 2,
+        }
+    }
+
+    fn knows(id: u32) -> bool {
+        matches!(id, 1 | 2)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum EngineCtrlMsg {
     Bar,
+    Error(RemoteError),
 }
 
 impl SerializeFd for EngineCtrlMsg {
     fn extract_fd(&self) -> Option<RawFd> {
         match self {
             Self::Bar => None,
+            Self::Error(_) => None,
         }
     }
 
     fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
         let msg = match self {
             Self::Bar => self,
+            Self::Error(_) => self,
         };
 
         Ok(msg)
     }
 }
+
+// Session protocols
+
+privsep_channel::variant!(pub PeerSocket(RawFd): CtrlParseMsg, CtrlEngineMsg);
+privsep_channel::variant!(pub ControlLane(RawFd): CtrlParseMsg);
+
+/// Controller -> parser: peer socket, then the socket for priority messages
+/// such as `Stop`.
+pub type ParserSetup = Transmit<PeerSocket, Transmit<ControlLane, Open>>;
+pub type ParserHandshake = Recv<PeerSocket, Recv<ControlLane, Open>>;
+
+/// Controller -> engine: peer socket.
+pub type EngineSetup = Transmit<PeerSocket, Open>;
+pub type EngineHandshake = Recv<PeerSocket, Open>;
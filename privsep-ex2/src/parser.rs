@@ -1,34 +1,110 @@
-use crate::{
-    msg::{CtrlParseMsg, EngineParseMsg, ParseCtrlMsg, ParseEngineMsg},
-    proc::SOCKFD,
+use crate::msg::{
+    ControlLane, CtrlParseMsg, EngineParseMsg, ParseCtrlMsg, ParseEngineMsg, ParserHandshake,
+    HEARTBEAT,
 };
 use nix::unistd::{getpid, Pid};
 use privsep_channel::{
     channel_redux::{Channel, ChannelRx, ChannelTx},
     error::ChannelError,
+    priority::PriorityRx,
+    remote::RemoteError,
+    session::{Open, Recv, Session},
+    transport::{RxTransport, TxTransport},
+};
+use privsep_framework::{
+    limits::Limits, namespaces::Namespaces, proc, sandbox, subsystem::Subsystem,
 };
 use privsep_rpn::rpn::{eval_rpn, RpnError};
+use privsep_sandbox::Stages;
+use std::future::Future;
 use std::os::fd::FromRawFd;
 use thiserror::Error;
 use tokio::net::UnixStream;
 
-#[cfg(target_os = "openbsd")]
-use pledge::pledge_promises;
-
 static NAME: &str = "parser";
 
-pub async fn parser() -> Result<(), ParserError> {
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Recvfd].unwrap();
+pub struct Parser;
+
+impl Subsystem for Parser {
+    const NAME: &'static str = "parser";
+    const ABOUT: &'static str = "Parser subsystem";
+
+    type ToChild = CtrlParseMsg;
+    type FromChild = ParseCtrlMsg;
+    type Error = ParserError;
+
+    // No network and no filesystem at all, on Linux.
+    fn namespaces() -> Namespaces {
+        Namespaces::new().network().pid().ipc().uts().empty_root()
+    }
+
+    // Bounds what a hostile expression can make us allocate or spin on.
+    // Memory is capped by the cgroup, or RLIMIT_DATA without one, rather than
+    // RLIMIT_AS, which tokio's worker threads, each with a stack and a malloc
+    // arena, would soon hit.
+    fn limits() -> Limits {
+        Limits::new()
+            .open_files(64)
+            .core_size(0)
+            .memory(256 << 20)
+            .cpu_percent(50)
+    }
+
+    fn sandbox() -> Stages {
+        use privsep_sandbox::Promise::*;
 
+        Stages::new()
+            .stage("setup", &[Stdio, Recvfd])
+            .stage("parse", &[Stdio])
+    }
+
+    fn main() -> impl Future<Output = Result<(), ParserError>> {
+        parser()
+    }
+}
+
+pub async fn parser() -> Result<(), ParserError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    let (mut _tx_ctrl, mut rx_ctrl) = Channel::new_from_fd::<ParseCtrlMsg, CtrlParseMsg>(SOCKFD)?;
-    let (mut tx_engine, mut rx_engine) = expect_peer_channel(pid, &mut rx_ctrl).await?;
+    let (tx_ctrl, rx_ctrl) = proc::parent::<Parser>()?.into_split();
+    let session = Session::<ParserHandshake, ParseCtrlMsg, CtrlParseMsg>::new(tx_ctrl, rx_ctrl);
+    let ((tx_engine, mut rx_engine), session) = expect_peer_channel(pid, session).await?;
+    let tx_engine = tx_engine.with_flow_control(&mut rx_engine);
+    let (lane_fd, session) = session.recv().await?;
+    let (tx_ctrl, rx_ctrl) = session.into_channel();
+    let (_, rx_lane) = Channel::new_from_fd::<ParseCtrlMsg, CtrlParseMsg>(lane_fd)?;
+    let rx_ctrl = PriorityRx::new(rx_ctrl, rx_lane);
+    let mut tx_ctrl = tx_ctrl.with_timestamps();
+
+    sandbox::advance("parse")?;
 
     println!("{NAME}[{pid}]: Looping.");
 
+    let result = run(pid, &mut tx_ctrl, rx_ctrl, tx_engine, rx_engine).await;
+
+    // Let the controller know why we are exiting; it may already be gone.
+    if let Err(ref e) = result {
+        let msg = ParseCtrlMsg::Error(RemoteError::new(NAME, pid, e));
+        let _ = tx_ctrl.send(&msg).await;
+    }
+
+    result
+}
+
+async fn run<T, R>(
+    pid: Pid,
+    tx_ctrl: &mut ChannelTx<ParseCtrlMsg, T>,
+    mut rx_ctrl: PriorityRx<CtrlParseMsg, R>,
+    mut tx_engine: ChannelTx<ParseEngineMsg, T>,
+    mut rx_engine: ChannelRx<EngineParseMsg, R>,
+) -> Result<(), ParserError>
+where
+    T: TxTransport,
+    R: RxTransport,
+{
+    let mut heartbeat = HEARTBEAT.ticker();
+
     loop {
         tokio::select! {
             msg = rx_engine.recv() => {
@@ -40,13 +116,33 @@ pub async fn parser() -> Result<(), ParserError> {
                 match msg? {
                     CtrlParseMsg::Data(data) => {
                         match parse_evaluate_rpn(&data)  {
-                            Ok(value) => tx_engine.send(&ParseEngineMsg::NewValue(value)).await?,
-                            Err(e) => println!("{NAME}[{pid}]: Bad input: {e:?}"),
+                            Ok(value) => match tx_engine
+                                .send(&ParseEngineMsg::NewValue(value))
+                                .await
+                            {
+                                // The engine is behind: drop the value and say so.
+                                Err(e @ ChannelError::NoCredit) => {
+                                    println!("{NAME}[{pid}]: Dropping {value}: {e}");
+                                    let msg = ParseCtrlMsg::Error(RemoteError::new(NAME, pid, &e));
+                                    tx_ctrl.send(&msg).await?;
+                                }
+                                result => result?,
+                            },
+                            Err(e) => {
+                                println!("{NAME}[{pid}]: Bad input: {e:?}");
+                                let msg = ParseCtrlMsg::Error(RemoteError::new(NAME, pid, &e));
+                                tx_ctrl.send(&msg).await?;
+                            }
                         }
                     },
+                    CtrlParseMsg::Stop => {
+                        println!("{NAME}[{pid}]: Stopping.");
+                        return Ok(());
+                    }
                     _ => println!("{NAME}[{pid}]: unexpected message"),
                 }
             }
+            _ = heartbeat.tick() => tx_ctrl.send_heartbeat().await?,
         }
     }
 }
@@ -63,15 +159,21 @@ pub enum ParserError {
     Rpn(#[from] RpnError),
 }
 
+type PeerChannel = (ChannelTx<ParseEngineMsg>, ChannelRx<EngineParseMsg>);
+
 async fn expect_peer_channel(
     pid: Pid,
-    rx: &mut ChannelRx<CtrlParseMsg>,
-) -> Result<(ChannelTx<ParseEngineMsg>, ChannelRx<EngineParseMsg>), ParserError> {
+    session: Session<ParserHandshake, ParseCtrlMsg, CtrlParseMsg>,
+) -> Result<
+    (
+        PeerChannel,
+        Session<Recv<ControlLane, Open>, ParseCtrlMsg, CtrlParseMsg>,
+    ),
+    ParserError,
+> {
     println!("{NAME}[{pid}]: Waiting on peer channel...");
 
-    let CtrlParseMsg::PeerSocket(ch_fd) = rx.recv().await? else {
-        panic!("expected peer socket");
-    };
+    let (ch_fd, session) = session.recv().await?;
 
     println!("{NAME}[{pid}]: received peer channel fd = {ch_fd}");
 
@@ -83,7 +185,7 @@ async fn expect_peer_channel(
 
     println!("{NAME}[{pid}]: Peer channel received");
 
-    Ok(ch)
+    Ok((ch, session))
 }
 
 fn parse_evaluate_rpn(data: &str) -> Result<f64, ParserError> {
@@ -93,3 +195,88 @@ fn parse_evaluate_rpn(data: &str) -> Result<f64, ParserError> {
 
     Ok(value)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use crate::msg::{CtrlParseMsg, EngineParseMsg, ParseCtrlMsg, ParseEngineMsg};
+    use nix::unistd::getpid;
+    use privsep_channel::channel_redux::Channel;
+    use privsep_channel::priority::{PriorityRx, PriorityTx};
+
+    #[tokio::test]
+    async fn test_data_is_evaluated_and_forwarded() {
+        let ((mut tx_ctrl, mut rx_ctrl), (mut tx_parser_ctrl, rx_parser_ctrl)) =
+            Channel::loopback::<CtrlParseMsg, ParseCtrlMsg>();
+        let ((_, rx_parser_lane), (_tx_lane, _)) =
+            Channel::loopback::<ParseCtrlMsg, CtrlParseMsg>();
+        let rx_parser_ctrl = PriorityRx::new(rx_parser_ctrl, rx_parser_lane);
+        let ((tx_parser, rx_parser), (_tx_engine, mut rx_engine)) =
+            Channel::loopback::<ParseEngineMsg, EngineParseMsg>();
+
+        let parser = tokio::spawn(async move {
+            run(
+                getpid(),
+                &mut tx_parser_ctrl,
+                rx_parser_ctrl,
+                tx_parser,
+                rx_parser,
+            )
+            .await
+        });
+
+        tx_ctrl
+            .send(&CtrlParseMsg::Data("1 +".to_owned()))
+            .await
+            .unwrap();
+        tx_ctrl
+            .send(&CtrlParseMsg::Data("3 4 +".to_owned()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            rx_engine.recv().await.unwrap(),
+            ParseEngineMsg::NewValue(7.0)
+        );
+
+        let ParseCtrlMsg::Error(e) = rx_ctrl.recv().await.unwrap() else {
+            panic!("expected error report");
+        };
+        assert_eq!(e.subsystem, "parser");
+        assert_eq!(e.chain, ["Not enough operands for '+'."]);
+
+        parser.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stop_overtakes_queued_data() {
+        let ((tx_ctrl, _rx_ctrl), (mut tx_parser_ctrl, rx_parser_ctrl)) =
+            Channel::loopback::<CtrlParseMsg, ParseCtrlMsg>();
+        let ((_, rx_parser_lane), (tx_lane, _)) = Channel::loopback::<ParseCtrlMsg, CtrlParseMsg>();
+        let rx_parser_ctrl = PriorityRx::new(rx_parser_ctrl, rx_parser_lane);
+        let ((tx_parser, rx_parser), (_tx_engine, mut rx_engine)) =
+            Channel::loopback::<ParseEngineMsg, EngineParseMsg>();
+
+        let mut tx_ctrl = PriorityTx::new(tx_ctrl, tx_lane);
+        for _ in 0..10 {
+            tx_ctrl
+                .send(&CtrlParseMsg::Data("3 4 +".to_owned()))
+                .await
+                .unwrap();
+        }
+        tx_ctrl.send(&CtrlParseMsg::Stop).await.unwrap();
+
+        run(
+            getpid(),
+            &mut tx_parser_ctrl,
+            rx_parser_ctrl,
+            tx_parser,
+            rx_parser,
+        )
+        .await
+        .unwrap();
+
+        // Stopped before evaluating any of it.
+        assert!(rx_engine.recv().await.is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use clap::Command;
+use privsep_sandbox::Stages;
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+
+use crate::limits::Limits;
+use crate::namespaces::Namespaces;
+use crate::privileges::Privileges;
+use crate::proc;
+use crate::sandbox;
+use crate::subsystem::Subsystem;
+use crate::supervisor::Supervisor;
+use crate::topology::{Peer, Topology};
+
+type Main<E> = fn() -> Pin<Box<dyn Future<Output = Result<(), E>>>>;
+
+struct Entry<E> {
+    name: &'static str,
+    about: &'static str,
+    privileges: fn() -> Privileges,
+    namespaces: fn() -> Namespaces,
+    limits: fn() -> Limits,
+    sandbox: fn() -> Stages,
+    peers: fn() -> Vec<Peer>,
+    main: Main<E>,
+}
+
+/// A privsep application: one binary that runs as the controller, or as one
+/// of its subsystems when re-executed by `Supervisor::spawn`.
+///
+/// `E` is the application's error type, which every subsystem's error (and
+/// the controller's) converts into.
+pub struct App<E> {
+    name: &'static str,
+    version: Option<&'static str>,
+    subsystems: Vec<Entry<E>>,
+    topology: Topology,
+}
+
+impl<E> App<E>
+where
+    E: From<io::Error> + 'static,
+{
+    pub fn new(name: &'static str) -> Self {
+        App {
+            name,
+            version: None,
+            subsystems: Vec::new(),
+            topology: Topology::new(),
+        }
+    }
+
+    pub fn version(mut self, version: &'static str) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn subsystem<S>(mut self) -> Self
+    where
+        S: Subsystem + 'static,
+        S::Error: Into<E>,
+    {
+        self.subsystems.push(Entry {
+            name: S::NAME,
+            about: S::ABOUT,
+            privileges: S::privileges,
+            namespaces: S::namespaces,
+            limits: S::limits,
+            sandbox: S::sandbox,
+            peers: S::peers,
+            main: || Box::pin(async { S::main().await.map_err(Into::into) }),
+        });
+        self.topology = self.topology.subsystem::<S>();
+        self
+    }
+
+    /// Connects `A` and `B`, both registered with `subsystem`.
+    pub fn edge<A: Subsystem, B: Subsystem>(mut self) -> Self {
+        self.topology = self.topology.edge::<A, B>();
+        self
+    }
+
+    /// Replaces the topology built by `subsystem` and `edge`, e.g. with one
+    /// from `Topology::from_toml`. Only subsystems it lists are started.
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Dispatches on the command line: runs the subsystem it names, in its
+    /// namespaces with its privileges dropped and sandboxed, or `controller`
+    /// if it names none. The controller only starts once the topology has
+    /// been validated.
+    pub async fn run<F, Fut, CE>(self, controller: F) -> Result<(), E>
+    where
+        F: FnOnce(Supervisor) -> Fut,
+        Fut: Future<Output = Result<(), CE>>,
+        CE: Into<E>,
+    {
+        let matches = self.command().get_matches();
+
+        let Some(name) = matches.subcommand_name() else {
+            self.topology
+                .validate(|name| self.entry(name).map(|entry| (entry.peers)()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+            let privileged = self
+                .subsystems
+                .iter()
+                .filter(|entry| {
+                    !(entry.privileges)().is_empty() || !(entry.namespaces)().is_empty()
+                })
+                .map(|entry| entry.name)
+                .collect();
+            let confined = self
+                .subsystems
+                .iter()
+                .map(|entry| (entry.name, (entry.namespaces)(), (entry.limits)()))
+                .collect();
+
+            return controller(Supervisor::new(self.topology, privileged, confined))
+                .await
+                .map_err(Into::into);
+        };
+        let entry = self
+            .entry(name)
+            .expect("clap only accepts declared subsystems");
+
+        let privileges = (entry.privileges)();
+        let namespaces = (entry.namespaces)();
+        if !privileges.is_empty() || !namespaces.is_empty() {
+            drop_privileges(&privileges, &namespaces)?;
+        }
+        sandbox::enter((entry.sandbox)())?;
+        (entry.main)().await
+    }
+
+    fn entry(&self, name: &str) -> Option<&Entry<E>> {
+        self.subsystems.iter().find(|entry| entry.name == name)
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(self.name)
+            .subcommand_value_name("SUBSYSTEM")
+            .subcommand_help_heading("Subsystems")
+            .disable_help_subcommand(true);
+        if let Some(version) = self.version {
+            command = command.version(version);
+        }
+
+        for entry in &self.subsystems {
+            command = command.subcommand(Command::new(entry.name).about(entry.about));
+        }
+
+        command
+    }
+}
+
+/// Sets up the running subsystem's `namespaces` and drops it to `privileges`,
+/// then tells the controller how that went on the status socket.
+pub(crate) fn drop_privileges(privileges: &Privileges, namespaces: &Namespaces) -> io::Result<()> {
+    let dropped = namespaces.apply().and_then(|()| privileges.apply());
+    let mut status = UnixStream::from(proc::claim_fd(proc::STATUS)?);
+    if let Err(e) = &dropped {
+        // Best effort: the controller also sees us exit.
+        let _ = write!(status, "{e}");
+    }
+
+    dropped
+}
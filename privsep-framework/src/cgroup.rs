@@ -0,0 +1,107 @@
+use nix::unistd::{self, AccessFlags};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::limits::Limits;
+use crate::proc;
+
+const ROOT: &str = "/sys/fs/cgroup";
+
+/// The cgroup v2 delegated to the controller, under which each subsystem
+/// gets a cgroup of its own for `Limits::memory` and `Limits::cpu_percent`.
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    dir: PathBuf,
+}
+
+impl Cgroup {
+    /// Our cgroup, if it is on a cgroup v2 hierarchy and ours to manage. As
+    /// one that hands controllers to its children can't hold processes, we
+    /// move into a leaf of it first, named after the controller. If we can't,
+    /// e.g. as others share the cgroup, it isn't ours and caps go unenforced.
+    pub(crate) fn delegated() -> io::Result<Option<Cgroup>> {
+        let cgroups = match fs::read_to_string("/proc/self/cgroup") {
+            Ok(cgroups) => cgroups,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some(path) = own_path(&cgroups) else {
+            return Ok(None);
+        };
+        let dir = Path::new(ROOT).join(path.trim_start_matches('/'));
+        let controllers = match fs::read_to_string(dir.join("cgroup.controllers")) {
+            Ok(controllers) => controllers,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if unistd::access(&dir.join("cgroup.subtree_control"), AccessFlags::W_OK).is_err() {
+            return Ok(None);
+        }
+
+        let leaf = dir.join(proc::CONTROLLER);
+        create(&leaf)?;
+        if fs::write(leaf.join("cgroup.procs"), "0").is_err() {
+            let _ = fs::remove_dir(&leaf);
+            return Ok(None);
+        }
+
+        let enable: Vec<_> = controllers
+            .split_whitespace()
+            .filter(|controller| ["cpu", "memory"].contains(controller))
+            .map(|controller| format!("+{controller}"))
+            .collect();
+        if fs::write(dir.join("cgroup.subtree_control"), enable.join(" ")).is_err() {
+            // Leave the cgroup as we found it.
+            let _ = fs::write(dir.join("cgroup.procs"), "0");
+            let _ = fs::remove_dir(&leaf);
+            return Ok(None);
+        }
+
+        Ok(Some(Cgroup { dir }))
+    }
+
+    /// Sets up the cgroup for subsystem `name` with `limits`' caps, and opens
+    /// its `cgroup.procs` for the child to move itself in with.
+    pub(crate) fn child(&self, name: &str, limits: &Limits) -> io::Result<File> {
+        let dir = self.dir.join(name);
+        create(&dir)?;
+        for (file, value) in limits.cgroup() {
+            fs::write(dir.join(file), value)?;
+        }
+
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join("cgroup.procs"))
+    }
+
+    /// Removes the cgroup of subsystem `name`, once its child has exited.
+    /// Best effort: one that is still in use stays.
+    pub(crate) fn remove(&self, name: &str) {
+        let _ = fs::remove_dir(self.dir.join(name));
+    }
+}
+
+fn create(dir: &Path) -> io::Result<()> {
+    match fs::create_dir(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Our path in the cgroup v2 hierarchy, from `/proc/self/cgroup`.
+fn own_path(cgroups: &str) -> Option<&str> {
+    cgroups.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::own_path;
+
+    #[test]
+    fn test_own_path_is_on_the_unified_hierarchy() {
+        let cgroups = "4:memory:/legacy\n0::/system.slice/privsep.service\n";
+        assert_eq!(own_path(cgroups), Some("/system.slice/privsep.service"));
+        assert_eq!(own_path("4:memory:/legacy\n"), None);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod app;
+mod cgroup;
+pub mod limits;
+pub mod namespaces;
+pub mod privileges;
+pub mod proc;
+pub mod sandbox;
+pub mod subsystem;
+pub mod supervisor;
+pub mod topology;
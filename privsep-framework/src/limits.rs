@@ -0,0 +1,212 @@
+use nix::sys::resource::{self, Resource};
+use std::io;
+
+/// What tokio's worker stacks and malloc arenas may take on top of a memory
+/// cap, where RLIMIT_DATA stands in for it.
+const RUNTIME_HEADROOM: u64 = 128 << 20;
+
+/// Caps on what a subsystem may use (see `Subsystem::limits`). The rlimits
+/// are set in the child as it starts. Memory and CPU caps need a cgroup v2
+/// hierarchy delegated to the controller; without one, memory is bounded
+/// more loosely by RLIMIT_DATA and CPU not at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    address_space: Option<u64>,
+    cpu_seconds: Option<u64>,
+    open_files: Option<u64>,
+    core_size: Option<u64>,
+    processes: Option<u64>,
+    memory: Option<u64>,
+    cpu_percent: Option<u32>,
+    // RLIMIT_DATA, standing in for `memory` without a cgroup.
+    data: Option<u64>,
+}
+
+/// How much of a subsystem's `Limits` holds, as returned by
+/// `Supervisor::spawn`. The rlimits always do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enforcement {
+    Full,
+    /// No cgroup: memory is bounded by RLIMIT_DATA instead, and CPU not at
+    /// all.
+    Partial,
+    /// No cgroup, and no memory cap to stand in for: CPU isn't bounded.
+    None,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    /// In bytes. Data segment size where there is no address space limit,
+    /// as on OpenBSD.
+    pub fn address_space(mut self, bytes: u64) -> Self {
+        self.address_space = Some(bytes);
+        self
+    }
+
+    /// CPU time over the child's whole life, after which it is killed. Only
+    /// for one-shot workers: a long-running subsystem would hit it in time.
+    pub fn cpu_seconds(mut self, seconds: u64) -> Self {
+        self.cpu_seconds = Some(seconds);
+        self
+    }
+
+    /// One more than the highest fd the child can open.
+    pub fn open_files(mut self, count: u64) -> Self {
+        self.open_files = Some(count);
+        self
+    }
+
+    /// In bytes, 0 for no core dumps.
+    pub fn core_size(mut self, bytes: u64) -> Self {
+        self.core_size = Some(bytes);
+        self
+    }
+
+    /// Counted across everything the child's real user runs, threads
+    /// included, so mostly of use with `Privileges::user`.
+    pub fn processes(mut self, count: u64) -> Self {
+        self.processes = Some(count);
+        self
+    }
+
+    /// The cgroup's `memory.max`, in bytes.
+    pub fn memory(mut self, bytes: u64) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+
+    /// The cgroup's `cpu.max`, as a percentage of one CPU.
+    pub fn cpu_percent(mut self, percent: u32) -> Self {
+        self.cpu_percent = Some(percent);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// Stands in for the cgroup caps where there is no cgroup to hold them.
+    pub(crate) fn without_cgroup(mut self) -> (Limits, Enforcement) {
+        let enforcement = match self.memory {
+            Some(_) => Enforcement::Partial,
+            None => Enforcement::None,
+        };
+        self.data = self.memory.take().map(|bytes| bytes + RUNTIME_HEADROOM);
+        self.cpu_percent = None;
+
+        (self, enforcement)
+    }
+
+    /// Each rlimit, to set as both the soft and the hard limit.
+    pub(crate) fn rlimits(&self) -> Vec<(Resource, u64)> {
+        #[cfg(not(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")))]
+        let (address_space, data) = (
+            (Resource::RLIMIT_AS, self.address_space),
+            (Resource::RLIMIT_DATA, self.data),
+        );
+        // Both are the data segment here: the lower one wins.
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+        let (address_space, data) = (
+            (
+                Resource::RLIMIT_DATA,
+                match (self.address_space, self.data) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                },
+            ),
+            (Resource::RLIMIT_DATA, None),
+        );
+
+        [
+            address_space,
+            data,
+            (Resource::RLIMIT_CPU, self.cpu_seconds),
+            (Resource::RLIMIT_NOFILE, self.open_files),
+            (Resource::RLIMIT_CORE, self.core_size),
+            (Resource::RLIMIT_NPROC, self.processes),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| Some((resource, limit?)))
+        .collect()
+    }
+
+    /// The cgroup files to write, and what.
+    pub(crate) fn cgroup(&self) -> Vec<(&'static str, String)> {
+        let mut files = Vec::new();
+        if let Some(bytes) = self.memory {
+            files.push(("memory.max", bytes.to_string()));
+        }
+        if let Some(percent) = self.cpu_percent {
+            files.push(("cpu.max", format!("{} 100000", u64::from(percent) * 1000)));
+        }
+
+        files
+    }
+}
+
+/// Sets `rlimits` in the forked child. Nothing here allocates.
+pub(crate) fn apply(rlimits: &[(Resource, u64)]) -> io::Result<()> {
+    for &(resource, limit) in rlimits {
+        resource::setrlimit(resource, limit as _, limit as _)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Enforcement, Limits, RUNTIME_HEADROOM};
+    use crate::namespaces::Namespaces;
+    use crate::proc::spawn;
+    use nix::sys::resource::Resource;
+    use std::os::unix::net::UnixStream;
+    use tokio::io::AsyncReadExt;
+    use tokio::process::Command;
+
+    #[tokio::test]
+    async fn test_child_starts_with_its_rlimits() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let mut ours = tokio::net::UnixStream::from_std(ours).unwrap();
+        let mut cmd = Command::new("bash");
+        cmd.args(["-c", "echo $(ulimit -n) $(ulimit -c) $(ulimit -t) >&56"]);
+
+        let limits = Limits::new().open_files(32).core_size(0).cpu_seconds(5);
+        let mut child = spawn(
+            cmd,
+            theirs.into(),
+            Vec::new(),
+            Namespaces::default(),
+            limits,
+            None,
+        )
+        .unwrap();
+        assert!(child.wait().await.unwrap().success());
+
+        let mut out = String::new();
+        ours.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out.trim(), "32 0 5");
+
+        let limits = Limits::new().memory(64 << 20).cpu_percent(50);
+        assert!(limits.rlimits().is_empty());
+        assert_eq!(limits.cgroup()[1], ("cpu.max", "50000 100000".to_owned()));
+    }
+
+    #[test]
+    fn test_memory_falls_back_to_rlimit_data() {
+        let limits = Limits::new().memory(64 << 20).cpu_percent(50);
+        let (fallback, enforcement) = limits.without_cgroup();
+        assert_eq!(enforcement, Enforcement::Partial);
+        assert!(fallback.cgroup().is_empty());
+        assert_eq!(
+            fallback.rlimits(),
+            [(Resource::RLIMIT_DATA, (64 << 20) + RUNTIME_HEADROOM)]
+        );
+
+        let (_, enforcement) = Limits::new().cpu_percent(50).without_cgroup();
+        assert_eq!(enforcement, Enforcement::None);
+    }
+}
@@ -0,0 +1,303 @@
+use std::io;
+
+#[cfg(target_os = "linux")]
+use nix::libc::{self, c_int};
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+
+/// The Linux namespaces a subsystem runs in (see `Subsystem::namespaces`),
+/// unshared as it is started. Without root, a user namespace is unshared
+/// too, mapping us to root inside it. Ignored on other platforms.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Namespaces {
+    user: bool,
+    network: bool,
+    mount: bool,
+    pid: bool,
+    ipc: bool,
+    uts: bool,
+    empty_root: bool,
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        Namespaces::default()
+    }
+
+    /// Also as root, e.g. to keep root from meaning anything outside.
+    pub fn user(mut self) -> Self {
+        self.user = true;
+        self
+    }
+
+    /// Leaves the child only a loopback interface, which is down.
+    pub fn network(mut self) -> Self {
+        self.network = true;
+        self
+    }
+
+    pub fn mount(mut self) -> Self {
+        self.mount = true;
+        self
+    }
+
+    /// Makes the child pid 1 of its own namespace. It is killed with the
+    /// process the controller supervises in its place.
+    pub fn pid(mut self) -> Self {
+        self.pid = true;
+        self
+    }
+
+    pub fn ipc(mut self) -> Self {
+        self.ipc = true;
+        self
+    }
+
+    pub fn uts(mut self) -> Self {
+        self.uts = true;
+        self
+    }
+
+    /// Replaces the child's filesystem with an empty, read-only tmpfs once it
+    /// has started, in a mount namespace of its own.
+    pub fn empty_root(mut self) -> Self {
+        self.mount = true;
+        self.empty_root = true;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Namespaces::default()
+    }
+
+    /// What the child does in `pre_exec`, prepared beforehand as it can't
+    /// allocate there.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn unshare(&self) -> Unshare {
+        let mut flags = 0;
+        for (wanted, flag) in [
+            (self.network, libc::CLONE_NEWNET),
+            (self.mount, libc::CLONE_NEWNS),
+            (self.pid, libc::CLONE_NEWPID),
+            (self.ipc, libc::CLONE_NEWIPC),
+            (self.uts, libc::CLONE_NEWUTS),
+        ] {
+            if wanted {
+                flags |= flag;
+            }
+        }
+
+        let root = unsafe { libc::geteuid() } == 0;
+        let mut maps = Vec::new();
+        if self.user || (flags != 0 && !root) {
+            flags |= libc::CLONE_NEWUSER;
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            for (file, contents) in [
+                ("/proc/self/setgroups", "deny".to_owned()),
+                ("/proc/self/uid_map", format!("0 {uid} 1")),
+                ("/proc/self/gid_map", format!("0 {gid} 1")),
+            ] {
+                let file = CString::new(file).unwrap();
+                maps.push((file, contents.into_bytes()));
+            }
+        }
+
+        Unshare {
+            flags,
+            maps,
+            pid: self.pid,
+        }
+    }
+
+    /// Swaps the root for an empty tmpfs, in the child. Every thread follows.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.empty_root {
+            unsafe { empty_root() }?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn empty_root() -> io::Result<()> {
+    let check = |result: c_int, what: &str| {
+        if result == 0 {
+            Ok(())
+        } else {
+            let e = io::Error::last_os_error();
+            Err(io::Error::new(e.kind(), format!("{what} failed: {e}")))
+        }
+    };
+    let none = std::ptr::null::<libc::c_char>();
+
+    // Nothing we do here may show up outside.
+    check(
+        libc::mount(
+            none,
+            c"/".as_ptr(),
+            none,
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        ),
+        "making / private",
+    )?;
+    // Any directory will do as the mount point, and /tmp is as good as always
+    // there. Pivoting into it leaves the old root beneath, to be detached.
+    // The smallest size it takes, as size=0 is no limit at all.
+    check(
+        libc::mount(
+            c"tmpfs".as_ptr(),
+            c"/tmp".as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            c"size=1,mode=0555".as_ptr().cast(),
+        ),
+        "mounting tmpfs",
+    )?;
+    check(libc::chdir(c"/tmp".as_ptr()), "chdir to /tmp")?;
+    check(
+        libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as c_int,
+        "pivot_root",
+    )?;
+    check(
+        libc::umount2(c".".as_ptr(), libc::MNT_DETACH),
+        "detaching the old root",
+    )?;
+    check(
+        libc::mount(
+            none,
+            c"/".as_ptr(),
+            none,
+            // Whatever isn't passed again is cleared.
+            libc::MS_REMOUNT
+                | libc::MS_BIND
+                | libc::MS_RDONLY
+                | libc::MS_NOSUID
+                | libc::MS_NODEV
+                | libc::MS_NOEXEC,
+            std::ptr::null(),
+        ),
+        "remounting / read-only",
+    )?;
+    check(libc::chdir(c"/".as_ptr()), "chdir to /")
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) struct Unshare {
+    flags: c_int,
+    // Files under /proc/self to write, in order, once in the user namespace.
+    maps: Vec<(CString, Vec<u8>)>,
+    pid: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl Unshare {
+    /// Moves the forked child into the new namespaces. Only what is
+    /// async-signal-safe may be done here.
+    pub(crate) unsafe fn enter(&self) -> io::Result<()> {
+        if self.flags == 0 {
+            return Ok(());
+        }
+        if libc::unshare(self.flags) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for (file, contents) in &self.maps {
+            let fd = libc::open(file.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+            libc::close(fd);
+            if written == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // Only our children join a new pid namespace. The first becomes pid 1
+        // and goes on to exec, while we wait for it with nothing open, so
+        // that std still learns when it has.
+        if self.pid {
+            // We hold the write end for as long as we live, so the child can
+            // tell if we died before it asked to die with us. getppid(2)
+            // can't: it is 0 for a parent outside the pid namespace.
+            let mut alive = [0; 2];
+            if libc::pipe2(alive.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let [watch, hold] = alive;
+
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {
+                    libc::close(hold);
+                    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                    let mut byte = 0u8;
+                    if libc::read(watch, std::ptr::addr_of_mut!(byte).cast(), 1) == 0 {
+                        libc::_exit(127);
+                    }
+                    libc::close(watch);
+                }
+                child => {
+                    let (hold, last) = (hold as libc::c_uint, libc::c_uint::MAX);
+                    libc::syscall(libc::SYS_close_range, 0, hold - 1, 0);
+                    libc::syscall(libc::SYS_close_range, hold + 1, last, 0);
+                    let mut status = 0;
+                    while libc::waitpid(child, &mut status, 0) == -1 {
+                        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                            libc::_exit(127);
+                        }
+                    }
+                    let code = if libc::WIFEXITED(status) {
+                        libc::WEXITSTATUS(status)
+                    } else {
+                        128 + libc::WTERMSIG(status)
+                    };
+                    libc::_exit(code);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::Namespaces;
+    use crate::limits::Limits;
+    use crate::proc::spawn;
+    use std::os::unix::net::UnixStream;
+    use tokio::io::AsyncReadExt;
+    use tokio::process::Command;
+
+    #[tokio::test]
+    async fn test_child_is_alone_in_its_namespaces() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let mut ours = tokio::net::UnixStream::from_std(ours).unwrap();
+        let mut cmd = Command::new("bash");
+        cmd.args([
+            "-c",
+            "hostname isolated; echo $$ $(hostname) $(cut -d: -f1 -s /proc/net/dev) >&56",
+        ]);
+
+        let namespaces = Namespaces::new().network().pid().uts();
+        let mut child = spawn(
+            cmd,
+            theirs.into(),
+            Vec::new(),
+            namespaces,
+            Limits::default(),
+            None,
+        )
+        .unwrap();
+        assert!(child.wait().await.unwrap().success());
+
+        let mut out = String::new();
+        ours.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out.trim(), "1 isolated lo");
+        assert!(Namespaces::new().is_empty());
+    }
+}
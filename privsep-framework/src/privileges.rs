@@ -0,0 +1,162 @@
+use nix::unistd::{self, Gid, Group, Uid, User};
+use std::io;
+use std::path::PathBuf;
+
+/// Who a subsystem runs as and where (see `Subsystem::privileges`). Applied in
+/// the child right after exec, before its sandbox and `main`. The controller's
+/// `Supervisor::spawn` fails if they can't be.
+#[derive(Debug, Clone, Default)]
+pub struct Privileges {
+    user: Option<String>,
+    group: Option<String>,
+    groups: Option<Vec<String>>,
+    chroot: Option<PathBuf>,
+    workdir: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Ids {
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    groups: Option<Vec<Gid>>,
+}
+
+impl Privileges {
+    pub fn new() -> Self {
+        Privileges::default()
+    }
+
+    /// Runs as `name`, in its primary group and no supplementary groups
+    /// unless `group` or `groups` say otherwise.
+    pub fn user(mut self, name: &str) -> Self {
+        self.user = Some(name.to_owned());
+        self
+    }
+
+    pub fn group(mut self, name: &str) -> Self {
+        self.group = Some(name.to_owned());
+        self
+    }
+
+    /// The supplementary groups, replacing the user's default.
+    pub fn groups(mut self, names: &[&str]) -> Self {
+        self.groups = Some(names.iter().map(|name| (*name).to_owned()).collect());
+        self
+    }
+
+    /// Confines the child to `dir`, typically an empty directory owned by
+    /// root such as `/var/empty`.
+    pub fn chroot(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.chroot = Some(dir.into());
+        self
+    }
+
+    /// Where the child starts, inside the chroot if there is one. Defaults to
+    /// the root of the chroot.
+    pub fn workdir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.workdir = Some(dir.into());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none()
+            && self.group.is_none()
+            && self.groups.is_none()
+            && self.chroot.is_none()
+            && self.workdir.is_none()
+    }
+
+    fn ids(&self) -> io::Result<Ids> {
+        let user = match &self.user {
+            Some(name) => Some(User::from_name(name)?.ok_or_else(|| unknown("user", name))?),
+            None => None,
+        };
+        let gid = match &self.group {
+            Some(name) => Some(group(name)?),
+            None => user.as_ref().map(|user| user.gid),
+        };
+        let groups = match &self.groups {
+            Some(names) => Some(
+                names
+                    .iter()
+                    .map(|name| group(name))
+                    .collect::<io::Result<_>>()?,
+            ),
+            None => gid.map(|gid| vec![gid]),
+        };
+
+        Ok(Ids {
+            uid: user.map(|user| user.uid),
+            gid,
+            groups,
+        })
+    }
+
+    /// Drops to these privileges. Names are looked up first, as the chroot
+    /// usually hides the user database, and the user is changed last, as
+    /// everything before it needs root.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        let ids = self.ids()?;
+
+        if let Some(dir) = &self.chroot {
+            unistd::chroot(dir).map_err(failed(format!("chroot to {}", dir.display())))?;
+            unistd::chdir("/").map_err(failed("chdir to /".to_owned()))?;
+        }
+        if let Some(dir) = &self.workdir {
+            unistd::chdir(dir).map_err(failed(format!("chdir to {}", dir.display())))?;
+        }
+        if let Some(groups) = &ids.groups {
+            unistd::setgroups(groups).map_err(failed("setgroups".to_owned()))?;
+        }
+        if let Some(gid) = ids.gid {
+            unistd::setgid(gid).map_err(failed(format!("setgid to {gid}")))?;
+        }
+        if let Some(uid) = ids.uid {
+            unistd::setuid(uid).map_err(failed(format!("setuid to {uid}")))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn group(name: &str) -> io::Result<Gid> {
+    Ok(Group::from_name(name)?
+        .ok_or_else(|| unknown("group", name))?
+        .gid)
+}
+
+fn unknown(what: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no such {what}: {name}"))
+}
+
+fn failed(what: String) -> impl FnOnce(nix::Error) -> io::Error {
+    move |e| io::Error::new(io::Error::from(e).kind(), format!("{what} failed: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ids, Privileges};
+    use nix::unistd::{Gid, Uid};
+    use std::io;
+
+    #[test]
+    fn test_user_brings_its_primary_group_only() {
+        let ids = Privileges::new().user("root").ids().unwrap();
+        assert_eq!(
+            ids,
+            Ids {
+                uid: Some(Uid::from_raw(0)),
+                gid: Some(Gid::from_raw(0)),
+                groups: Some(vec![Gid::from_raw(0)]),
+            }
+        );
+
+        let ids = Privileges::new().groups(&[]).ids().unwrap();
+        assert_eq!(ids.groups, Some(Vec::new()));
+        assert_eq!(ids.uid, None);
+
+        let e = Privileges::new().user("no-such-user").ids().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(Privileges::new().is_empty());
+    }
+}
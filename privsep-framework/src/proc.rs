@@ -0,0 +1,393 @@
+use nix::libc;
+use privsep_channel::channel_redux::Channel;
+use privsep_channel::seal::SealKeys;
+use privsep_channel::serializefd::SerializeFd;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Result};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+use tokio::process::{Child, Command};
+
+use crate::limits::{self, Limits};
+use crate::namespaces::Namespaces;
+use crate::subsystem::Subsystem;
+
+/// Where a child finds its first inherited fd, its end of the controller's
+/// socket. The others follow in the order of the manifest, see `FDS_ENV`.
+pub static SOCKFD: RawFd = 56;
+
+/// How many fds a child inherited, from `SOCKFD` on. In the manner of
+/// systemd's `LISTEN_FDS`.
+pub const FDS_ENV: &str = "PRIVSEP_FDS";
+
+/// The names of the inherited fds, in order and separated by `:`.
+pub const FDNAMES_ENV: &str = "PRIVSEP_FDNAMES";
+
+/// The socket the manifest was made for, as the device and inode numbers of
+/// the fd at `SOCKFD`. Like systemd's `LISTEN_PID`, it keeps a process that
+/// inherits the environment, but not the fds, from taking it for its own.
+pub const FDS_ID_ENV: &str = "PRIVSEP_FDS_ID";
+
+/// The name of the controller's socket in the manifest.
+pub const CONTROLLER: &str = "controller";
+
+/// The name of the socket a child with `Subsystem::privileges` reports
+/// dropping them on: an error message, or nothing once it has.
+pub const STATUS: &str = "status";
+
+/// The name of the pipe a child sealed with `Supervisor::seal` reads its
+/// keys from.
+pub const KEYS: &str = "keys";
+
+// Fds handed out by `claim_fd`, which must not be owned twice.
+static CLAIMED: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// Re-executes the current binary as `subsystem`, with `child_sock` installed
+/// at `SOCKFD` and each of `fds` after it, in `namespaces` and within
+/// `limits`. A `cgroup`, its `cgroup.procs` open for writing, is joined
+/// before anything else. No other fd of ours but stdio survives into the
+/// child.
+pub fn start(
+    subsystem: &str,
+    child_sock: OwnedFd,
+    fds: Vec<(String, OwnedFd)>,
+    namespaces: Namespaces,
+    limits: Limits,
+    cgroup: Option<OwnedFd>,
+) -> Result<Child> {
+    let exe = std::env::current_exe()?;
+    let mut cmd = Command::new(exe);
+    cmd.arg(subsystem);
+
+    spawn(cmd, child_sock, fds, namespaces, limits, cgroup)
+}
+
+/// Like `start`, but runs `cmd`, e.g. a subsystem under test that is driven
+/// by something other than its controller.
+pub fn spawn(
+    mut cmd: Command,
+    child_sock: OwnedFd,
+    fds: Vec<(String, OwnedFd)>,
+    namespaces: Namespaces,
+    limits: Limits,
+    cgroup: Option<OwnedFd>,
+) -> Result<Child> {
+    let fds: Vec<_> = [(CONTROLLER.to_owned(), child_sock)]
+        .into_iter()
+        .chain(fds)
+        .collect();
+    let mut names: Vec<&str> = Vec::new();
+    for (name, _) in &fds {
+        if name.is_empty() || name.contains(':') || names.contains(&name.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name:?} can't name an inherited fd"),
+            ));
+        }
+        names.push(name);
+    }
+    cmd.env(FDS_ENV, fds.len().to_string());
+    cmd.env(FDNAMES_ENV, names.join(":"));
+    cmd.env(FDS_ID_ENV, file_id(fds[0].1.as_raw_fd())?);
+
+    let mut raw: Vec<RawFd> = fds.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+    let top = SOCKFD + raw.len() as RawFd;
+    #[cfg(target_os = "linux")]
+    let unshare = namespaces.unshare();
+    #[cfg(not(target_os = "linux"))]
+    let _ = namespaces;
+    let rlimits = limits.rlimits();
+    let cgroup_fd = cgroup.as_ref().map(AsRawFd::as_raw_fd);
+
+    unsafe {
+        cmd.pre_exec(move || {
+            // Writing 0 moves the writer, before its fd can be clobbered.
+            if let Some(fd) = cgroup_fd {
+                if libc::write(fd, b"0".as_ptr().cast(), 1) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            // Move everything above the range first, so that installing one
+            // fd can't clobber another that is still to be installed. The
+            // originals are close-on-exec.
+            for fd in raw.iter_mut() {
+                *fd = libc::fcntl(*fd, libc::F_DUPFD, top);
+                if *fd == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            for (target, &fd) in (SOCKFD..).zip(raw.iter()) {
+                if libc::dup2(fd, target) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::close(fd);
+            }
+
+            // Anything else we have open, such as other children's sockets,
+            // is closed at exec. Closing it here would also close the pipe
+            // std reports a failed exec through.
+            cloexec_range(3, SOCKFD - 1);
+            cloexec_range(top, RawFd::MAX);
+
+            #[cfg(target_os = "linux")]
+            unshare.enter()?;
+
+            limits::apply(&rlimits)
+        });
+    }
+
+    // Our copies of `fds` and `cgroup` are closed once the child has its
+    // own.
+    cmd.spawn()
+}
+
+/// Marks every open fd from `first` to `last` close-on-exec.
+unsafe fn cloexec_range(first: RawFd, last: RawFd) {
+    #[cfg(target_os = "linux")]
+    {
+        let (first, last) = (first as libc::c_uint, last as libc::c_uint);
+        if libc::syscall(
+            libc::SYS_close_range,
+            first,
+            last,
+            libc::CLOSE_RANGE_CLOEXEC,
+        ) == 0
+        {
+            return;
+        }
+    }
+
+    // Without close_range, one at a time up to the descriptor limit.
+    let max = match libc::sysconf(libc::_SC_OPEN_MAX) {
+        max if max > 0 => max.min(RawFd::MAX as libc::c_long) as RawFd,
+        _ => 1024,
+    };
+    for fd in first..=last.min(max - 1) {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+}
+
+/// Identifies the file open at `fd`, see `FDS_ID_ENV`.
+fn file_id(fd: RawFd) -> Result<String> {
+    let stat = nix::sys::stat::fstat(fd)?;
+
+    Ok(format!("{}:{}", stat.st_dev, stat.st_ino))
+}
+
+/// Takes ownership of the inherited fd called `name`. Each fd can be
+/// claimed once.
+pub fn claim_fd(name: &str) -> Result<OwnedFd> {
+    let fd = manifest()?
+        .into_iter()
+        .find(|(fd_name, _)| fd_name == name)
+        .map(|(_, fd)| fd)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no inherited fd called {name}"),
+            )
+        })?;
+
+    let mut claimed = CLAIMED.lock().unwrap();
+    if claimed.contains(&fd) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("inherited fd {name} was already claimed"),
+        ));
+    }
+    claimed.push(fd);
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// The names and numbers of the inherited fds, none if the manifest in our
+/// environment was meant for another process. They are close-on-exec from
+/// then on, so that nothing we run inherits them.
+pub fn manifest() -> Result<Vec<(String, RawFd)>> {
+    let count = std::env::var(FDS_ENV).ok();
+    let names = std::env::var(FDNAMES_ENV).unwrap_or_default();
+    let ours =
+        std::env::var(FDS_ID_ENV).is_ok_and(|id| file_id(SOCKFD).is_ok_and(|actual| actual == id));
+    if !ours {
+        return Ok(Vec::new());
+    }
+
+    let manifest = parse_manifest(count.as_deref(), &names)?;
+    for &(_, fd) in &manifest {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    Ok(manifest)
+}
+
+fn parse_manifest(count: Option<&str>, names: &str) -> Result<Vec<(String, RawFd)>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed fd manifest");
+
+    let Some(count) = count else {
+        return Ok(Vec::new());
+    };
+    let count: usize = count.parse().map_err(|_| invalid())?;
+    let names: Vec<&str> = names.split(':').filter(|name| !name.is_empty()).collect();
+    if names.len() != count {
+        return Err(invalid());
+    }
+
+    Ok(names.into_iter().map(str::to_owned).zip(SOCKFD..).collect())
+}
+
+/// The child's end of the channel to the controller, from inside subsystem
+/// `S`.
+pub fn parent<S: Subsystem>() -> Result<Channel<S::FromChild, S::ToChild>> {
+    Channel::duplex_from_fd(claim_fd(CONTROLLER)?.into_raw_fd())
+}
+
+/// The keys for the child's channel to the controller, if it was sealed
+/// with `Supervisor::seal`.
+pub fn seal_keys() -> Result<SealKeys> {
+    SealKeys::import(claim_fd(KEYS)?)
+}
+
+/// The child's channel to its peer `name`, sending `M` and receiving `N` as
+/// declared in `Subsystem::peers`.
+pub fn peer<M, N>(name: &str) -> Result<Channel<M, N>>
+where
+    M: SerializeFd,
+    M: Serialize,
+    N: SerializeFd,
+    N: DeserializeOwned,
+{
+    Channel::duplex_from_fd(claim_fd(name)?.into_raw_fd())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{manifest, parse_manifest, spawn, FDS_ENV, SOCKFD};
+    use crate::limits::Limits;
+    use crate::namespaces::Namespaces;
+    use nix::libc;
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+    use tokio::process::Command;
+
+    fn pair() -> (UnixStream, OwnedFd) {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        (UnixStream::from_std(ours).unwrap(), theirs.into())
+    }
+
+    #[tokio::test]
+    async fn test_child_finds_named_fds_from_sockfd() {
+        let (mut parent_sock, child_sock) = pair();
+        let (mut engine, theirs) = pair();
+        let (mut log, log_theirs) = pair();
+        let mut cmd = Command::new("bash");
+        cmd.args([
+            "-c",
+            "printf hello >&56; printf $PRIVSEP_FDS >&57; printf $PRIVSEP_FDNAMES >&58",
+        ]);
+
+        let fds = vec![
+            ("engine".to_owned(), theirs),
+            ("log".to_owned(), log_theirs),
+        ];
+        let mut child = spawn(
+            cmd,
+            child_sock,
+            fds,
+            Namespaces::default(),
+            Limits::default(),
+            None,
+        )
+        .unwrap();
+        assert!(child.wait().await.unwrap().success());
+
+        for (sock, expected) in [
+            (&mut parent_sock, "hello"),
+            (&mut engine, "3"),
+            (&mut log, "controller:engine:log"),
+        ] {
+            let mut out = String::new();
+            sock.read_to_string(&mut out).await.unwrap();
+            assert_eq!(out, expected);
+        }
+
+        let manifest = parse_manifest(Some("3"), "controller:engine:log").unwrap();
+        assert_eq!(manifest[2], ("log".to_owned(), 58));
+        assert!(parse_manifest(Some("2"), "controller:engine:log").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_child_inherits_nothing_else() {
+        let (mut parent_sock, child_sock) = pair();
+        let (_engine, theirs) = pair();
+        // Neither close-on-exec nor ours to hand out.
+        let leaked = unsafe { libc::dup(parent_sock.as_raw_fd()) };
+        assert!(leaked > 2);
+        let mut cmd = Command::new("bash");
+        cmd.args(["-c", "ls /proc/$$/fd >&56"]);
+
+        let fds = vec![("engine".to_owned(), theirs)];
+        let mut child = spawn(
+            cmd,
+            child_sock,
+            fds,
+            Namespaces::default(),
+            Limits::default(),
+            None,
+        )
+        .unwrap();
+        assert!(child.wait().await.unwrap().success());
+        unsafe { libc::close(leaked) };
+
+        let mut out = String::new();
+        parent_sock.read_to_string(&mut out).await.unwrap();
+        let mut open: Vec<i32> = out.lines().map(|fd| fd.parse().unwrap()).collect();
+        open.sort();
+        assert_eq!(open, [0, 1, 2, 56, 57]);
+    }
+
+    const NAME: &str = "proc::tests::test_manifest_stays_with_its_process";
+
+    #[tokio::test]
+    async fn test_manifest_stays_with_its_process() {
+        if std::env::var_os("PRIVSEP_TEST_GRANDCHILD").is_some() {
+            // The manifest is in our environment, but the fd at SOCKFD is
+            // not the socket it names.
+            assert!(manifest().unwrap().is_empty());
+            return;
+        }
+        if std::env::var(FDS_ENV).is_ok() {
+            assert_eq!(manifest().unwrap().len(), 1);
+            let flags = unsafe { libc::fcntl(SOCKFD, libc::F_GETFD) };
+            assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+            let status = std::process::Command::new("bash")
+                .args(["-c", "exec \"$0\" \"$1\" 56</dev/null"])
+                .arg(std::env::current_exe().unwrap())
+                .arg(NAME)
+                .env("PRIVSEP_TEST_GRANDCHILD", "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let (_parent_sock, child_sock) = pair();
+        let mut cmd = Command::new(std::env::current_exe().unwrap());
+        cmd.arg(NAME);
+        let mut child = spawn(
+            cmd,
+            child_sock,
+            Vec::new(),
+            Namespaces::default(),
+            Limits::default(),
+            None,
+        )
+        .unwrap();
+        assert!(child.wait().await.unwrap().success());
+    }
+}
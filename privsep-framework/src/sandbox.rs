@@ -0,0 +1,29 @@
+use privsep_sandbox::{Enforcement, Stages};
+use std::io;
+use std::sync::Mutex;
+
+// The running subsystem's stages (see `Subsystem::sandbox`), from the first
+// on.
+static STAGES: Mutex<Option<Stages>> = Mutex::new(None);
+
+/// Enters the first of `stages`, before the subsystem's `main` runs.
+pub(crate) fn enter(mut stages: Stages) -> io::Result<()> {
+    if let Some(first) = stages.first() {
+        stages.advance(first)?;
+    }
+    *STAGES.lock().unwrap() = Some(stages);
+
+    Ok(())
+}
+
+/// Narrows the running subsystem to the stage `name` of its
+/// `Subsystem::sandbox`, see `Stages::advance`.
+pub fn advance(name: &str) -> io::Result<Enforcement> {
+    match STAGES.lock().unwrap().as_mut() {
+        Some(stages) => stages.advance(name),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not running as a subsystem",
+        )),
+    }
+}
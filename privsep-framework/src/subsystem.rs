@@ -0,0 +1,58 @@
+use privsep_channel::serializefd::SerializeFd;
+use privsep_sandbox::Stages;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+
+use crate::limits::Limits;
+use crate::namespaces::Namespaces;
+use crate::privileges::Privileges;
+use crate::topology::Peer;
+
+/// A child process of a privsep application. Implement it on a marker type
+/// (e.g. `struct Parser;`), register it with `App::subsystem` and start it
+/// from the controller with `Supervisor::spawn`.
+pub trait Subsystem {
+    /// Names the child on the command line it is re-executed with.
+    const NAME: &'static str;
+    /// Shown by `--help`.
+    const ABOUT: &'static str = "";
+
+    /// Messages from the controller to the child.
+    type ToChild: SerializeFd + Serialize + DeserializeOwned;
+    /// Messages from the child to the controller.
+    type FromChild: SerializeFd + Serialize + DeserializeOwned;
+    type Error;
+
+    /// Dropped to in the child, before its sandbox is applied.
+    fn privileges() -> Privileges {
+        Privileges::default()
+    }
+
+    /// Unshared for the child as it starts, on Linux. Any empty root is set
+    /// up before its privileges are dropped.
+    fn namespaces() -> Namespaces {
+        Namespaces::default()
+    }
+
+    /// What the child may use, capped as it starts.
+    fn limits() -> Limits {
+        Limits::default()
+    }
+
+    /// The child's stages, each narrower than the last. The first is
+    /// entered before `main` runs, which moves on with `sandbox::advance`.
+    fn sandbox() -> Stages {
+        Stages::new()
+    }
+
+    /// The subsystems this one has a direct channel to, each of which must
+    /// be joined to it by an edge of the `Topology`.
+    fn peers() -> Vec<Peer> {
+        Vec::new()
+    }
+
+    /// The child's entry point. Its channel to the controller is
+    /// `proc::parent::<Self>()`.
+    fn main() -> impl Future<Output = Result<(), Self::Error>>;
+}
@@ -0,0 +1,389 @@
+use privsep_channel::channel_redux::Channel;
+use privsep_channel::error::ChannelError;
+use privsep_channel::seal::SealKeys;
+use privsep_channel::serializefd::SerializeFd;
+use privsep_channel::session::{Session, Transmit, Variant};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::pin::pin;
+use std::process::ExitStatus;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::UnixStream;
+use tokio::process::Child;
+use tokio::time::timeout;
+
+use crate::cgroup::Cgroup;
+use crate::limits::{Enforcement, Limits};
+use crate::namespaces::Namespaces;
+use crate::proc;
+use crate::subsystem::Subsystem;
+use crate::topology::Topology;
+
+/// How long a child with `Subsystem::privileges` has to drop them.
+const DROP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The controller's handle on its children.
+pub struct Supervisor {
+    topology: Topology,
+    // Subsystems that drop privileges, and report back on a status socket.
+    privileged: Vec<&'static str>,
+    confined: Vec<(&'static str, Namespaces, Limits)>,
+    // Looked up the first time a child needs one.
+    cgroup: Option<Option<Cgroup>>,
+    children: Vec<(String, Child)>,
+    // Fds to install in children not started yet: (for, name, fd). Peer
+    // sockets are named after the peer.
+    inherited: Vec<(String, String, OwnedFd)>,
+    // Keys for the channels to children not started yet, see `seal`.
+    sealed: Vec<(String, SealKeys)>,
+    // Our ends of the channels to children started by `spawn_all`.
+    unclaimed: Vec<(String, UnixStream)>,
+}
+
+impl Supervisor {
+    pub(crate) fn new(
+        topology: Topology,
+        privileged: Vec<&'static str>,
+        confined: Vec<(&'static str, Namespaces, Limits)>,
+    ) -> Self {
+        Supervisor {
+            topology,
+            privileged,
+            confined,
+            cgroup: None,
+            children: Vec::new(),
+            inherited: Vec::new(),
+            sealed: Vec::new(),
+            unclaimed: Vec::new(),
+        }
+    }
+
+    /// Starts subsystem `S` in a child process, with a socket to each of its
+    /// peers in the topology, and returns our end of the channel to it and
+    /// how much of its `Subsystem::limits` holds. Fails if `S` can't drop to
+    /// its `Subsystem::privileges`.
+    pub async fn spawn<S: Subsystem>(
+        &mut self,
+    ) -> io::Result<(Channel<S::ToChild, S::FromChild>, Enforcement)> {
+        let (parent_sock, enforcement) = self.start(S::NAME).await?;
+
+        Ok((Channel::duplex(parent_sock), enforcement))
+    }
+
+    /// Starts every subsystem of the topology that isn't running yet, and
+    /// returns how much of each one's limits holds. Take the channels to
+    /// them with `channel`.
+    pub async fn spawn_all(&mut self) -> io::Result<Vec<(String, Enforcement)>> {
+        let mut started = Vec::new();
+        for name in self.topology.subsystems.clone() {
+            if !self.children.iter().any(|(child, _)| *child == name) {
+                let (parent_sock, enforcement) = self.start(&name).await?;
+                self.unclaimed.push((name.clone(), parent_sock));
+                started.push((name, enforcement));
+            }
+        }
+
+        Ok(started)
+    }
+
+    /// Hands `fd` to `S` when it is started, to be claimed there with
+    /// `proc::claim_fd(name)`.
+    pub fn pass_fd<S: Subsystem>(&mut self, name: &str, fd: impl Into<OwnedFd>) -> io::Result<()> {
+        let taken = name == proc::CONTROLLER
+            || name == proc::STATUS
+            || name == proc::KEYS
+            || self.topology.contains(name)
+            || self
+                .inherited
+                .iter()
+                .any(|(owner, fd_name, _)| owner == S::NAME && fd_name == name);
+        if taken {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already inherits an fd called {name}", S::NAME),
+            ));
+        }
+        if self.children.iter().any(|(child, _)| child == S::NAME) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has already been started", S::NAME),
+            ));
+        }
+
+        self.inherited
+            .push((S::NAME.to_owned(), name.to_owned(), fd.into()));
+
+        Ok(())
+    }
+
+    /// Generates keys to seal the channel to `S` with (see
+    /// `ChannelTx::with_seal`), and hands the child its end when it is
+    /// started, to be taken there with `proc::seal_keys`. Returns ours.
+    pub fn seal<S: Subsystem>(&mut self) -> io::Result<SealKeys> {
+        if self.sealed.iter().any(|(owner, _)| owner == S::NAME) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("the channel to {} is already sealed", S::NAME),
+            ));
+        }
+        if self.children.iter().any(|(child, _)| child == S::NAME) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has already been started", S::NAME),
+            ));
+        }
+
+        let keys = SealKeys::generate();
+        self.sealed.push((S::NAME.to_owned(), keys.clone()));
+
+        Ok(keys)
+    }
+
+    /// Our end of the channel to `S`, started by `spawn_all`.
+    pub fn channel<S: Subsystem>(&mut self) -> io::Result<Channel<S::ToChild, S::FromChild>> {
+        let i = self
+            .unclaimed
+            .iter()
+            .position(|(name, _)| name == S::NAME)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} was not started by spawn_all", S::NAME),
+                )
+            })?;
+
+        Ok(Channel::duplex(self.unclaimed.remove(i).1))
+    }
+
+    async fn start(&mut self, name: &str) -> io::Result<(UnixStream, Enforcement)> {
+        if !self.topology.contains(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} is not part of the topology"),
+            ));
+        }
+
+        // The first of two peers to start makes the socket between them.
+        for peer in self.topology.peers_of(name) {
+            let made = self
+                .inherited
+                .iter()
+                .any(|(owner, fd_name, _)| owner == name && fd_name == peer);
+            if !made {
+                let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+                self.inherited
+                    .push((name.to_owned(), peer.to_owned(), ours.into()));
+                self.inherited
+                    .push((peer.to_owned(), name.to_owned(), theirs.into()));
+            }
+        }
+
+        let (fds, rest) = std::mem::take(&mut self.inherited)
+            .into_iter()
+            .partition(|(owner, _, _)| owner == name);
+        self.inherited = rest;
+        let mut fds: Vec<_> = fds
+            .into_iter()
+            .map(|(_, fd_name, fd)| (fd_name, fd))
+            .collect();
+
+        let mut status = None;
+        if self.privileged.contains(&name) {
+            let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+            fds.push((proc::STATUS.to_owned(), theirs.into()));
+            status = Some(ours);
+        }
+
+        let (parent_sock, child_sock) = UnixStream::pair()?;
+        let child_sock = child_sock.into_std()?.into();
+        let (namespaces, mut limits) = self
+            .confined
+            .iter()
+            .find(|(subsystem, _, _)| *subsystem == name)
+            .map(|(_, namespaces, limits)| (*namespaces, *limits))
+            .unwrap_or_default();
+        let mut cgroup = None;
+        let mut enforcement = Enforcement::Full;
+        if !limits.cgroup().is_empty() {
+            if self.cgroup.is_none() {
+                self.cgroup = Some(Cgroup::delegated()?);
+            }
+            match &self.cgroup {
+                Some(Some(delegated)) => cgroup = Some(delegated.child(name, &limits)?.into()),
+                _ => (limits, enforcement) = limits.without_cgroup(),
+            }
+        }
+        if let Some(i) = self.sealed.iter().position(|(owner, _)| owner == name) {
+            fds.push((proc::KEYS.to_owned(), self.sealed.remove(i).1.export()?));
+        }
+        let mut child = proc::start(name, child_sock, fds, namespaces, limits, cgroup)?;
+
+        if let Some(status) = status {
+            if let Err(e) = dropped(name, status).await {
+                let _ = child.start_kill();
+                return Err(e);
+            }
+        }
+        self.children.push((name.to_owned(), child));
+
+        Ok((parent_sock, enforcement))
+    }
+
+    /// Waits for any child to exit, returning its name. That child is no
+    /// longer supervised.
+    pub async fn exited(&mut self) -> (String, io::Result<ExitStatus>) {
+        let (i, status) = poll_fn(|cx| {
+            for (i, (_, child)) in self.children.iter_mut().enumerate() {
+                if let Poll::Ready(status) = pin!(child.wait()).poll(cx) {
+                    return Poll::Ready((i, status));
+                }
+            }
+
+            Poll::Pending
+        })
+        .await;
+
+        let name = self.children.remove(i).0;
+        if let Some(Some(cgroup)) = &self.cgroup {
+            cgroup.remove(&name);
+        }
+
+        (name, status)
+    }
+
+    /// Kills every child still running.
+    pub async fn kill_all(&mut self) -> io::Result<()> {
+        for (name, child) in &mut self.children {
+            child.kill().await?;
+            if let Some(Some(cgroup)) = &self.cgroup {
+                cgroup.remove(name);
+            }
+        }
+        self.children.clear();
+
+        Ok(())
+    }
+}
+
+/// Much like std waits for exec to succeed: the child closes its end of
+/// `status` once its privileges are dropped, or says why it couldn't.
+async fn dropped(name: &str, status: std::os::unix::net::UnixStream) -> io::Result<()> {
+    status.set_nonblocking(true)?;
+    let mut status = UnixStream::from_std(status)?;
+    let mut error = String::new();
+    timeout(DROP_TIMEOUT, status.read_to_string(&mut error))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{name} did not drop privileges in time"),
+            )
+        })??;
+    if !error.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{name} could not drop privileges: {error}"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Connects two children directly: each gets one end of a new socket through
+/// the handshake step it is at.
+#[allow(clippy::type_complexity)]
+pub async fn wire<VA, PA, MA, NA, VB, PB, MB, NB>(
+    a: Session<Transmit<VA, PA>, MA, NA>,
+    b: Session<Transmit<VB, PB>, MB, NB>,
+) -> Result<(Session<PA, MA, NA>, Session<PB, MB, NB>), ChannelError>
+where
+    VA: Variant<MA, Value = RawFd>,
+    MA: SerializeFd + Serialize,
+    NA: SerializeFd + DeserializeOwned,
+    VB: Variant<MB, Value = RawFd>,
+    MB: SerializeFd + Serialize,
+    NB: SerializeFd + DeserializeOwned,
+{
+    // Our copies of the ends are closed once they have been sent.
+    let (left, right) = UnixStream::pair()?;
+
+    Ok((
+        a.send(left.as_raw_fd()).await?,
+        b.send(right.as_raw_fd()).await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Supervisor;
+    use crate::app::drop_privileges;
+    use crate::namespaces::Namespaces;
+    use crate::privileges::Privileges;
+    use crate::proc;
+    use crate::subsystem::Subsystem;
+    use crate::topology::Topology;
+    use privsep_channel::test_util::TestMsg;
+    use std::future::Future;
+    use std::io;
+
+    // Also what the test binary is re-executed with, which runs this test
+    // again as the child.
+    const NAME: &str = "supervisor::tests::test_failed_drop_is_reported";
+
+    #[tokio::test]
+    async fn test_failed_drop_is_reported() {
+        if std::env::var(proc::FDS_ENV).is_ok() {
+            let privileges = Privileges::new().user("no-such-user");
+            assert!(drop_privileges(&privileges, &Namespaces::default()).is_err());
+            return;
+        }
+
+        let topology = Topology {
+            subsystems: vec![NAME.to_owned()],
+            edges: Vec::new(),
+        };
+        let mut supervisor = Supervisor::new(topology, vec![NAME], Vec::new());
+        let e = supervisor.spawn_all().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(e.to_string().contains("no such user"), "{e}");
+    }
+
+    struct Sealed;
+
+    impl Subsystem for Sealed {
+        const NAME: &'static str = "supervisor::tests::test_sealed_child_gets_its_keys";
+
+        type ToChild = TestMsg;
+        type FromChild = TestMsg;
+        type Error = io::Error;
+
+        fn main() -> impl Future<Output = Result<(), io::Error>> {
+            async { Ok(()) }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sealed_child_gets_its_keys() {
+        if std::env::var(proc::FDS_ENV).is_ok() {
+            let keys = proc::seal_keys().unwrap();
+            let (tx, _rx) = proc::parent::<Sealed>().unwrap().into_split();
+            let mut tx = tx.with_seal(&keys);
+            tx.send(&TestMsg::Hello(7)).await.unwrap();
+            return;
+        }
+
+        let topology = Topology::new().subsystem::<Sealed>();
+        let mut supervisor = Supervisor::new(topology, Vec::new(), Vec::new());
+        let keys = supervisor.seal::<Sealed>().unwrap();
+        assert!(supervisor.seal::<Sealed>().is_err());
+        let (_tx, rx) = supervisor.spawn::<Sealed>().await.unwrap().0.into_split();
+        let mut rx = rx.with_seal(&keys);
+
+        assert_eq!(rx.recv().await.unwrap(), TestMsg::Hello(7));
+    }
+}
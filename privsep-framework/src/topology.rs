@@ -0,0 +1,302 @@
+use std::any::{type_name, TypeId};
+use thiserror::Error;
+use toml_edit::{DocumentMut, Item, Value};
+
+use crate::subsystem::Subsystem;
+
+/// One end of an edge as declared by a subsystem (see `Subsystem::peers`):
+/// who it talks to and the message types it sends and receives.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub(crate) name: &'static str,
+    sends: (TypeId, &'static str),
+    receives: (TypeId, &'static str),
+}
+
+impl Peer {
+    /// Talks to `S`, sending `M` and receiving `N`. The child opens the
+    /// channel with `proc::peer::<M, N>(S::NAME)`.
+    pub fn new<S: Subsystem, M: 'static, N: 'static>() -> Self {
+        Peer {
+            name: S::NAME,
+            sends: (TypeId::of::<M>(), type_name::<M>()),
+            receives: (TypeId::of::<N>(), type_name::<N>()),
+        }
+    }
+}
+
+/// Which subsystems run and which of them share a socket. Every edge is
+/// checked against both ends' `Subsystem::peers` before anything is spawned.
+///
+/// In TOML:
+///
+/// ```toml
+/// subsystems = ["parser", "engine"]
+/// edges = [["parser", "engine"]]
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    pub(crate) subsystems: Vec<String>,
+    pub(crate) edges: Vec<(String, String)>,
+}
+
+#[derive(Debug, Error)]
+pub enum TopologyError {
+    #[error("Invalid topology: {0}")]
+    Parse(String),
+    #[error("{0} is not a subsystem of this application")]
+    UnknownSubsystem(String),
+    #[error("Edge {0} -- {1} is listed twice")]
+    DuplicateEdge(String, String),
+    #[error("{0} has an edge to {1} but does not declare it as a peer")]
+    UndeclaredPeer(String, String),
+    #[error("{0} declares {1} as a peer but the topology has no edge between them")]
+    MissingEdge(String, String),
+    #[error("{from} sends {sends} to {to}, which expects {expects}")]
+    MismatchedTypes {
+        from: String,
+        to: String,
+        sends: &'static str,
+        expects: &'static str,
+    },
+}
+
+impl Topology {
+    pub fn new() -> Self {
+        Topology::default()
+    }
+
+    pub fn subsystem<S: Subsystem>(mut self) -> Self {
+        if !self.subsystems.iter().any(|name| name == S::NAME) {
+            self.subsystems.push(S::NAME.to_owned());
+        }
+        self
+    }
+
+    /// Connects `A` and `B`, which must both be in the topology.
+    pub fn edge<A: Subsystem, B: Subsystem>(mut self) -> Self {
+        self.edges.push((A::NAME.to_owned(), B::NAME.to_owned()));
+        self
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, TopologyError> {
+        let doc: DocumentMut = toml
+            .parse()
+            .map_err(|e| TopologyError::Parse(format!("{e}")))?;
+
+        let mut topology = Topology::new();
+        for name in strings(doc.get("subsystems"), "subsystems")? {
+            topology.subsystems.push(name);
+        }
+        for edge in array(doc.get("edges"), "edges")? {
+            let ends = strings(Some(&Item::Value(edge.clone())), "edge")?;
+            let [a, b] = <[String; 2]>::try_from(ends)
+                .map_err(|_| TopologyError::Parse("an edge has two ends".to_owned()))?;
+            topology.edges.push((a, b));
+        }
+
+        Ok(topology)
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.subsystems.iter().any(|s| s == name)
+    }
+
+    /// The other end of every edge at `name`.
+    pub(crate) fn peers_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.edges.iter().filter_map(move |(a, b)| {
+            if a == name {
+                Some(b.as_str())
+            } else if b == name {
+                Some(a.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Checks the topology against the declared peers of each subsystem,
+    /// found with `peers`.
+    pub(crate) fn validate(
+        &self,
+        peers: impl Fn(&str) -> Option<Vec<Peer>>,
+    ) -> Result<(), TopologyError> {
+        if let Some(name) = self.subsystems.iter().find(|name| peers(name).is_none()) {
+            return Err(TopologyError::UnknownSubsystem(name.clone()));
+        }
+
+        for name in &self.subsystems {
+            for peer in peers(name).into_iter().flatten() {
+                if !self.peers_of(name).any(|other| other == peer.name) {
+                    return Err(TopologyError::MissingEdge(
+                        name.clone(),
+                        peer.name.to_owned(),
+                    ));
+                }
+            }
+        }
+
+        for (i, (a, b)) in self.edges.iter().enumerate() {
+            for end in [a, b] {
+                if !self.contains(end) {
+                    return Err(TopologyError::UnknownSubsystem(end.clone()));
+                }
+            }
+            let same = |(x, y): &(String, String)| (x == a && y == b) || (x == b && y == a);
+            if self.edges[..i].iter().any(same) {
+                return Err(TopologyError::DuplicateEdge(a.clone(), b.clone()));
+            }
+
+            let find = |from: &String, to: &String| {
+                peers(from)
+                    .into_iter()
+                    .flatten()
+                    .find(|peer| peer.name == to)
+                    .ok_or_else(|| TopologyError::UndeclaredPeer(from.clone(), to.clone()))
+            };
+            let (pa, pb) = (find(a, b)?, find(b, a)?);
+            for (from, to, sends, expects) in
+                [(a, b, pa.sends, pb.receives), (b, a, pb.sends, pa.receives)]
+            {
+                if sends.0 != expects.0 {
+                    return Err(TopologyError::MismatchedTypes {
+                        from: from.clone(),
+                        to: to.clone(),
+                        sends: sends.1,
+                        expects: expects.1,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn array<'a>(item: Option<&'a Item>, key: &str) -> Result<Vec<&'a Value>, TopologyError> {
+    match item {
+        None => Ok(Vec::new()),
+        Some(item) => match item.as_array() {
+            Some(array) => Ok(array.iter().collect()),
+            None => Err(TopologyError::Parse(format!("{key} must be an array"))),
+        },
+    }
+}
+
+fn strings(item: Option<&Item>, key: &str) -> Result<Vec<String>, TopologyError> {
+    array(item, key)?
+        .into_iter()
+        .map(|value| {
+            value
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| TopologyError::Parse(format!("{key} must list names")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Peer, Topology, TopologyError};
+    use crate::subsystem::Subsystem;
+    use privsep_channel::error::ChannelError;
+    use privsep_channel::serializefd::SerializeFd;
+    use serde::{Deserialize, Serialize};
+    use std::collections::VecDeque;
+    use std::future::Future;
+    use std::os::fd::RawFd;
+
+    #[derive(Serialize, Deserialize)]
+    struct Nothing;
+
+    impl SerializeFd for Nothing {
+        fn extract_fd(&self) -> Option<RawFd> {
+            None
+        }
+
+        fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+            Ok(self)
+        }
+    }
+
+    macro_rules! subsystem {
+        ($marker:ident, $name:literal, $peer:ty, $sends:ty, $receives:ty) => {
+            struct $marker;
+
+            impl Subsystem for $marker {
+                const NAME: &'static str = $name;
+
+                type ToChild = Nothing;
+                type FromChild = Nothing;
+                type Error = ();
+
+                fn peers() -> Vec<Peer> {
+                    vec![Peer::new::<$peer, $sends, $receives>()]
+                }
+
+                fn main() -> impl Future<Output = Result<(), ()>> {
+                    async { Ok(()) }
+                }
+            }
+        };
+    }
+
+    subsystem!(Parser, "parser", Engine, u32, String);
+    subsystem!(Engine, "engine", Parser, String, u32);
+
+    fn peers(name: &str) -> Option<Vec<Peer>> {
+        match name {
+            "parser" => Some(Parser::peers()),
+            "engine" => Some(Engine::peers()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_edges_are_checked_against_declared_peers() {
+        let toml = r#"
+            subsystems = ["parser", "engine"]
+            edges = [["engine", "parser"]]
+        "#;
+        let topology = Topology::from_toml(toml).unwrap();
+        topology.validate(peers).unwrap();
+
+        // The engine's view of the edge disagrees with the parser's.
+        let confused = |name: &str| match name {
+            "engine" => Some(vec![Peer::new::<Parser, String, u64>()]),
+            name => peers(name),
+        };
+        assert!(matches!(
+            topology.validate(confused),
+            Err(TopologyError::MismatchedTypes { from, sends: "u32", expects: "u64", .. })
+                if from == "parser"
+        ));
+
+        let unaware = |name: &str| match name {
+            "engine" => Some(Vec::new()),
+            name => peers(name),
+        };
+        assert!(matches!(
+            topology.validate(unaware),
+            Err(TopologyError::UndeclaredPeer(..))
+        ));
+
+        let topology = Topology::new().subsystem::<Parser>().subsystem::<Engine>();
+        assert!(matches!(
+            topology.validate(peers),
+            Err(TopologyError::MissingEdge(..))
+        ));
+
+        let topology = topology.edge::<Parser, Engine>().edge::<Engine, Parser>();
+        assert!(matches!(
+            topology.validate(peers),
+            Err(TopologyError::DuplicateEdge(..))
+        ));
+
+        let topology = Topology::from_toml(r#"subsystems = ["parser", "printer"]"#).unwrap();
+        assert!(matches!(
+            topology.validate(peers),
+            Err(TopologyError::UnknownSubsystem(name)) if name == "printer"
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 use nix::unistd::getpid;
-use privsep_channel::channel::ChannelOld;
 use privsep_channel::error::ChannelError;
+use privsep_framework::supervisor::Supervisor;
 use std::fs::File;
 use std::io::{self, Write};
 use std::os::unix::io::AsRawFd;
@@ -8,45 +8,25 @@ use std::time::Duration;
 use thiserror::Error;
 use tokio::net::UnixStream;
 
-#[cfg(target_os = "openbsd")]
-use pledge::pledge_promises;
+use privsep_sandbox::Stages;
 
+use crate::engine::Engine;
 use crate::msg::Msg;
-use crate::proc;
+use crate::parser::Parser;
 
 static NAME: &str = "controller";
 
-pub async fn controller() -> Result<(), ControllerError> {
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Rpath Wpath Cpath Sendfd Proc Exec Ps].unwrap();
+pub async fn controller(mut supervisor: Supervisor) -> Result<(), ControllerError> {
+    let mut stages = stages();
+    stages.advance("spawn")?;
 
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    // Start parser
-    let (mut parser_ch, mut parser) = {
-        let (parent_sock, child_sock) = UnixStream::pair()?;
+    let (mut parser_ch, _) = supervisor.spawn::<Parser>().await?;
+    let (mut engine_ch, _) = supervisor.spawn::<Engine>().await?;
 
-        let child = proc::start("parser", parent_sock.as_raw_fd(), child_sock)?;
-
-        let channel = ChannelOld::new(parent_sock);
-
-        (channel, child)
-    };
-
-    // Start engine
-    let (mut engine_ch, mut engine) = {
-        let (parent_sock, child_sock) = UnixStream::pair()?;
-
-        let child = proc::start("engine", parent_sock.as_raw_fd(), child_sock)?;
-
-        let channel = ChannelOld::new(parent_sock);
-
-        (channel, child)
-    };
-
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio Rpath Wpath Cpath Sendfd].unwrap();
+    stages.advance("setup")?;
 
     // Child-to-child socket
     {
@@ -79,8 +59,7 @@ pub async fn controller() -> Result<(), ControllerError> {
 
     let mut flag = false;
 
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio].unwrap();
+    stages.advance("run")?;
 
     loop {
         tokio::select! {
@@ -99,13 +78,9 @@ pub async fn controller() -> Result<(), ControllerError> {
                 // parser_ch.send(&Msg::IntegerMessage(22)).await.unwrap();
                 msg?;
             }
-            _ = parser.wait() => {
-                engine.kill().await?;
-                break;
-
-            }
-            _ = engine.wait() => {
-                parser.kill().await?;
+            (name, _) = supervisor.exited() => {
+                println!("{NAME}[{pid}]: {name} exited, stopping");
+                supervisor.kill_all().await?;
                 break;
             }
         }
@@ -127,6 +102,21 @@ fn create_temp_file(content: &str) -> Result<File, ControllerError> {
     Ok(temp_file)
 }
 
+/// What the controller may do as it goes, from spawning the children to
+/// serving.
+fn stages() -> Stages {
+    use privsep_sandbox::Promise::*;
+
+    Stages::new()
+        .stage(
+            "spawn",
+            &[Stdio, Rpath, Wpath, Cpath, Sendfd, Proc, Exec, Ps],
+        )
+        // Proc is kept to stop the children.
+        .stage("setup", &[Stdio, Rpath, Wpath, Cpath, Sendfd, Proc])
+        .stage("run", &[Stdio, Proc])
+}
+
 #[derive(Debug, Error)]
 pub enum ControllerError {
     #[error("I/O error: {0}")]
@@ -1,17 +1,34 @@
-use crate::{msg::Msg, proc::SOCKFD};
+use crate::msg::Msg;
 use nix::unistd::getpid;
-use privsep_channel::{channel::ChannelOld, error::ChannelError};
+use privsep_channel::{channel_redux::Channel, error::ChannelError};
+use privsep_framework::{proc, subsystem::Subsystem};
+use std::future::Future;
 use std::os::fd::FromRawFd;
 use thiserror::Error;
 use tokio::net::UnixStream;
 
 static NAME: &str = "engine";
 
+pub struct Engine;
+
+impl Subsystem for Engine {
+    const NAME: &'static str = "engine";
+    const ABOUT: &'static str = "Engine subsystem";
+
+    type ToChild = Msg;
+    type FromChild = Msg;
+    type Error = EngineError;
+
+    fn main() -> impl Future<Output = Result<(), EngineError>> {
+        engine()
+    }
+}
+
 pub async fn engine() -> Result<(), EngineError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    let mut ctrl_ch = ChannelOld::new_from_fd(SOCKFD)?;
+    let mut ctrl_ch = proc::parent::<Engine>()?;
 
     let msg = ctrl_ch.recv().await.expect("Expected fd");
 
@@ -23,7 +40,7 @@ pub async fn engine() -> Result<(), EngineError> {
             stream.set_nonblocking(true)?;
             let stream = UnixStream::from_std(stream)?;
 
-            ChannelOld::new(stream)
+            Channel::duplex::<Msg, Msg>(stream)
         }
         _ => todo!(),
     };
@@ -1,5 +1,5 @@
-use privsep_channel::channel::Result;
 use privsep_channel::error::ChannelError;
+use privsep_channel::error::Result;
 use privsep_channel::serializefd::SerializeFd;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
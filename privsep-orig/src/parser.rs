@@ -1,22 +1,35 @@
-use crate::{msg::Msg, proc::SOCKFD};
+use crate::msg::Msg;
 use nix::unistd::getpid;
-use privsep_channel::{channel::ChannelOld, error::ChannelError};
-use std::{fs::File, io::Read, os::fd::FromRawFd};
+use privsep_channel::{channel_redux::Channel, error::ChannelError};
+use privsep_framework::{proc, subsystem::Subsystem};
+use std::{fs::File, future::Future, io::Read, os::fd::FromRawFd};
 use thiserror::Error;
 use tokio::net::UnixStream;
 
-#[cfg(target_os = "openbsd")]
-use pledge::pledge_promises;
-#[cfg(target_os = "openbsd")]
-use unveil::unveil;
+use privsep_sandbox::{promises, unveil::Unveil};
 
 static NAME: &str = "parser";
 
+pub struct Parser;
+
+impl Subsystem for Parser {
+    const NAME: &'static str = "parser";
+    const ABOUT: &'static str = "Parser subsystem";
+
+    type ToChild = Msg;
+    type FromChild = Msg;
+    type Error = ParserError;
+
+    fn main() -> impl Future<Output = Result<(), ParserError>> {
+        parser()
+    }
+}
+
 pub async fn parser() -> Result<(), ParserError> {
     let pid = getpid();
     println!("{NAME}[{pid}]: Starting...");
 
-    let mut ctrl_ch = ChannelOld::<Msg>::new_from_fd(SOCKFD)?;
+    let mut ctrl_ch = proc::parent::<Parser>()?;
 
     println!("{NAME}[{pid}]: Waiting on peer channel...");
 
@@ -24,7 +37,7 @@ pub async fn parser() -> Result<(), ParserError> {
 
     println!("{NAME}[{pid}]: Peer channel received");
 
-    let mut engine_ch: ChannelOld<Msg> = match msg {
+    let mut engine_ch: Channel<Msg, Msg> = match msg {
         Msg::FileDescriptor(ch_fd) => {
             println!("{NAME}[{pid}]: received peer channel fd = {ch_fd}");
 
@@ -32,7 +45,7 @@ pub async fn parser() -> Result<(), ParserError> {
             stream.set_nonblocking(true)?;
             let stream = UnixStream::from_std(stream)?;
 
-            ChannelOld::new(stream)
+            Channel::duplex(stream)
         }
         _ => return Err(ParserError::UnexpectedMessage(msg)),
     };
@@ -58,8 +71,9 @@ pub async fn parser() -> Result<(), ParserError> {
 
     println!("{NAME}[{pid}]: Looping.");
 
-    #[cfg(target_os = "openbsd")]
-    pledge_promises![Stdio].unwrap();
+    // Hide the filesystem while we still may.
+    Unveil::new().lock().unwrap();
+    promises![Stdio].unwrap();
 
     loop {
         tokio::select! {
@@ -76,9 +90,6 @@ pub async fn parser() -> Result<(), ParserError> {
                     Msg::TextMessage(_) => {},
                     Msg::IntegerMessage(int) => {
                         println!("{NAME}[{pid}]: GOT INTEGER {int:?}.");
-
-                        #[cfg(target_os = "openbsd")]
-                        unveil("", "").unwrap();
                     },
                     Msg::FileDescriptor(_) => {},
                 }
@@ -0,0 +1,50 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Pretty-print a capture
+    Print {
+        /// Capture file (see `PRIVSEP_CAPTURE`)
+        capture: PathBuf,
+        /// Only show frames with this label
+        #[arg(long)]
+        label: Option<String>,
+        /// Also show each payload in hex
+        #[arg(long)]
+        hex: bool,
+    },
+    /// Replay one side of a capture into a subsystem under test
+    Replay {
+        /// Capture file (see `PRIVSEP_CAPTURE`)
+        capture: PathBuf,
+        /// Frames to replay, e.g. `parser`
+        #[arg(long)]
+        label: String,
+        /// Replay the frames the tapped end sent (`tx`) or received (`rx`)
+        #[arg(long, value_enum, default_value = "tx")]
+        direction: Direction,
+        /// Keep the original spacing between frames
+        #[arg(long)]
+        realtime: bool,
+        /// How long to wait for replies after the last frame, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        wait_ms: u64,
+        /// Subsystem command line, e.g. `target/debug/privsep-ex2 parser`
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
@@ -0,0 +1,261 @@
+mod cli;
+
+use clap::Parser;
+use cli::{Cli, Command};
+use privsep_channel::capture::{Direction, Record};
+use privsep_channel::channel_redux::Channel;
+use privsep_channel::error::ChannelError;
+use privsep_channel::serializefd::SerializeFd;
+use privsep_framework::limits::Limits;
+use privsep_framework::namespaces::Namespaces;
+use privsep_framework::proc;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UnixStream;
+use tokio::process::Child;
+use tokio::time::Instant;
+
+#[tokio::main]
+async fn main() -> Result<(), ReplayError> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Print {
+            capture,
+            label,
+            hex,
+        } => print(&read_capture(&capture)?, label.as_deref(), hex),
+        Command::Replay {
+            capture,
+            label,
+            direction,
+            realtime,
+            wait_ms,
+            command,
+        } => {
+            let direction = match direction {
+                cli::Direction::Tx => Direction::Tx,
+                cli::Direction::Rx => Direction::Rx,
+            };
+            let records = read_capture(&capture)?
+                .into_iter()
+                .filter(|r| r.label == label && r.direction == direction)
+                .collect::<Vec<_>>();
+
+            let wait = Duration::from_millis(wait_ms);
+            replay(&records, realtime, wait, &command).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_capture(path: &Path) -> Result<Vec<Record>, ReplayError> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| line.parse().map_err(|_| ReplayError::Malformed(i + 1)))
+        .collect()
+}
+
+fn print(records: &[Record], label: Option<&str>, hex: bool) {
+    for record in records {
+        if label.is_some_and(|label| label != record.label) {
+            continue;
+        }
+
+        let arrow = match record.direction {
+            Direction::Tx => "->",
+            Direction::Rx => "<-",
+        };
+        let fds = match record.fds {
+            0 => String::new(),
+            n => format!(" [{n} fd]"),
+        };
+
+        println!(
+            "{:>12.6} {:<10} {arrow} {}{fds}",
+            record.time.as_secs_f64(),
+            record.label,
+            record.debug
+        );
+        if hex {
+            println!("{:>12} {:<10}    {}", "", "", record.payload_hex());
+        }
+    }
+}
+
+/// Stands in for the subsystem's message types, which we don't know: frames
+/// are only ever sent and received raw.
+#[derive(Serialize, Deserialize, Debug)]
+struct Opaque;
+
+impl SerializeFd for Opaque {
+    fn extract_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn compose_fd(self, _fds: &mut VecDeque<RawFd>) -> Result<Self, ChannelError> {
+        Ok(self)
+    }
+}
+
+/// Sends `records` to the subsystem run by `command`, returning what it
+/// sends back.
+async fn replay(
+    records: &[Record],
+    realtime: bool,
+    wait: Duration,
+    command: &[String],
+) -> Result<Vec<Vec<u8>>, ReplayError> {
+    let (parent_sock, child_sock) = UnixStream::pair()?;
+    let mut child = start(command, child_sock)?;
+    let (mut tx, mut rx) = Channel::from_stream::<Opaque, Opaque>(parent_sock);
+
+    // The captured fds are long gone. Each is replaced by one end of a fresh
+    // socket pair; we hold on to the other so the subsystem doesn't see EOF.
+    let mut stand_ins = Vec::new();
+
+    let start = Instant::now();
+    let first = records.first().map(|r| r.time).unwrap_or_default();
+
+    for record in records {
+        if realtime {
+            // Nothing guarantees a capture is in order, e.g. one edited by hand.
+            tokio::time::sleep_until(start + record.time.saturating_sub(first)).await;
+        }
+
+        let fd = match record.fds {
+            0 => None,
+            _ => {
+                let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+                stand_ins.push(ours);
+                Some(theirs)
+            }
+        };
+
+        tx.send_raw(&record.payload, fd.as_ref().map(|s| s.as_raw_fd()))
+            .await?;
+        println!("-> {}", record.debug);
+    }
+
+    // Show whatever the subsystem sends back until it goes quiet, or has
+    // exited and nothing of its is left to read.
+    let mut replies = Vec::new();
+    let mut exited = false;
+    loop {
+        tokio::select! {
+            payload = rx.recv_raw() => match payload {
+                Ok(payload) => {
+                    println!("<- {}", hex(&payload));
+                    replies.push(payload);
+                }
+                Err(ChannelError::ConnectionClosedPrematurely) => break,
+                Err(e) => return Err(e.into()),
+            },
+            status = child.wait(), if !exited => {
+                println!("subsystem exited: {}", status?);
+                exited = true;
+            }
+            _ = tokio::time::sleep(wait) => break,
+        }
+    }
+
+    if !exited {
+        child.kill().await?;
+    }
+
+    Ok(replies)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Spawns the subsystem as its controller would, so that it finds
+/// `child_sock` with `proc::parent`.
+fn start(command: &[String], child_sock: UnixStream) -> std::io::Result<Child> {
+    let mut cmd = tokio::process::Command::new(&command[0]);
+    cmd.args(&command[1..]);
+
+    proc::spawn(
+        cmd,
+        child_sock.into_std()?.into(),
+        Vec::new(),
+        Namespaces::default(),
+        Limits::default(),
+        None,
+    )
+}
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Channel error: {0}")]
+    Channel(#[from] ChannelError),
+    #[error("Malformed capture record on line {0}")]
+    Malformed(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::replay;
+    use privsep_channel::capture::{Direction, Record};
+    use privsep_channel::error::ChannelError;
+    use privsep_channel::test_util::TestMsg;
+    use privsep_framework::proc;
+    use privsep_framework::subsystem::Subsystem;
+    use std::time::Duration;
+
+    /// Sends back whatever it is sent, until its controller hangs up.
+    struct Echo;
+
+    impl Subsystem for Echo {
+        const NAME: &'static str = "tests::test_replays_into_a_subsystem";
+
+        type ToChild = TestMsg;
+        type FromChild = TestMsg;
+        type Error = ChannelError;
+
+        async fn main() -> Result<(), ChannelError> {
+            let mut channel = proc::parent::<Self>()?;
+            loop {
+                let msg = channel.recv().await?;
+                channel.send(&msg).await?;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replays_into_a_subsystem() {
+        if std::env::var(proc::FDS_ENV).is_ok() {
+            let _ = Echo::main().await;
+            return;
+        }
+
+        let records: Vec<_> = (0..3)
+            .map(|i| Record {
+                time: Duration::from_millis(i),
+                label: "echo".to_owned(),
+                direction: Direction::Tx,
+                fds: 0,
+                payload: bincode::serialize(&TestMsg::Hello(i as u32)).unwrap(),
+                debug: format!("Hello({i})"),
+            })
+            .collect();
+        let exe = std::env::current_exe().unwrap();
+        let command = [exe.to_string_lossy().into_owned(), Echo::NAME.to_owned()];
+
+        let replies = replay(&records, false, Duration::from_secs(1), &command)
+            .await
+            .unwrap();
+        let payloads: Vec<_> = records.into_iter().map(|r| r.payload).collect();
+        assert_eq!(replies, payloads);
+    }
+}
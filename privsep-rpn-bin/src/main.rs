@@ -3,8 +3,7 @@ use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    #[cfg(target_os = "openbsd")]
-    pledge::pledge_promises![Stdio Rpath Wpath Cpath Inet].unwrap();
+    privsep_sandbox::promises![Stdio Rpath Wpath Cpath Inet]?;
 
     let listener = TcpListener::bind("0.0.0.0:4000").await?;
     println!("RPN server listening on port 4000");
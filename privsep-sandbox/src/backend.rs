@@ -0,0 +1,52 @@
+use std::io;
+
+use crate::promise::Promise;
+use crate::unveil::{Enforcement, Unveil};
+
+/// Something that can confine the process: promises as pledge(2) takes
+/// them and filesystem visibility as unveil(2) does. Both only narrow.
+pub trait Sandbox {
+    fn restrict(&mut self, promises: &[Promise]) -> io::Result<Enforcement>;
+    fn lock(&mut self, unveil: &Unveil) -> io::Result<Enforcement>;
+}
+
+/// pledge/unveil on OpenBSD, seccomp/Landlock on Linux.
+#[derive(Debug, Default)]
+pub struct Native;
+
+impl Sandbox for Native {
+    fn restrict(&mut self, promises: &[Promise]) -> io::Result<Enforcement> {
+        crate::restrict(promises)
+    }
+
+    fn lock(&mut self, unveil: &Unveil) -> io::Result<Enforcement> {
+        unveil.lock()
+    }
+}
+
+/// Enforces nothing, only reports on stderr what would have been.
+#[derive(Debug, Default)]
+pub struct Audit;
+
+impl Sandbox for Audit {
+    fn restrict(&mut self, promises: &[Promise]) -> io::Result<Enforcement> {
+        let names: Vec<_> = promises.iter().map(|promise| promise.name()).collect();
+        eprintln!(
+            "sandbox[{}]: would pledge {:?}",
+            std::process::id(),
+            names.join(" ")
+        );
+        Ok(Enforcement::None)
+    }
+
+    fn lock(&mut self, unveil: &Unveil) -> io::Result<Enforcement> {
+        eprintln!("sandbox[{}]: would unveil {unveil:?}", std::process::id());
+        Ok(Enforcement::None)
+    }
+}
+
+/// The sandbox this platform enforces, or `Audit` where there is none.
+#[cfg(any(target_os = "openbsd", target_os = "linux"))]
+pub type Platform = Native;
+#[cfg(not(any(target_os = "openbsd", target_os = "linux")))]
+pub type Platform = Audit;
@@ -0,0 +1,314 @@
+use nix::libc::{self, c_int};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::unveil::Enforcement;
+
+// From linux/landlock.h.
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: u32 = 1;
+
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const REMOVE_DIR: u64 = 1 << 4;
+const REMOVE_FILE: u64 = 1 << 5;
+const MAKE_CHAR: u64 = 1 << 6;
+const MAKE_DIR: u64 = 1 << 7;
+const MAKE_REG: u64 = 1 << 8;
+const MAKE_SOCK: u64 = 1 << 9;
+const MAKE_FIFO: u64 = 1 << 10;
+const MAKE_BLOCK: u64 = 1 << 11;
+const MAKE_SYM: u64 = 1 << 12;
+// ABI 2.
+const REFER: u64 = 1 << 13;
+// ABI 3.
+const TRUNCATE: u64 = 1 << 14;
+
+/// ABI 1: everything up to `MAKE_SYM`.
+const V1: u64 = (MAKE_SYM << 1) - 1;
+
+/// The rights that apply to a file, rather than to what is beneath a
+/// directory.
+const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The rights behind unveil(2) `permissions`.
+fn access(permissions: &str) -> io::Result<u64> {
+    let mut access = 0;
+    for permission in permissions.chars() {
+        access |= match permission {
+            'r' => READ_FILE | READ_DIR,
+            'w' => WRITE_FILE | TRUNCATE,
+            'x' => EXECUTE,
+            'c' => {
+                MAKE_CHAR
+                    | MAKE_DIR
+                    | MAKE_REG
+                    | MAKE_SOCK
+                    | MAKE_FIFO
+                    | MAKE_BLOCK
+                    | MAKE_SYM
+                    | REMOVE_DIR
+                    | REMOVE_FILE
+                    | REFER
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown unveil permissions {permissions:?}"),
+                ))
+            }
+        };
+    }
+
+    Ok(access)
+}
+
+/// Everything the running kernel's Landlock ABI can restrict, or `None`
+/// without Landlock.
+fn handled() -> Option<(u64, Enforcement)> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+
+    match abi {
+        abi if abi < 1 => None,
+        1 => Some((V1, Enforcement::Partial)),
+        2 => Some((V1 | REFER, Enforcement::Partial)),
+        _ => Some((V1 | REFER | TRUNCATE, Enforcement::Full)),
+    }
+}
+
+pub(crate) fn lock(paths: &[(&Path, &str)]) -> io::Result<Enforcement> {
+    let Some((handled, enforcement)) = handled() else {
+        return Ok(Enforcement::None);
+    };
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+    for &(path, permissions) in paths {
+        let mut access = access(permissions)? & handled;
+        // A path that isn't there yet can only be created, through its
+        // directory, which unveils its siblings too.
+        let path = match fs::metadata(path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                access &= FILE;
+                path
+            }
+            Ok(_) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && permissions.contains('c') => {
+                match path.parent() {
+                    Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+                    Some(parent) => parent,
+                    None => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        let name = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.as_raw_fd(),
+        };
+        let added = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if added != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    restrict_all_threads(ruleset.as_raw_fd())?;
+
+    Ok(enforcement)
+}
+
+// Landlock confines only the calling thread (and threads it starts later),
+// so like glibc's setxid, every other thread is signalled to confine itself.
+static BROADCAST: Mutex<()> = Mutex::new(());
+static RULESET: AtomicI32 = AtomicI32::new(-1);
+static CONFINED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+fn restrict_self(ruleset: RawFd) -> bool {
+    unsafe {
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+            && libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) == 0
+    }
+}
+
+extern "C" fn confine_thread(_: c_int) {
+    if !restrict_self(RULESET.load(Ordering::SeqCst)) {
+        FAILED.store(true, Ordering::SeqCst);
+    }
+    CONFINED.fetch_add(1, Ordering::SeqCst);
+}
+
+fn restrict_all_threads(ruleset: RawFd) -> io::Result<()> {
+    let _broadcast = BROADCAST.lock().unwrap();
+    RULESET.store(ruleset, Ordering::SeqCst);
+    FAILED.store(false, Ordering::SeqCst);
+
+    // Alone, as before a runtime has started, there is no one to signal.
+    let alone = fs::read_dir("/proc/self/task").is_ok_and(|tasks| tasks.count() == 1);
+    if alone {
+        if !restrict_self(ruleset) {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    let signal = libc::SIGRTMIN();
+    let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+    unsafe {
+        if libc::sigaction(signal, std::ptr::null(), &mut old) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // The application's own, which we must not take over.
+    if old.sa_sigaction != libc::SIG_DFL && old.sa_sigaction != libc::SIG_IGN {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "SIGRTMIN has a handler, so other threads can't be confined",
+        ));
+    }
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = confine_thread as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let broadcast = signal_threads(signal);
+    unsafe { libc::sigaction(signal, &old, std::ptr::null_mut()) };
+    let confined = broadcast?;
+    if FAILED.load(Ordering::SeqCst) {
+        partly_confined(io::Error::other("a thread could not be confined"));
+    }
+
+    // Ourselves last, as /proc is usually hidden from then on.
+    if !restrict_self(ruleset) {
+        let e = io::Error::last_os_error();
+        if confined > 0 {
+            partly_confined(e);
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Some threads are confined and others may not be, which no caller could
+/// recover from.
+fn partly_confined(e: io::Error) -> ! {
+    eprintln!("landlock[{}]: {e}, aborting", std::process::id());
+    std::process::abort()
+}
+
+/// Has every thread but this one run the handler for `signal`, including
+/// threads started meanwhile by threads not confined yet, and returns how
+/// many did.
+fn signal_threads(signal: c_int) -> io::Result<usize> {
+    let pid = std::process::id() as libc::pid_t;
+    let me = unsafe { libc::gettid() };
+    let mut done = vec![me];
+    let mut confined = 0;
+
+    loop {
+        let tasks = fs::read_dir("/proc/self/task").and_then(|tasks| {
+            tasks
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<io::Result<Vec<_>>>()
+        });
+        let tasks = match tasks {
+            Ok(tasks) => tasks,
+            Err(e) if confined == 0 => return Err(e),
+            Err(e) => partly_confined(e),
+        };
+        let mut pending = Vec::new();
+        for task in tasks {
+            let tid = task.to_string_lossy().parse().unwrap_or(me);
+            if !done.contains(&tid) {
+                pending.push(tid);
+            }
+        }
+        if pending.is_empty() {
+            return Ok(confined);
+        }
+
+        CONFINED.store(0, Ordering::SeqCst);
+        let mut expected = 0;
+        for &tid in &pending {
+            // A thread that has exited since has nothing to confine.
+            if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) } == 0 {
+                expected += 1;
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while CONFINED.load(Ordering::SeqCst) < expected {
+            if Instant::now() > deadline {
+                partly_confined(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "a thread did not answer the landlock broadcast",
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        confined += expected;
+        done.extend(pending);
+    }
+}
@@ -0,0 +1,80 @@
+//! Pledge-style promises for every platform we run on: pledge(2) itself on
+//! OpenBSD and an equivalent seccomp-bpf filter on x86_64 and aarch64 Linux.
+//! Elsewhere they are not enforced. `unveil::Unveil` does the same for
+//! unveil(2), with Landlock. `Stages` declares a process's whole policy at
+//! once, for any `Sandbox`.
+//!
+//! Like pledge, promises can only be narrowed: each call restricts the
+//! process further.
+
+pub mod backend;
+#[cfg(target_os = "linux")]
+mod landlock;
+pub mod promise;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub mod seccomp;
+pub mod stage;
+pub mod unveil;
+
+pub use backend::{Audit, Native, Platform, Sandbox};
+pub use promise::Promise;
+pub use stage::Stages;
+pub use unveil::Enforcement;
+
+use std::io;
+
+/// Restricts the whole process to `promises`. On Linux nothing is enforced
+/// while `exec` is promised, see `seccomp::Filter`.
+pub fn restrict(promises: &[Promise]) -> io::Result<Enforcement> {
+    #[cfg(target_os = "openbsd")]
+    let enforcement = {
+        let names: Vec<_> = promises.iter().map(|promise| promise.name()).collect();
+        pledge::pledge(names.join(" ").as_str(), None::<&str>)
+            .map_err(|e| io::Error::other(format!("pledge failed: {e:?}")))?;
+        Enforcement::Full
+    };
+
+    // What we exec would inherit the filter, where pledge(2) leaves it
+    // unrestricted.
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    let enforcement = if promises.contains(&Promise::Exec) {
+        Enforcement::None
+    } else {
+        seccomp::Filter::new(promises).install()?;
+        Enforcement::Full
+    };
+
+    #[cfg(not(any(
+        target_os = "openbsd",
+        all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )
+    )))]
+    let enforcement = {
+        let _ = promises;
+        Enforcement::None
+    };
+
+    Ok(enforcement)
+}
+
+/// `restrict`, with the promises listed as pledge(2) takes them.
+pub fn pledge(promises: &str) -> io::Result<Enforcement> {
+    restrict(&Promise::parse_list(promises)?)
+}
+
+/// Restricts the process to the named promises, e.g.
+/// `promises![Stdio Inet Recvfd]`, in the manner of `pledge_promises!`.
+#[macro_export]
+macro_rules! promises {
+    ($($promise:ident)*) => {
+        $crate::restrict(&[$($crate::Promise::$promise),*])
+    };
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// A pledge(2) promise: a group of system calls a process keeps using.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Promise {
+    Stdio,
+    Rpath,
+    Wpath,
+    Cpath,
+    Inet,
+    Unix,
+    Dns,
+    Sendfd,
+    Recvfd,
+    Proc,
+    Exec,
+    Ps,
+    Id,
+}
+
+impl Promise {
+    pub const ALL: [Promise; 13] = [
+        Promise::Stdio,
+        Promise::Rpath,
+        Promise::Wpath,
+        Promise::Cpath,
+        Promise::Inet,
+        Promise::Unix,
+        Promise::Dns,
+        Promise::Sendfd,
+        Promise::Recvfd,
+        Promise::Proc,
+        Promise::Exec,
+        Promise::Ps,
+        Promise::Id,
+    ];
+
+    /// The name pledge(2) knows it by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Promise::Stdio => "stdio",
+            Promise::Rpath => "rpath",
+            Promise::Wpath => "wpath",
+            Promise::Cpath => "cpath",
+            Promise::Inet => "inet",
+            Promise::Unix => "unix",
+            Promise::Dns => "dns",
+            Promise::Sendfd => "sendfd",
+            Promise::Recvfd => "recvfd",
+            Promise::Proc => "proc",
+            Promise::Exec => "exec",
+            Promise::Ps => "ps",
+            Promise::Id => "id",
+        }
+    }
+
+    /// Parses a space separated list, e.g. `"stdio recvfd"`.
+    pub fn parse_list(promises: &str) -> io::Result<Vec<Promise>> {
+        promises.split_whitespace().map(str::parse).collect()
+    }
+}
+
+impl fmt::Display for Promise {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Promise {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Promise> {
+        Promise::ALL
+            .into_iter()
+            .find(|promise| promise.name() == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown promise {name}"),
+                )
+            })
+    }
+}
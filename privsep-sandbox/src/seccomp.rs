@@ -0,0 +1,562 @@
+use nix::libc::{self, c_long, sock_filter};
+use std::io;
+
+use crate::promise::Promise;
+
+// Classic BPF, as seccomp runs it.
+const LD_W_ABS: u16 = 0x20;
+const ALU_AND_K: u16 = 0x54;
+const JEQ_K: u16 = 0x15;
+#[cfg(target_arch = "x86_64")]
+const JGE_K: u16 = 0x35;
+const RET_K: u16 = 0x06;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// x32 system calls share our audit arch, and are told apart by this bit.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets into struct seccomp_data.
+const NR: u32 = 0;
+const ARCH: u32 = 4;
+
+/// The low half of argument `i`.
+const fn arg(i: u32) -> u32 {
+    16 + 8 * i
+}
+
+const DENY: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+/// When a system call is allowed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rule {
+    Always,
+    /// `(arg & mask) == value`, on the low 32 bits of the argument.
+    Arg {
+        arg: u32,
+        mask: u32,
+        value: u32,
+    },
+}
+
+/// A seccomp-bpf filter allowing the system calls behind a set of promises.
+/// Anything else fails with EPERM, where pledge(2) would kill the process.
+///
+/// Linux can't tell some promises apart: `sendfd` and `recvfd` add nothing to
+/// `stdio` (sendmsg(2) is in both), and `stdio` allows the stat family since
+/// glibc's fstat(3) is a newfstatat(2). A filter also survives exec, so
+/// `restrict` installs none while `exec` is promised.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    rules: Vec<(c_long, Rule)>,
+    // Denied with another errno than EPERM.
+    errnos: Vec<(c_long, i32)>,
+}
+
+impl Filter {
+    pub fn new(promises: &[Promise]) -> Self {
+        let mut filter = Filter {
+            rules: Vec::new(),
+            errnos: Vec::new(),
+        };
+        // Narrowing further is always allowed.
+        filter.allow(&[libc::SYS_exit, libc::SYS_exit_group, libc::SYS_seccomp]);
+        let no_new_privs = libc::PR_SET_NO_NEW_PRIVS as u32;
+        filter.allow_if(libc::SYS_prctl, 0, u32::MAX, &[no_new_privs]);
+        for promise in promises {
+            filter.promise(*promise);
+        }
+
+        let has = |promise| promises.contains(&promise);
+        filter.open(
+            has(Promise::Rpath) || has(Promise::Ps) || has(Promise::Dns),
+            has(Promise::Wpath),
+            has(Promise::Cpath),
+        );
+        if !has(Promise::Proc) {
+            // Its arguments are out of reach, but libc falls back to clone(2).
+            filter.errnos.push((libc::SYS_clone3, libc::ENOSYS));
+        }
+
+        filter
+    }
+
+    fn allow(&mut self, syscalls: &[c_long]) {
+        for &nr in syscalls {
+            self.rules.push((nr, Rule::Always));
+        }
+    }
+
+    fn allow_if(&mut self, nr: c_long, arg: u32, mask: u32, values: &[u32]) {
+        for &value in values {
+            self.rules.push((nr, Rule::Arg { arg, mask, value }));
+        }
+    }
+
+    fn promise(&mut self, promise: Promise) {
+        let inet = [libc::AF_INET as u32, libc::AF_INET6 as u32];
+        let serve = [
+            libc::SYS_bind,
+            libc::SYS_listen,
+            libc::SYS_connect,
+            libc::SYS_accept,
+            libc::SYS_accept4,
+        ];
+
+        match promise {
+            Promise::Stdio => self.stdio(),
+            Promise::Rpath => {
+                self.allow(&[
+                    libc::SYS_getcwd,
+                    libc::SYS_chdir,
+                    libc::SYS_fchdir,
+                    libc::SYS_readlinkat,
+                    libc::SYS_faccessat,
+                    libc::SYS_faccessat2,
+                    libc::SYS_getdents64,
+                    libc::SYS_statfs,
+                    libc::SYS_fstatfs,
+                ]);
+                #[cfg(target_arch = "x86_64")]
+                self.allow(&[
+                    libc::SYS_readlink,
+                    libc::SYS_access,
+                    libc::SYS_stat,
+                    libc::SYS_lstat,
+                ]);
+            }
+            Promise::Wpath => self.allow(&[libc::SYS_truncate]),
+            Promise::Cpath => {
+                self.allow(&[
+                    libc::SYS_mkdirat,
+                    libc::SYS_renameat,
+                    libc::SYS_renameat2,
+                    libc::SYS_unlinkat,
+                    libc::SYS_symlinkat,
+                    libc::SYS_linkat,
+                ]);
+                #[cfg(target_arch = "x86_64")]
+                self.allow(&[
+                    libc::SYS_mkdir,
+                    libc::SYS_rename,
+                    libc::SYS_unlink,
+                    libc::SYS_rmdir,
+                    libc::SYS_symlink,
+                    libc::SYS_link,
+                    libc::SYS_creat,
+                ]);
+            }
+            Promise::Inet => {
+                self.allow_if(libc::SYS_socket, 0, u32::MAX, &inet);
+                self.allow(&serve);
+            }
+            Promise::Unix => {
+                self.allow_if(libc::SYS_socket, 0, u32::MAX, &[libc::AF_UNIX as u32]);
+                self.allow(&serve);
+            }
+            Promise::Dns => {
+                self.allow_if(libc::SYS_socket, 0, u32::MAX, &inet);
+                self.allow(&[libc::SYS_connect]);
+            }
+            Promise::Proc => {
+                self.allow(&[
+                    libc::SYS_clone,
+                    libc::SYS_clone3,
+                    libc::SYS_kill,
+                    libc::SYS_tgkill,
+                    libc::SYS_setpgid,
+                    libc::SYS_getpgid,
+                    libc::SYS_setsid,
+                    libc::SYS_getsid,
+                    libc::SYS_setpriority,
+                    libc::SYS_getpriority,
+                    libc::SYS_sched_setaffinity,
+                    libc::SYS_pidfd_send_signal,
+                ]);
+                #[cfg(target_arch = "x86_64")]
+                self.allow(&[libc::SYS_fork, libc::SYS_vfork]);
+            }
+            Promise::Exec => self.allow(&[libc::SYS_execve, libc::SYS_execveat]),
+            Promise::Id => self.allow(&[
+                libc::SYS_setuid,
+                libc::SYS_setgid,
+                libc::SYS_setreuid,
+                libc::SYS_setregid,
+                libc::SYS_setresuid,
+                libc::SYS_setresgid,
+                libc::SYS_setgroups,
+                libc::SYS_setfsuid,
+                libc::SYS_setfsgid,
+            ]),
+            // Opening files is covered by `open`, fd passing by `stdio`.
+            Promise::Ps | Promise::Sendfd | Promise::Recvfd => {}
+        }
+    }
+
+    fn stdio(&mut self) {
+        self.allow(&[
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_readv,
+            libc::SYS_writev,
+            libc::SYS_pread64,
+            libc::SYS_pwrite64,
+            libc::SYS_preadv,
+            libc::SYS_pwritev,
+            libc::SYS_preadv2,
+            libc::SYS_pwritev2,
+            libc::SYS_close,
+            libc::SYS_close_range,
+            libc::SYS_dup,
+            libc::SYS_dup3,
+            libc::SYS_fcntl,
+            libc::SYS_fstat,
+            libc::SYS_newfstatat,
+            libc::SYS_statx,
+            libc::SYS_lseek,
+            libc::SYS_ftruncate,
+            libc::SYS_fsync,
+            libc::SYS_fdatasync,
+            libc::SYS_mmap,
+            libc::SYS_munmap,
+            libc::SYS_mprotect,
+            libc::SYS_madvise,
+            libc::SYS_mremap,
+            libc::SYS_msync,
+            libc::SYS_mincore,
+            libc::SYS_brk,
+            libc::SYS_rt_sigaction,
+            libc::SYS_rt_sigprocmask,
+            libc::SYS_rt_sigreturn,
+            libc::SYS_rt_sigtimedwait,
+            libc::SYS_sigaltstack,
+            libc::SYS_getpid,
+            libc::SYS_gettid,
+            libc::SYS_getppid,
+            libc::SYS_getuid,
+            libc::SYS_geteuid,
+            libc::SYS_getgid,
+            libc::SYS_getegid,
+            libc::SYS_getresuid,
+            libc::SYS_getresgid,
+            libc::SYS_getgroups,
+            libc::SYS_getrusage,
+            libc::SYS_prlimit64,
+            libc::SYS_umask,
+            libc::SYS_uname,
+            libc::SYS_clock_gettime,
+            libc::SYS_clock_getres,
+            libc::SYS_clock_nanosleep,
+            libc::SYS_nanosleep,
+            libc::SYS_gettimeofday,
+            libc::SYS_futex,
+            libc::SYS_sched_yield,
+            libc::SYS_sched_getaffinity,
+            libc::SYS_set_robust_list,
+            libc::SYS_get_robust_list,
+            libc::SYS_set_tid_address,
+            libc::SYS_rseq,
+            libc::SYS_membarrier,
+            libc::SYS_epoll_create1,
+            libc::SYS_epoll_ctl,
+            libc::SYS_epoll_pwait,
+            libc::SYS_ppoll,
+            libc::SYS_pselect6,
+            libc::SYS_eventfd2,
+            libc::SYS_timerfd_create,
+            libc::SYS_timerfd_settime,
+            libc::SYS_timerfd_gettime,
+            libc::SYS_pipe2,
+            libc::SYS_recvfrom,
+            libc::SYS_sendto,
+            libc::SYS_recvmsg,
+            libc::SYS_sendmsg,
+            libc::SYS_recvmmsg,
+            libc::SYS_sendmmsg,
+            libc::SYS_shutdown,
+            libc::SYS_getsockopt,
+            libc::SYS_setsockopt,
+            libc::SYS_getsockname,
+            libc::SYS_getpeername,
+            libc::SYS_getrandom,
+            libc::SYS_wait4,
+            libc::SYS_waitid,
+            libc::SYS_pidfd_open,
+        ]);
+        #[cfg(target_arch = "x86_64")]
+        self.allow(&[
+            libc::SYS_dup2,
+            libc::SYS_poll,
+            libc::SYS_select,
+            libc::SYS_epoll_wait,
+            libc::SYS_pipe,
+            libc::SYS_getrlimit,
+        ]);
+
+        self.allow_if(libc::SYS_socketpair, 0, u32::MAX, &[libc::AF_UNIX as u32]);
+        let ioctls = [libc::FIONBIO, libc::FIONREAD, libc::FIOCLEX, libc::FIONCLEX];
+        let ioctls = ioctls.map(|request| request as u32);
+        self.allow_if(libc::SYS_ioctl, 1, u32::MAX, &ioctls);
+        self.allow_if(libc::SYS_ioctl, 1, u32::MAX, &[libc::TCGETS as u32]);
+        let names = [libc::PR_SET_NAME as u32, libc::PR_GET_NAME as u32];
+        self.allow_if(libc::SYS_prctl, 0, u32::MAX, &names);
+
+        // Threads, but not processes, and signals only to ourselves (abort).
+        // Not to pid 0, our process group, which children of a controller
+        // share with it.
+        let thread = libc::CLONE_THREAD as u32;
+        self.allow_if(libc::SYS_clone, 0, thread, &[thread]);
+        let pid = std::process::id();
+        self.allow_if(libc::SYS_kill, 0, u32::MAX, &[pid]);
+        self.allow_if(libc::SYS_tgkill, 0, u32::MAX, &[pid]);
+    }
+
+    /// Which files may be opened, by their flags: reading needs `read`,
+    /// writing or truncating `write`, and creating `create` as well.
+    fn open(&mut self, read: bool, write: bool, create: bool) {
+        let accmode = libc::O_ACCMODE as u32;
+        let creat = libc::O_CREAT as u32;
+        let trunc = libc::O_TRUNC as u32;
+        // O_TMPFILE without the O_DIRECTORY it includes.
+        let tmpfile = (libc::O_TMPFILE & !libc::O_DIRECTORY) as u32;
+        let mask = accmode | creat | trunc | tmpfile;
+
+        let mut values = Vec::new();
+        for (mode, reads, writes) in [
+            (libc::O_RDONLY as u32, true, false),
+            (libc::O_WRONLY as u32, false, true),
+            (libc::O_RDWR as u32, true, true),
+        ] {
+            for truncates in [false, true] {
+                for creates in [0, creat, tmpfile] {
+                    if (reads && !read)
+                        || ((writes || truncates) && !write)
+                        || (creates != 0 && !create)
+                    {
+                        continue;
+                    }
+                    values.push(mode | creates | if truncates { trunc } else { 0 });
+                }
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let calls = [(libc::SYS_openat, 2), (libc::SYS_open, 1)];
+        #[cfg(not(target_arch = "x86_64"))]
+        let calls = [(libc::SYS_openat, 2)];
+        for (nr, arg) in calls {
+            self.allow_if(nr, arg, mask, &values);
+        }
+    }
+
+    /// The filter, in the form the kernel takes it.
+    pub fn program(&self) -> Vec<sock_filter> {
+        let mut program = vec![
+            stmt(LD_W_ABS, ARCH),
+            jump(JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(LD_W_ABS, NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([jump(JGE_K, X32_SYSCALL_BIT, 0, 1), stmt(RET_K, DENY)]);
+
+        let mut seen = Vec::new();
+        for &(nr, _) in &self.rules {
+            if seen.contains(&nr) {
+                continue;
+            }
+            seen.push(nr);
+
+            let rules: Vec<Rule> = self
+                .rules
+                .iter()
+                .filter(|(other, _)| *other == nr)
+                .map(|(_, rule)| *rule)
+                .collect();
+            let mut block = Vec::new();
+            for rule in &rules {
+                if let Rule::Arg {
+                    arg: i,
+                    mask,
+                    value,
+                } = *rule
+                {
+                    block.extend([
+                        stmt(LD_W_ABS, arg(i)),
+                        stmt(ALU_AND_K, mask),
+                        jump(JEQ_K, value, 0, 1),
+                        stmt(RET_K, libc::SECCOMP_RET_ALLOW),
+                    ]);
+                }
+            }
+            if rules.contains(&Rule::Always) {
+                block = vec![stmt(RET_K, libc::SECCOMP_RET_ALLOW)];
+            } else {
+                block.push(stmt(RET_K, DENY));
+            }
+
+            let skip = u8::try_from(block.len()).expect("too many rules for one system call");
+            program.push(jump(JEQ_K, nr as u32, 0, skip));
+            program.extend(block);
+        }
+
+        for &(nr, errno) in &self.errnos {
+            if !seen.contains(&nr) {
+                program.extend([
+                    jump(JEQ_K, nr as u32, 0, 1),
+                    stmt(RET_K, libc::SECCOMP_RET_ERRNO | errno as u32),
+                ]);
+            }
+        }
+        program.push(stmt(RET_K, DENY));
+
+        program
+    }
+
+    /// Confines every thread of the process, for good.
+    pub fn install(&self) -> io::Result<()> {
+        let program = self.program();
+        let fprog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut sock_filter,
+        };
+
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Without TSYNC only this thread would be, not tokio's workers.
+            match libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &fprog,
+            ) {
+                0 => Ok(()),
+                -1 => Err(io::Error::last_os_error()),
+                tid => Err(io::Error::other(format!(
+                    "thread {tid} could not be confined"
+                ))),
+            }
+        }
+    }
+}
+
+fn stmt(code: u16, k: u32) -> sock_filter {
+    sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code, jt, jf, k }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, ALU_AND_K, AUDIT_ARCH, JEQ_K, LD_W_ABS, RET_K};
+    use crate::promise::Promise;
+    use nix::libc::{self, c_long, sock_filter};
+    use privsep_rpn::rpn::eval_rpn;
+    use std::process::Command;
+
+    /// What `program` returns for system call `nr` with `args`.
+    fn run(program: &[sock_filter], nr: c_long, args: [u64; 6]) -> u32 {
+        let mut data = vec![nr as u32, AUDIT_ARCH, 0, 0];
+        for arg in args {
+            data.extend([arg as u32, (arg >> 32) as u32]);
+        }
+
+        let (mut pc, mut acc) = (0, 0);
+        loop {
+            let op = program[pc];
+            pc += 1;
+            match op.code {
+                LD_W_ABS => acc = data[op.k as usize / 4],
+                ALU_AND_K => acc &= op.k,
+                RET_K => return op.k,
+                code => {
+                    let taken = match code {
+                        JEQ_K => acc == op.k,
+                        _ => acc >= op.k,
+                    };
+                    pc += usize::from(if taken { op.jt } else { op.jf });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_each_open_right_is_its_own() {
+        let openat = |promises: &[Promise], flags: i32| {
+            let program = Filter::new(promises).program();
+            run(&program, libc::SYS_openat, [0, 0, flags as u64, 0, 0, 0])
+                == libc::SECCOMP_RET_ALLOW
+        };
+
+        assert!(!openat(&[Promise::Wpath], libc::O_RDONLY));
+        assert!(openat(&[Promise::Wpath], libc::O_WRONLY | libc::O_TRUNC));
+        assert!(!openat(&[Promise::Wpath], libc::O_WRONLY | libc::O_CREAT));
+        assert!(!openat(&[Promise::Cpath], libc::O_RDONLY));
+        assert!(!openat(&[Promise::Rpath], libc::O_RDONLY | libc::O_TRUNC));
+        assert!(openat(&[Promise::Rpath], libc::O_RDONLY | libc::O_CLOEXEC));
+        assert!(openat(
+            &[Promise::Wpath, Promise::Cpath],
+            libc::O_WRONLY | libc::O_CREAT
+        ));
+        assert!(!openat(&[Promise::Wpath, Promise::Cpath], libc::O_RDWR));
+    }
+
+    #[test]
+    fn test_rpath_allows_access() {
+        let program = Filter::new(&[Promise::Rpath]).program();
+        for nr in [libc::SYS_faccessat, libc::SYS_faccessat2] {
+            assert_eq!(run(&program, nr, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        }
+        let program = Filter::new(&[Promise::Stdio]).program();
+        assert_ne!(
+            run(&program, libc::SYS_faccessat2, [0; 6]),
+            libc::SECCOMP_RET_ALLOW
+        );
+    }
+
+    // Set in the copy of the test that runs confined.
+    const CONFINED: &str = "PRIVSEP_SANDBOX_CONFINED";
+
+    #[test]
+    fn test_shibboleth_cannot_spawn_sh() {
+        if let Ok(marker) = std::env::var(CONFINED) {
+            Filter::new(&[Promise::Stdio, Promise::Inet])
+                .install()
+                .unwrap();
+            let _ = eval_rpn(&["SHIBBOLETH", "touch", &marker]);
+            assert_eq!(eval_rpn(&["3", "4", "+"]).unwrap(), 7.0);
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let free = dir.path().join("free");
+        let confined = dir.path().join("confined");
+
+        // Unconfined, the backdoor works.
+        let _ = eval_rpn(&["SHIBBOLETH", "touch", free.to_str().unwrap()]);
+        assert!(free.exists());
+
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "seccomp::tests::test_shibboleth_cannot_spawn_sh"])
+            .arg("--test-threads=1")
+            .env(CONFINED, &confined)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!confined.exists());
+    }
+}
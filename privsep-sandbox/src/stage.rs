@@ -0,0 +1,189 @@
+use std::io;
+
+use crate::backend::{Platform, Sandbox};
+use crate::promise::Promise;
+use crate::unveil::{Enforcement, Unveil};
+
+/// A named point in a process's life and what it may still do from there.
+#[derive(Debug, Clone)]
+pub struct Stage {
+    name: &'static str,
+    promises: Vec<Promise>,
+    unveil: Option<Unveil>,
+}
+
+/// A process's whole sandbox policy, declared once as stages each narrower
+/// than the last, e.g.
+///
+/// ```no_run
+/// use privsep_sandbox::{Promise::*, Stages};
+///
+/// let mut stages = Stages::new()
+///     .stage("spawn", &[Stdio, Sendfd, Proc, Exec])
+///     .stage("serve", &[Stdio]);
+/// stages.advance("spawn")?;
+/// // ...
+/// stages.advance("serve")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Stages<S = Platform> {
+    sandbox: S,
+    stages: Vec<Stage>,
+    current: Option<usize>,
+}
+
+impl Stages {
+    pub fn new() -> Self {
+        Stages::with(Platform::default())
+    }
+}
+
+impl Default for Stages {
+    fn default() -> Self {
+        Stages::new()
+    }
+}
+
+impl<S: Sandbox> Stages<S> {
+    /// Stages enforced by `sandbox` rather than the platform's.
+    pub fn with(sandbox: S) -> Self {
+        Stages {
+            sandbox,
+            stages: Vec::new(),
+            current: None,
+        }
+    }
+
+    pub fn stage(mut self, name: &'static str, promises: &[Promise]) -> Self {
+        self.stages.push(Stage {
+            name,
+            promises: promises.to_vec(),
+            unveil: None,
+        });
+        self
+    }
+
+    /// Locks `unveil` on entering the stage last declared. As with unveil(2),
+    /// only one stage can.
+    pub fn unveil(mut self, unveil: Unveil) -> Self {
+        if let Some(stage) = self.stages.last_mut() {
+            stage.unveil = Some(unveil);
+        }
+        self
+    }
+
+    /// The stage declared first, which a process usually enters at once.
+    pub fn first(&self) -> Option<&'static str> {
+        self.stages.first().map(|stage| stage.name)
+    }
+
+    /// The stage entered last, if any.
+    pub fn current(&self) -> Option<&'static str> {
+        self.current.map(|i| self.stages[i].name)
+    }
+
+    /// Enters the stage `name`, which must come after the current one and
+    /// promise nothing it doesn't. Stages in between are skipped, but what
+    /// they unveil is still locked. Returns the weakest enforcement of what
+    /// was locked and promised.
+    pub fn advance(&mut self, name: &str) -> io::Result<Enforcement> {
+        let next = match self.stages.iter().position(|stage| stage.name == name) {
+            Some(next) if self.current.map_or(true, |current| next > current) => next,
+            Some(_) => return Err(invalid(format!("stage {name} is already past"))),
+            None => return Err(invalid(format!("no such stage {name}"))),
+        };
+
+        let stage = &self.stages[next];
+        if let Some(current) = self.current {
+            let current = &self.stages[current];
+            if let Some(promise) = stage
+                .promises
+                .iter()
+                .find(|promise| !current.promises.contains(promise))
+            {
+                return Err(invalid(format!(
+                    "stage {name} promises {promise}, which {} does not",
+                    current.name
+                )));
+            }
+        }
+
+        // Unveiling is itself a pledge(2) promise, so it comes first.
+        let first = self.current.map_or(0, |current| current + 1);
+        let mut enforcement = Enforcement::Full;
+        for unveil in self.stages[first..=next]
+            .iter()
+            .filter_map(|stage| stage.unveil.as_ref())
+        {
+            enforcement = enforcement.max(self.sandbox.lock(unveil)?);
+        }
+        enforcement = enforcement.max(self.sandbox.restrict(&stage.promises)?);
+        self.current = Some(next);
+
+        Ok(enforcement)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stages;
+    use crate::backend::Sandbox;
+    use crate::promise::Promise::{self, *};
+    use crate::unveil::{Enforcement, Unveil};
+    use std::io;
+
+    #[derive(Default)]
+    struct Record(Vec<String>);
+
+    impl Sandbox for Record {
+        fn restrict(&mut self, promises: &[Promise]) -> io::Result<Enforcement> {
+            let names: Vec<_> = promises.iter().map(|promise| promise.name()).collect();
+            self.0.push(names.join(" "));
+            Ok(Enforcement::Full)
+        }
+
+        fn lock(&mut self, _: &Unveil) -> io::Result<Enforcement> {
+            self.0.push("unveil".to_owned());
+            Ok(Enforcement::Partial)
+        }
+    }
+
+    #[test]
+    fn test_stages_only_narrow_and_only_advance() {
+        let mut stages = Stages::with(Record::default())
+            .stage("spawn", &[Stdio, Rpath, Proc, Exec])
+            .stage("setup", &[Stdio, Rpath])
+            .unveil(Unveil::new())
+            .stage("widen", &[Stdio, Inet])
+            .stage("serve", &[Stdio]);
+
+        assert_eq!(stages.advance("spawn").unwrap(), Enforcement::Full);
+        assert_eq!(stages.advance("setup").unwrap(), Enforcement::Partial);
+        let e = stages.advance("widen").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        stages.advance("serve").unwrap();
+        assert!(stages.advance("spawn").is_err());
+        assert!(stages.advance("nowhere").is_err());
+
+        assert_eq!(stages.current(), Some("serve"));
+        assert_eq!(
+            stages.sandbox.0,
+            ["stdio rpath proc exec", "unveil", "stdio rpath", "stdio"]
+        );
+
+        // Skipping a stage still locks what it unveils.
+        let mut stages = Stages::with(Record::default())
+            .stage("spawn", &[Stdio, Rpath])
+            .stage("setup", &[Stdio, Rpath])
+            .unveil(Unveil::new())
+            .stage("serve", &[Stdio]);
+        stages.advance("spawn").unwrap();
+        stages.advance("serve").unwrap();
+        assert_eq!(stages.sandbox.0, ["stdio rpath", "unveil", "stdio"]);
+    }
+}
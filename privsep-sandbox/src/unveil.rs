@@ -0,0 +1,115 @@
+use std::io;
+use std::path::PathBuf;
+
+/// How much of an `Unveil`, or of a set of promises, the running system
+/// enforces. Ordered strongest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Enforcement {
+    Full,
+    /// An older Landlock, which can't stop truncating or renaming across
+    /// directories.
+    Partial,
+    None,
+}
+
+/// Filesystem visibility in the manner of unveil(2): once locked, only the
+/// unveiled paths, and what is beneath them, can be reached. unveil(2) itself
+/// on OpenBSD and Landlock on Linux; elsewhere it is not enforced.
+#[derive(Debug, Clone, Default)]
+pub struct Unveil {
+    paths: Vec<(PathBuf, String)>,
+}
+
+impl Unveil {
+    pub fn new() -> Self {
+        Unveil::default()
+    }
+
+    /// Unveils `path` with unveil(2) `permissions`: any of `r`, `w`, `x` and
+    /// `c`. On Linux a path that doesn't exist yet unveils its directory.
+    pub fn path(mut self, path: impl Into<PathBuf>, permissions: &str) -> Self {
+        self.paths.push((path.into(), permissions.to_owned()));
+        self
+    }
+
+    /// Hides everything not unveiled from every thread, for good. Locking
+    /// nothing hides the whole filesystem.
+    pub fn lock(&self) -> io::Result<Enforcement> {
+        #[cfg(target_os = "openbsd")]
+        {
+            for (path, permissions) in &self.paths {
+                let path = path.to_str().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "unveil path is not UTF-8")
+                })?;
+                unveil::unveil(path, permissions)
+                    .map_err(|e| io::Error::other(format!("unveil failed: {e:?}")))?;
+            }
+            unveil::unveil("", "")
+                .map_err(|e| io::Error::other(format!("unveil failed: {e:?}")))?;
+            Ok(Enforcement::Full)
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let paths: Vec<_> = self
+                .paths
+                .iter()
+                .map(|(path, permissions)| (path.as_path(), permissions.as_str()))
+                .collect();
+            crate::landlock::lock(&paths)
+        }
+
+        #[cfg(not(any(target_os = "openbsd", target_os = "linux")))]
+        Ok(Enforcement::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Enforcement, Unveil};
+    use std::fs;
+    use std::process::Command;
+
+    const CONFINED: &str = "PRIVSEP_UNVEIL_CONFINED";
+
+    #[test]
+    fn test_other_threads_see_only_what_is_unveiled() {
+        let Ok(dir) = std::env::var(CONFINED) else {
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir(dir.path().join("visible")).unwrap();
+            fs::write(dir.path().join("hidden"), "hidden").unwrap();
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "unveil::tests::test_other_threads_see_only_what_is_unveiled",
+                ])
+                .env(CONFINED, dir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        };
+
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let dir = std::path::PathBuf::from(dir);
+        let visible = dir.join("visible");
+        let worker = std::thread::spawn(move || {
+            rx.recv().unwrap();
+            fs::write(visible.join("created"), "ok").unwrap();
+            fs::read_to_string(dir.join("hidden"))
+        });
+
+        let enforcement = Unveil::new()
+            .path(std::env::var(CONFINED).unwrap() + "/visible", "rwc")
+            .lock()
+            .unwrap();
+        tx.send(()).unwrap();
+        let hidden = worker.join().unwrap();
+        if enforcement != Enforcement::None {
+            assert_eq!(
+                hidden.unwrap_err().kind(),
+                std::io::ErrorKind::PermissionDenied
+            );
+        }
+    }
+}